@@ -0,0 +1,53 @@
+#![warn(clippy::pedantic)]
+
+use clap::Parser;
+use cpu_emulator::screen::Image;
+use std::path::PathBuf;
+use std::process;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+/// Compares two screen snapshots, failing if any pixel differs.
+struct Args {
+    /// The golden PNG or PBM image
+    expected: PathBuf,
+
+    /// The image to check against it
+    actual: PathBuf,
+
+    /// Writes the mismatching pixels as an image
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let load = |path: &PathBuf| Image::load(path).unwrap_or_else(|why| panic!("{why}"));
+    let expected = load(&args.expected);
+    let actual = load(&args.actual);
+    let diff = expected.diff(&actual);
+
+    if let Some(output) = &args.output {
+        if let Err(why) = diff.image.save(output) {
+            panic!("{why}")
+        }
+    }
+
+    if expected.width != actual.width || expected.height != actual.height {
+        println!(
+            "Sizes differ: {}x{} expected, {}x{} actual",
+            expected.width, expected.height, actual.width, actual.height
+        );
+    }
+    match diff.bounds {
+        None => println!("Images match"),
+        Some((left, top, right, bottom)) => {
+            println!(
+                "{} pixels differ between ({left}, {top}) and ({right}, {bottom})",
+                diff.pixels
+            );
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,167 @@
+#![warn(clippy::pedantic)]
+
+use clap::Parser;
+use cpu_emulator::keyboard::KeyScript;
+use cpu_emulator::trace_diff::{self, Align, Observed, Outcome, Run};
+use cpu_emulator::{parse_number, HackMachine, Stop, Symbols, HEAP_BASE, KBD, STACK_BASE};
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+/// Runs two Hack programs, or one program with two inputs, side by side and reports the first
+/// memory write where they differ, with both call stacks.
+struct Args {
+    /// The .hack file to run on the left
+    left: PathBuf,
+
+    /// The .hack file to run on the right [default: the left one]
+    right: Option<PathBuf>,
+
+    /// Stops each run after this many cycles if it hasn't halted by then
+    #[clap(short, long, default_value_t = 10_000_000)]
+    cycles: u64,
+
+    /// Lines the runs up by watched memory writes, or by VM function calls and returns as well
+    #[clap(long, value_name = "writes|functions", default_value = "writes")]
+    align: Align,
+
+    /// Memory to watch for writes, as FIRST-LAST [default: statics, heap and screen for VM translator output, all of RAM and the screen otherwise]
+    #[clap(long, value_name = "FIRST-LAST", action = clap::ArgAction::Append)]
+    watch: Vec<String>,
+
+    /// Stores a value in memory before both runs, as ADDRESS=VALUE
+    #[clap(long = "set", value_name = "ADDRESS=VALUE", action = clap::ArgAction::Append)]
+    sets: Vec<String>,
+
+    /// Stores a value in memory before the left run only
+    #[clap(long = "left-set", value_name = "ADDRESS=VALUE", action = clap::ArgAction::Append)]
+    left_sets: Vec<String>,
+
+    /// Stores a value in memory before the right run only
+    #[clap(long = "right-set", value_name = "ADDRESS=VALUE", action = clap::ArgAction::Append)]
+    right_sets: Vec<String>,
+
+    /// Keyboard script for the left run
+    #[clap(long, value_name = "FILE")]
+    left_keys: Option<PathBuf>,
+
+    /// Keyboard script for the right run
+    #[clap(long, value_name = "FILE")]
+    right_keys: Option<PathBuf>,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let right_path = args.right.as_ref().unwrap_or(&args.left);
+    let mut left = start(
+        &args,
+        &args.left,
+        &args.left_sets,
+        args.left_keys.as_deref(),
+    );
+    let mut right = start(
+        &args,
+        right_path,
+        &args.right_sets,
+        args.right_keys.as_deref(),
+    );
+
+    match trace_diff::compare(&mut left, &mut right) {
+        Outcome::Same(events) => {
+            println!("Runs match over {events} events");
+            println!("left:  {}", ending(&left));
+            println!("right: {}", ending(&right));
+        }
+        Outcome::Diverged(divergence) => {
+            println!("Runs diverge at event {}", divergence.index);
+            if let Some((left, right)) = &divergence.agreed {
+                println!(
+                    "Last agreed on {}, cycle {} on the left and {} on the right",
+                    left.event, left.cycle, right.cycle
+                );
+            }
+            println!();
+            print_side("left", divergence.left.as_ref(), &left);
+            println!();
+            print_side("right", divergence.right.as_ref(), &right);
+            process::exit(1);
+        }
+    }
+}
+
+fn start(args: &Args, path: &Path, sets: &[String], keys: Option<&Path>) -> Run {
+    let source = match fs::read_to_string(path) {
+        Err(why) => panic!("couldn't open {}: {}", path.display(), why),
+        Ok(file) => file,
+    };
+    let mut machine = HackMachine::from_hack(&source);
+    let symbols = Symbols::load(path, None).unwrap_or_else(|why| panic!("{why}"));
+    for set in args.sets.iter().chain(sets) {
+        let Some((address, value)) = set.split_once('=') else {
+            panic!("Invalid --set {set}, expected ADDRESS=VALUE")
+        };
+        machine.write(number(address), number(value));
+    }
+
+    let keys = keys.map_or_else(KeyScript::default, |path| {
+        let source = match fs::read_to_string(path) {
+            Err(why) => panic!("couldn't open {}: {}", path.display(), why),
+            Ok(file) => file,
+        };
+        KeyScript::parse(&source, &symbols)
+            .unwrap_or_else(|why| panic!("{}: {why}", path.display()))
+    });
+
+    let watch: Vec<RangeInclusive<u16>> = if args.watch.is_empty() {
+        // The VM translator's pointers, temps and stack are bookkeeping an optimization is free
+        // to change
+        if symbols.is_vm() {
+            vec![16..=STACK_BASE - 1, HEAP_BASE..=KBD - 1]
+        } else {
+            vec![0..=KBD - 1]
+        }
+    } else {
+        args.watch
+            .iter()
+            .map(|range| {
+                let Some((first, last)) = range.split_once('-') else {
+                    panic!("Invalid --watch {range}, expected FIRST-LAST")
+                };
+                number(first)..=number(last)
+            })
+            .collect()
+    };
+    Run::new(machine, &symbols, keys, args.cycles, watch, args.align)
+}
+
+fn print_side(side: &str, observed: Option<&Observed>, run: &Run) {
+    let Some(observed) = observed else {
+        println!("{side}: {}", ending(run));
+        return;
+    };
+    println!(
+        "{side}: {} at {} on cycle {}",
+        observed.event, observed.pc, observed.cycle
+    );
+    for (depth, function) in observed.stack.iter().rev().enumerate() {
+        println!("    #{depth} {function}");
+    }
+}
+
+fn ending(run: &Run) -> String {
+    match run.end() {
+        Some(result) if result.stop == Stop::Halted => {
+            format!("halted after {} cycles", result.cycles)
+        }
+        Some(result) => format!("stopped after {} cycles", result.cycles),
+        None => format!("still running at cycle {}", run.machine().cycles),
+    }
+}
+
+fn number(text: &str) -> u16 {
+    parse_number(text).unwrap_or_else(|why| panic!("{why}"))
+}
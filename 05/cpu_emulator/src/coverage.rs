@@ -0,0 +1,153 @@
+use crate::{HackMachine, SourceMap, VmMap, ROM_SIZE};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+/// Records which instructions run and which ways conditional jumps go, for lcov reports.
+///
+/// Call `before_step` ahead of each instruction and `finish` once the run ends.
+pub struct Coverage {
+    /// Executions of each ROM address
+    counts: Vec<u64>,
+    /// Times each conditional jump jumped
+    taken: Vec<u64>,
+    /// Times each conditional jump fell through to the next instruction
+    not_taken: Vec<u64>,
+    /// The conditional jump that's executing, if one is
+    pending: Option<u16>,
+}
+
+/// Hit counts for the lines of one source file.
+#[derive(Default)]
+struct Record {
+    lines: BTreeMap<usize, u64>,
+    /// The taken and not taken counts of each conditional jump on a line, with jumps that never
+    /// ran as `None`
+    branches: BTreeMap<usize, Vec<Option<(u64, u64)>>>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage {
+            counts: vec![0; ROM_SIZE],
+            taken: vec![0; ROM_SIZE],
+            not_taken: vec![0; ROM_SIZE],
+            pending: None,
+        }
+    }
+}
+
+impl Coverage {
+    pub fn before_step(&mut self, machine: &HackMachine) {
+        self.finish(machine);
+        let pc = machine.pc;
+        self.counts[usize::from(pc)] += 1;
+        if is_conditional_jump(machine.fetch(pc)) {
+            self.pending = Some(pc);
+        }
+    }
+
+    /// Records where the last conditional jump went.
+    pub fn finish(&mut self, machine: &HackMachine) {
+        if let Some(jump) = self.pending.take() {
+            if machine.pc == (jump + 1) & 0x7FFF {
+                self.not_taken[usize::from(jump)] += 1;
+            } else {
+                self.taken[usize::from(jump)] += 1;
+            }
+        }
+    }
+
+    /// How many of `rom`'s instructions ran, out of how many, and the same for conditional jumps
+    /// that went both ways.
+    #[must_use]
+    pub fn summary(&self, rom: &[u16]) -> ((usize, usize), (usize, usize)) {
+        let executed = (0..rom.len()).filter(|&i| self.counts[i] > 0).count();
+        let jumps: Vec<usize> = (0..rom.len())
+            .filter(|&i| is_conditional_jump(rom[i]))
+            .collect();
+        let both = jumps
+            .iter()
+            .filter(|&&i| self.taken[i] > 0 && self.not_taken[i] > 0)
+            .count();
+        ((executed, rom.len()), (both, jumps.len()))
+    }
+
+    /// An lcov tracefile covering the assembly `map` was made from and, given the VM translator's
+    /// map of that assembly, the `.vm` files behind it. Source paths are taken relative to the
+    /// directory of each map.
+    ///
+    /// Lines count as run as often as their busiest instruction. Each conditional jump is a pair
+    /// of branches, jumping and falling through, on the line it belongs to.
+    #[must_use]
+    pub fn lcov(
+        &self,
+        rom: &[u16],
+        map: &SourceMap,
+        map_directory: &Path,
+        vm_map: Option<(&VmMap, &Path)>,
+    ) -> String {
+        let mut records: BTreeMap<String, Record> = BTreeMap::new();
+        let asm_path = map_directory.join(&map.source).display().to_string();
+
+        let mut addresses: Vec<(u16, usize)> = map
+            .lines()
+            .filter(|&(address, _)| usize::from(address) < rom.len())
+            .collect();
+        addresses.sort_unstable();
+        for (address, asm_line) in addresses {
+            let mut files = vec![(asm_path.clone(), asm_line)];
+            if let Some((vm_map, vm_directory)) = vm_map {
+                if let Some((file, line)) = vm_map.line(asm_line) {
+                    files.push((vm_directory.join(file).display().to_string(), line));
+                }
+            }
+
+            let i = usize::from(address);
+            let count = self.counts[i];
+            for (file, line) in files {
+                let record = records.entry(file).or_default();
+                let hits = record.lines.entry(line).or_default();
+                *hits = (*hits).max(count);
+                if is_conditional_jump(rom[i]) {
+                    let outcome = (count > 0).then_some((self.taken[i], self.not_taken[i]));
+                    record.branches.entry(line).or_default().push(outcome);
+                }
+            }
+        }
+
+        let mut lcov = String::new();
+        for (file, record) in records {
+            writeln!(lcov, "TN:\nSF:{file}").unwrap();
+            let mut found = 0;
+            let mut hit = 0;
+            for (line, jumps) in &record.branches {
+                for (block, outcome) in jumps.iter().enumerate() {
+                    let [taken, not_taken] = match outcome {
+                        Some((taken, not_taken)) => [taken.to_string(), not_taken.to_string()],
+                        None => ["-".to_string(), "-".to_string()],
+                    };
+                    writeln!(lcov, "BRDA:{line},{block},0,{taken}").unwrap();
+                    writeln!(lcov, "BRDA:{line},{block},1,{not_taken}").unwrap();
+                    found += 2;
+                    if let Some((taken, not_taken)) = outcome {
+                        hit += usize::from(*taken > 0) + usize::from(*not_taken > 0);
+                    }
+                }
+            }
+            writeln!(lcov, "BRF:{found}\nBRH:{hit}").unwrap();
+            for (line, hits) in &record.lines {
+                writeln!(lcov, "DA:{line},{hits}").unwrap();
+            }
+            let lines_hit = record.lines.values().filter(|&&hits| hits > 0).count();
+            writeln!(lcov, "LF:{}\nLH:{lines_hit}", record.lines.len()).unwrap();
+            writeln!(lcov, "end_of_record").unwrap();
+        }
+        lcov
+    }
+}
+
+/// A C-instruction that jumps only on some ALU outputs.
+fn is_conditional_jump(instruction: u16) -> bool {
+    instruction & 0x8000 != 0 && !matches!(instruction & 0b111, 0 | 0b111)
+}
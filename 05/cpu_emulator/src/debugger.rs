@@ -0,0 +1,714 @@
+use crate::history::History;
+use crate::{
+    disassemble, parse_number, HackMachine, Symbols, PREDEFINED, RETURN_LABEL, STACK_BASE,
+};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+
+/// `0;JMP`, which ends every VM `call`.
+const JUMP: u16 = 0b1110_1010_1000_0111;
+/// Deepest call stack the stack view walks before assuming memory is corrupt.
+const MAX_FRAMES: usize = 64;
+/// Most arguments or stack cells shown for a frame.
+const MAX_CELLS: u16 = 16;
+
+const HELP: &str = "\
+step [N]          execute N instructions (s)
+next              execute one instruction, running a VM call through to its return (n)
+continue          run until a breakpoint, a watch changes or the program halts (c)
+reverse-step [N]  take back N instructions (rs)
+reverse-continue  run backwards to a breakpoint or watch change, or the start of the history (rc)
+break [WHERE]     set a breakpoint on a ROM address or label, or list them (b)
+delete WHERE      remove a breakpoint
+watch EXPR        show EXPR at every stop and stop when it changes (w)
+unwatch EXPR      remove a watch
+print EXPR        show the value of EXPR (p)
+last-write EXPR   show the instruction that last wrote the RAM cell EXPR names, like SP or RAM[300] (lw)
+list [WHERE]      disassemble around the PC or another ROM address (l)
+stack             show the VM call stack (bt)
+quit              leave the debugger (q)
+
+Expressions are numbers, A, D, PC, RAM[EXPR], *EXPR for the RAM cell EXPR points to, variables,
+labels, SP, LCL, ARG, THIS, THAT and R0-R15, which stand for their RAM cells, and sums of those.
+*SP-1 is the top of the VM stack.";
+
+/// An expression from `print` or `watch`, parsed once so watches are cheap to check every step.
+#[derive(Clone, Debug)]
+enum Expression {
+    Number(u16),
+    Register(char),
+    /// The RAM cell at an address
+    Cell(Box<Expression>),
+    Sum(Box<Expression>, Box<Expression>),
+    Difference(Box<Expression>, Box<Expression>),
+}
+
+struct Watch {
+    text: String,
+    expression: Expression,
+    value: u16,
+}
+
+/// An interactive debugger for a `HackMachine`, driven by gdb-style commands. See `HELP` for them.
+pub struct Debugger {
+    pub machine: HackMachine,
+    symbols: Symbols,
+    /// Labels sorted by address, for naming ROM locations
+    labels: Vec<(u16, String)>,
+    breakpoints: Vec<u16>,
+    watches: Vec<Watch>,
+    /// Cycles `continue` and `next` run before giving up
+    limit: u64,
+    /// Every step taken, for running backwards
+    history: History,
+}
+
+impl Debugger {
+    #[must_use]
+    pub fn new(machine: HackMachine, symbols: Symbols, limit: u64) -> Self {
+        let mut labels: Vec<(u16, String)> = symbols
+            .labels()
+            .map(|(name, address)| (address, name.to_string()))
+            .collect();
+        labels.sort();
+        Debugger {
+            machine,
+            symbols,
+            labels,
+            breakpoints: Vec::new(),
+            watches: Vec::new(),
+            limit,
+            history: History::default(),
+        }
+    }
+
+    /// Reads commands until `quit` or the end of the input. An empty line repeats the last command.
+    ///
+    /// # Errors
+    /// If reading the input or writing the output fails.
+    pub fn run(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        writeln!(output, "{}", self.location(self.machine.pc))?;
+        let mut lines = input.lines();
+        let mut last = String::new();
+        loop {
+            write!(output, "(hdb) ")?;
+            output.flush()?;
+            let Some(line) = lines.next().transpose()? else {
+                writeln!(output)?;
+                return Ok(());
+            };
+            let line = match line.trim() {
+                "" => last.clone(),
+                line => line.to_string(),
+            };
+            if matches!(line.as_str(), "q" | "quit") {
+                return Ok(());
+            }
+            match self.command(&line) {
+                Ok(text) => write!(output, "{text}")?,
+                Err(why) => writeln!(output, "{why}")?,
+            }
+            last = line;
+        }
+    }
+
+    /// Carries out one command and returns what it prints.
+    ///
+    /// # Errors
+    /// For unknown commands and bad arguments, with a message saying what's wrong.
+    pub fn command(&mut self, line: &str) -> Result<String, String> {
+        let (name, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let argument = argument.trim();
+        match name {
+            "s" | "step" => {
+                for _ in 0..count(argument)? {
+                    self.step();
+                }
+                self.update_watches();
+                Ok(self.stopped(""))
+            }
+            "rs" | "reverse-step" => Ok(self.reverse_step(count(argument)?)),
+            "n" | "next" => Ok(self.next()),
+            "c" | "continue" => Ok(self.resume(|_| false)),
+            "rc" | "reverse-continue" => Ok(self.reverse()),
+            "b" | "break" if argument.is_empty() => {
+                Ok(self
+                    .breakpoints
+                    .iter()
+                    .fold(String::new(), |mut text, &address| {
+                        writeln!(text, "{}", self.location(address)).unwrap();
+                        text
+                    }))
+            }
+            "b" | "break" => {
+                let address = self.rom_address(argument)?;
+                if !self.breakpoints.contains(&address) {
+                    self.breakpoints.push(address);
+                }
+                Ok(format!("Breakpoint at {}\n", self.location(address)))
+            }
+            "delete" => {
+                let address = self.rom_address(argument)?;
+                self.breakpoints.retain(|&breakpoint| breakpoint != address);
+                Ok(String::new())
+            }
+            "w" | "watch" => {
+                let expression = self.parse(argument)?;
+                let value = self.evaluate(&expression);
+                self.watches.push(Watch {
+                    text: argument.to_string(),
+                    expression,
+                    value,
+                });
+                Ok(format!("{argument} = {}\n", show(value)))
+            }
+            "unwatch" => {
+                self.watches.retain(|watch| watch.text != argument);
+                Ok(String::new())
+            }
+            "p" | "print" => {
+                let expression = self.parse(argument)?;
+                Ok(format!(
+                    "{argument} = {}\n",
+                    show(self.evaluate(&expression))
+                ))
+            }
+            "lw" | "last-write" => self.last_write(argument),
+            "l" | "list" => {
+                let center = if argument.is_empty() {
+                    self.machine.pc
+                } else {
+                    self.rom_address(argument)?
+                };
+                Ok(self.list(center))
+            }
+            "bt" | "stack" => Ok(self.stack()),
+            "h" | "help" => Ok(format!("{HELP}\n")),
+            _ => Err(format!("Unknown command {name}, try help")),
+        }
+    }
+
+    /// Steps once, except that a VM `call` runs until the callee returns to it.
+    fn next(&mut self) -> String {
+        let pc = self.machine.pc;
+        let instruction = self.machine.fetch(pc);
+        let return_address = (pc + 1) & 0x7FFF;
+        let is_call = instruction == JUMP
+            && self
+                .labels_at(return_address)
+                .any(|label| label.contains(RETURN_LABEL));
+        if !is_call {
+            return self.command("step").unwrap();
+        }
+
+        // Recursive calls pass the same address, but only the return to this frame restores the
+        // caller's LCL, which the call has already saved in the callee's frame
+        let frame = self.machine.read(self.machine.read(1).wrapping_sub(4));
+        self.resume(|machine| machine.pc == return_address && machine.read(1) == frame)
+    }
+
+    /// Runs until `done` holds, a breakpoint is reached, a watch changes, the program halts or the
+    /// cycle limit runs out.
+    fn resume(&mut self, done: impl Fn(&HackMachine) -> bool) -> String {
+        let mut cycles = 0;
+        loop {
+            if self.machine.halted() {
+                return self.stopped("Halted\n");
+            }
+            if cycles == self.limit {
+                return self.stopped(&format!("Stopped after {cycles} cycles\n"));
+            }
+            self.step();
+            cycles += 1;
+
+            let changes = self.update_watches();
+            if !changes.is_empty() {
+                return self.stopped(&changes);
+            }
+            if done(&self.machine) {
+                return self.stopped("");
+            }
+            if self.breakpoints.contains(&self.machine.pc) {
+                return self.stopped("Breakpoint\n");
+            }
+        }
+    }
+
+    fn reverse_step(&mut self, count: u64) -> String {
+        let mut undone = 0;
+        while undone < count && self.history.undo(&mut self.machine) {
+            undone += 1;
+        }
+        self.update_watches();
+        if undone < count {
+            self.stopped("Reached the start of the history\n")
+        } else {
+            self.stopped("")
+        }
+    }
+
+    fn last_write(&self, argument: &str) -> Result<String, String> {
+        // Name the cell itself, so SP means RAM[0] rather than the address SP holds
+        let address = match self.parse(argument)? {
+            Expression::Cell(address) => self.evaluate(&address),
+            expression => self.evaluate(&expression),
+        };
+        Ok(match self.history.last_write(&self.machine, address) {
+            Some(write) => format!(
+                "RAM[{address}] changed from {} to {} {} steps ago, on cycle {}, by\n{}\n",
+                show(write.old),
+                show(write.new),
+                write.steps_ago,
+                write.cycle,
+                self.location(write.pc)
+            ),
+            None => format!(
+                "Nothing has written RAM[{address}] in the last {} steps\n",
+                self.history.len()
+            ),
+        })
+    }
+
+    /// Undoes steps until reaching a breakpoint, a watch changes or the history runs out.
+    fn reverse(&mut self) -> String {
+        loop {
+            if !self.history.undo(&mut self.machine) {
+                return self.stopped("Reached the start of the history\n");
+            }
+            let changes = self.update_watches();
+            if !changes.is_empty() {
+                return self.stopped(&changes);
+            }
+            if self.breakpoints.contains(&self.machine.pc) {
+                return self.stopped("Breakpoint\n");
+            }
+        }
+    }
+
+    fn step(&mut self) {
+        self.history.record(&self.machine);
+        self.machine.step();
+    }
+
+    /// Re-evaluates the watches, describing the ones that changed.
+    fn update_watches(&mut self) -> String {
+        let mut changes = String::new();
+        for i in 0..self.watches.len() {
+            let value = self.evaluate(&self.watches[i].expression);
+            let watch = &mut self.watches[i];
+            if value != watch.value {
+                writeln!(
+                    changes,
+                    "Watch {} changed from {} to {}",
+                    watch.text,
+                    show(watch.value),
+                    show(value)
+                )
+                .unwrap();
+                watch.value = value;
+            }
+        }
+        changes
+    }
+
+    /// What's printed when execution stops: the reason, the watches and the next instruction.
+    fn stopped(&self, reason: &str) -> String {
+        let mut text = reason.to_string();
+        for watch in &self.watches {
+            writeln!(text, "  {} = {}", watch.text, show(watch.value)).unwrap();
+        }
+        writeln!(text, "{}", self.location(self.machine.pc)).unwrap();
+        text
+    }
+
+    /// A line of disassembly, like `   17  LOOP+2  D=M`, marked `*` for a breakpoint and `=>` for
+    /// the PC.
+    fn location(&self, address: u16) -> String {
+        let marker = if address == self.machine.pc {
+            "=>"
+        } else {
+            "  "
+        };
+        let breakpoint = if self.breakpoints.contains(&address) {
+            '*'
+        } else {
+            ' '
+        };
+        let name = match self.labels.iter().rev().find(|(at, _)| *at <= address) {
+            Some((at, label)) if *at == address => label.clone(),
+            Some((at, label)) => format!("{label}+{}", address - at),
+            None => String::new(),
+        };
+        format!(
+            "{marker}{breakpoint}{address:5}  {name:24}  {}",
+            disassemble(self.machine.fetch(address))
+        )
+    }
+
+    fn list(&self, center: u16) -> String {
+        let mut text = String::new();
+        for address in center.saturating_sub(5)..=center.saturating_add(5).min(0x7FFF) {
+            for label in self.labels_at(address) {
+                writeln!(text, "({label})").unwrap();
+            }
+            writeln!(text, "{}", self.location(address)).unwrap();
+        }
+        text
+    }
+
+    fn labels_at(&self, address: u16) -> impl Iterator<Item = &str> {
+        self.labels
+            .iter()
+            .filter(move |(at, _)| *at == address)
+            .map(|(_, label)| label.as_str())
+    }
+
+    /// The VM function holding a ROM address: the closest label before it that isn't one of the
+    /// translator's `Function$label` labels.
+    fn function_at(&self, address: u16) -> &str {
+        self.labels
+            .iter()
+            .rev()
+            .find(|(at, label)| *at <= address && !label.contains('$'))
+            .map_or("?", |(_, label)| label.as_str())
+    }
+
+    /// Walks the VM call stack through the frames `call` pushes below each function's locals:
+    /// return address, then the caller's LCL, ARG, THIS and THAT.
+    fn stack(&self) -> String {
+        let read = |address: u16| self.machine.read(address);
+        let mut text = String::new();
+        let mut pc = self.machine.pc;
+        let (mut lcl, mut arg, mut top) = (read(1), read(2), read(0));
+        // Frames save their caller's pointers, so only the innermost one's are RAM[3] and RAM[4]
+        let (mut this, mut that) = (read(3), read(4));
+
+        for depth in 0..MAX_FRAMES {
+            let frame = lcl.wrapping_sub(5);
+            let read_frame = |offset: u16| read(frame.wrapping_add(offset));
+            writeln!(
+                text,
+                "#{depth} {} at {pc}  LCL={lcl} ARG={arg} THIS={this} THAT={that}",
+                self.function_at(pc),
+            )
+            .unwrap();
+            writeln!(text, "    arguments {}", cells(read, arg, frame)).unwrap();
+            writeln!(text, "    locals and stack {}", cells(read, lcl, top)).unwrap();
+
+            // The caller's frame sits lower on the stack, and the bootstrap's LCL is below the stack
+            let caller_lcl = read_frame(1);
+            if caller_lcl >= lcl || caller_lcl < STACK_BASE + 5 {
+                break;
+            }
+            pc = read_frame(0);
+            top = arg;
+            (lcl, arg) = (caller_lcl, read_frame(2));
+            (this, that) = (read_frame(3), read_frame(4));
+        }
+        text
+    }
+
+    fn rom_address(&self, text: &str) -> Result<u16, String> {
+        self.symbols
+            .rom_address(text)
+            .ok_or_else(|| format!("Unknown ROM address {text}"))
+    }
+
+    fn parse(&self, text: &str) -> Result<Expression, String> {
+        let tokens = tokenize(text);
+        let mut position = 0;
+        let expression = self.parse_expression(&tokens, &mut position)?;
+        if position == tokens.len() {
+            Ok(expression)
+        } else {
+            Err(format!("Unexpected {} in {text}", tokens[position]))
+        }
+    }
+
+    fn parse_expression(
+        &self,
+        tokens: &[String],
+        position: &mut usize,
+    ) -> Result<Expression, String> {
+        // `*` covers the whole sum after it, so *SP-1 is RAM[SP-1], the top of the stack
+        if tokens.get(*position).is_some_and(|token| token == "*") {
+            *position += 1;
+            let address = self.parse_expression(tokens, position)?;
+            return Ok(Expression::Cell(Box::new(address)));
+        }
+
+        let mut expression = self.parse_term(tokens, position)?;
+        while let Some(operator) = tokens
+            .get(*position)
+            .filter(|token| *token == "+" || *token == "-")
+        {
+            *position += 1;
+            let right = Box::new(self.parse_term(tokens, position)?);
+            expression = if operator == "+" {
+                Expression::Sum(Box::new(expression), right)
+            } else {
+                Expression::Difference(Box::new(expression), right)
+            };
+        }
+        Ok(expression)
+    }
+
+    fn parse_term(&self, tokens: &[String], position: &mut usize) -> Result<Expression, String> {
+        let token = tokens
+            .get(*position)
+            .ok_or_else(|| "Expression ends early".to_string())?;
+        *position += 1;
+        let cell = |address| Ok(Expression::Cell(Box::new(Expression::Number(address))));
+        match token.as_str() {
+            "A" | "D" | "PC" => Ok(Expression::Register(token.chars().next().unwrap())),
+            // A negative number, or the negative of any other term
+            "-" => {
+                let term = self.parse_term(tokens, position)?;
+                Ok(match term {
+                    Expression::Number(number) => Expression::Number(number.wrapping_neg()),
+                    term => Expression::Difference(Box::new(Expression::Number(0)), Box::new(term)),
+                })
+            }
+            "(" => {
+                let expression = self.parse_expression(tokens, position)?;
+                expect(tokens, position, ")")?;
+                Ok(expression)
+            }
+            "RAM" => {
+                expect(tokens, position, "[")?;
+                let address = self.parse_expression(tokens, position)?;
+                expect(tokens, position, "]")?;
+                Ok(Expression::Cell(Box::new(address)))
+            }
+            _ => {
+                if let Ok(number) = parse_number(token) {
+                    return Ok(Expression::Number(number));
+                }
+                if let Some(&(_, address)) = PREDEFINED.iter().find(|(name, _)| name == token) {
+                    return cell(address);
+                }
+                if let Some(register) = token
+                    .strip_prefix('R')
+                    .and_then(|number| number.parse::<u16>().ok())
+                    .filter(|number| *number < 16)
+                {
+                    return cell(register);
+                }
+                if let Some(address) = self.symbols.variable(token) {
+                    return cell(address);
+                }
+                self.symbols
+                    .label(token)
+                    .map(Expression::Number)
+                    .ok_or_else(|| format!("Unknown name {token}"))
+            }
+        }
+    }
+
+    fn evaluate(&self, expression: &Expression) -> u16 {
+        match expression {
+            Expression::Number(number) => *number,
+            Expression::Register('A') => self.machine.a,
+            Expression::Register('D') => self.machine.d,
+            Expression::Register(_) => self.machine.pc,
+            Expression::Cell(address) => self.machine.read(self.evaluate(address)),
+            Expression::Sum(left, right) => self.evaluate(left).wrapping_add(self.evaluate(right)),
+            Expression::Difference(left, right) => {
+                self.evaluate(left).wrapping_sub(self.evaluate(right))
+            }
+        }
+    }
+}
+
+/// The count a `step` or `reverse-step` argument gives, 1 if there isn't one.
+fn count(argument: &str) -> Result<u64, String> {
+    if argument.is_empty() {
+        Ok(1)
+    } else {
+        argument
+            .parse()
+            .map_err(|_| format!("Invalid count {argument}"))
+    }
+}
+
+/// Splits an expression into names and numbers, and single characters for everything else.
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let is_name = |c: &char| c.is_alphanumeric() || "_.$:".contains(*c);
+        if is_name(&c) {
+            let mut token = c.to_string();
+            while let Some(c) = chars.next_if(is_name) {
+                token.push(c);
+            }
+            tokens.push(token);
+        } else if !c.is_whitespace() {
+            tokens.push(c.to_string());
+        }
+    }
+    tokens
+}
+
+fn expect(tokens: &[String], position: &mut usize, expected: &str) -> Result<(), String> {
+    if tokens.get(*position).is_some_and(|token| token == expected) {
+        *position += 1;
+        Ok(())
+    } else {
+        Err(format!("Expected {expected}"))
+    }
+}
+
+/// A value in signed decimal and hexadecimal.
+fn show(value: u16) -> String {
+    format!("{} ({value:#06x})", value.cast_signed())
+}
+
+/// The values in RAM from `first` up to `end`, shortened when there are many.
+fn cells(read: impl Fn(u16) -> u16, first: u16, end: u16) -> String {
+    let count = end.saturating_sub(first);
+    let values: Vec<String> = (first..first + count.min(MAX_CELLS))
+        .map(|address| read(address).cast_signed().to_string())
+        .collect();
+    let more = if count > MAX_CELLS { ", ..." } else { "" };
+    format!("[{}{more}]", values.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A VM-style call to `f`, which restores the caller's LCL and returns to `Main$ret.0`.
+    const CALL: [u16; 10] = [
+        4, 0xEA87, // (Main) @f 0;JMP
+        2, 0xEA87, // (Main$ret.0) @Main$ret.0 0;JMP
+        300, 0xEC10, // (f) @300 D=A
+        1, 0xE308, // @LCL M=D
+        2, 0xEA87, // @Main$ret.0 0;JMP
+    ];
+
+    fn debugger() -> Debugger {
+        let symbols = Symbols::parse("label Main 0\nlabel Main$ret.0 2\nlabel f 4\n").unwrap();
+        Debugger::new(HackMachine::new(CALL.to_vec()), symbols, 1000)
+    }
+
+    /// Main, with LCL 261 and ARG 256, has called f with the argument 7 and f has pushed two
+    /// locals.
+    fn in_call() -> Debugger {
+        let mut debugger = debugger();
+        let machine = &mut debugger.machine;
+        for (address, value) in [
+            (0, 271),
+            (1, 269),
+            (2, 263),
+            (3, 5000),
+            (4, 6000),
+            (261, 1),
+            (262, 2),
+            (263, 7),
+            (264, 2),
+            (265, 261),
+            (266, 256),
+            (267, 3000),
+            (268, 4000),
+            (269, 10),
+            (270, 11),
+        ] {
+            machine.write(address, value);
+        }
+        machine.pc = 5;
+        debugger
+    }
+
+    #[test]
+    fn walks_call_stack() {
+        assert_eq!(
+            in_call().stack(),
+            "\
+#0 f at 5  LCL=269 ARG=263 THIS=5000 THAT=6000
+    arguments [7]
+    locals and stack [10, 11]
+#1 Main at 2  LCL=261 ARG=256 THIS=3000 THAT=4000
+    arguments []
+    locals and stack [1, 2]
+"
+        );
+    }
+
+    #[test]
+    fn next_runs_call_to_its_return() {
+        let mut debugger = debugger();
+        debugger.machine.write(0, 261);
+        debugger.machine.write(1, 261);
+        debugger.machine.write(257, 300);
+        debugger.command("step").unwrap();
+
+        let text = debugger.command("next").unwrap();
+        assert!(text.contains("Main$ret.0"));
+        assert_eq!((debugger.machine.pc, debugger.machine.read(1)), (2, 300));
+        assert_eq!(debugger.machine.cycles, 8);
+
+        // Anything other than a call is a single step
+        debugger.command("next").unwrap();
+        assert_eq!(debugger.machine.pc, 3);
+    }
+
+    #[test]
+    fn sets_breakpoints_on_labels_and_addresses() {
+        let mut debugger = debugger();
+        debugger.command("break f").unwrap();
+        debugger.command("b 8").unwrap();
+        debugger.command("b 8").unwrap();
+        assert_eq!(debugger.breakpoints, [4, 8]);
+        assert_eq!(
+            debugger.command("break g"),
+            Err("Unknown ROM address g".to_string())
+        );
+
+        assert!(debugger
+            .command("continue")
+            .unwrap()
+            .starts_with("Breakpoint\n"));
+        assert_eq!(debugger.machine.pc, 4);
+        debugger.command("delete 8").unwrap();
+        debugger.command("delete f").unwrap();
+        assert!(debugger.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn evaluates_expressions() {
+        let mut debugger = in_call();
+        let mut print = |expression: &str| debugger.command(&format!("print {expression}"));
+        assert_eq!(print("*SP-1"), Ok("*SP-1 = 11 (0x000b)\n".to_string()));
+        assert_eq!(
+            print("RAM[LCL + 1]"),
+            Ok("RAM[LCL + 1] = 11 (0x000b)\n".to_string())
+        );
+        assert_eq!(print("-1"), Ok("-1 = -1 (0xffff)\n".to_string()));
+        assert_eq!(
+            print("0x10-R3"),
+            Ok("0x10-R3 = -4984 (0xec88)\n".to_string())
+        );
+        assert_eq!(
+            print("(ARG+1)-f"),
+            Ok("(ARG+1)-f = 260 (0x0104)\n".to_string())
+        );
+        assert_eq!(print("PC"), Ok("PC = 5 (0x0005)\n".to_string()));
+        assert_eq!(print("(1"), Err("Expected )".to_string()));
+        assert_eq!(print("1 2"), Err("Unexpected 2 in 1 2".to_string()));
+        assert_eq!(print("nothing"), Err("Unknown name nothing".to_string()));
+    }
+
+    #[test]
+    fn watches_stop_when_they_change() {
+        let mut debugger = in_call();
+        debugger.machine.pc = 4;
+        assert_eq!(
+            debugger.command("watch LCL"),
+            Ok("LCL = 269 (0x010d)\n".to_string())
+        );
+        let text = debugger.command("continue").unwrap();
+        assert!(text.starts_with("Watch LCL changed from 269 (0x010d) to 300 (0x012c)\n"));
+        assert_eq!(debugger.machine.pc, 8);
+    }
+}
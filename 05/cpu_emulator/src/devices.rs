@@ -0,0 +1,436 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// Cycles per timer tick by default: 60 ticks a second on a 1 MHz machine, like CHIP-8's timers.
+pub const TIMER_PERIOD: u64 = 16_667;
+
+/// A peripheral mapped into the unused addresses above the keyboard with `HackMachine::attach`.
+///
+/// Offsets are relative to where the device is attached, and `cycles` is the machine's cycle
+/// count at the time of the access.
+pub trait Device: fmt::Debug {
+    /// Words of address space the device takes up.
+    fn size(&self) -> u16;
+
+    /// What reading `offset` would give, without anything a read does to the device. Debuggers
+    /// see memory through this.
+    fn peek(&self, offset: u16, cycles: u64) -> u16;
+
+    /// What the program gets reading `offset`.
+    fn read(&mut self, offset: u16, cycles: u64) -> u16 {
+        self.peek(offset, cycles)
+    }
+
+    fn write(&mut self, offset: u16, value: u16, cycles: u64);
+
+    /// The name command line specs use for this kind of device.
+    fn kind(&self) -> &'static str;
+
+    /// Whatever state the device has beyond its configuration, for save states.
+    fn save(&self) -> Vec<u16> {
+        Vec::new()
+    }
+
+    /// Puts back state from `save`.
+    ///
+    /// # Errors
+    /// If the state isn't one this kind of device saves.
+    fn restore(&mut self, state: &[u16]) -> Result<(), String> {
+        if state.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid {} state", self.kind()))
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn Device>;
+}
+
+impl Clone for Box<dyn Device> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// A device from a command line spec of `KIND@ADDRESS` with an optional `:OPTION`:
+///
+/// ```text
+/// timer@0x6001:16667
+/// rng@0x6004:1234
+/// console@0x6005
+/// storage@0x6006:disk.bin
+/// ```
+///
+/// The timer's option is its period in cycles, the random source's its seed and storage's the file
+/// holding it.
+///
+/// # Errors
+/// On an unknown kind, a missing or invalid address or an invalid or missing option.
+pub fn parse(spec: &str) -> Result<(u16, Box<dyn Device>), String> {
+    let invalid = || format!("Invalid device {spec}, expected KIND@ADDRESS[:OPTION]");
+    let (kind, rest) = spec.split_once('@').ok_or_else(invalid)?;
+    let (address, option) = match rest.split_once(':') {
+        Some((address, option)) => (address, Some(option)),
+        None => (rest, None),
+    };
+    let address = match address.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => address.parse(),
+    }
+    .map_err(|_| invalid())?;
+    let number = |default: u64| {
+        option.map_or(Ok(default), |option| {
+            option
+                .parse()
+                .map_err(|_| format!("Invalid option for {kind}: {option}"))
+        })
+    };
+
+    let device: Box<dyn Device> = match kind {
+        "timer" => Box::new(Timer::new(number(TIMER_PERIOD)?.max(1))),
+        "rng" => Box::new(Random::new(number(u64::from(Random::SEED))?)),
+        "console" => Box::new(Console),
+        "storage" => {
+            let path = option.ok_or("storage needs a file, as storage@ADDRESS:FILE")?;
+            Box::new(Storage::open(path.into())?)
+        }
+        _ => {
+            return Err(format!(
+                "Unknown device {kind}, expected timer, rng, console or storage"
+            ))
+        }
+    };
+    Ok((address, device))
+}
+
+/// Counts cycles and runs a countdown.
+///
+/// Offsets 0 and 1 are the low and high words of the cycle count, which programs can read for
+/// timing. Offset 2 counts down by one every `period` cycles from whatever was last written to
+/// it, stopping at 0.
+#[derive(Clone, Debug)]
+pub struct Timer {
+    period: u64,
+    /// The value the countdown was set to, and when
+    countdown: (u16, u64),
+}
+
+impl Timer {
+    #[must_use]
+    pub fn new(period: u64) -> Self {
+        Timer {
+            period,
+            countdown: (0, 0),
+        }
+    }
+}
+
+impl Device for Timer {
+    fn size(&self) -> u16 {
+        3
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn peek(&self, offset: u16, cycles: u64) -> u16 {
+        match offset {
+            0 => cycles as u16,
+            1 => (cycles >> 16) as u16,
+            _ => {
+                let (value, set) = self.countdown;
+                let ticks = (cycles - set) / self.period;
+                u16::try_from(u64::from(value).saturating_sub(ticks)).unwrap_or(0)
+            }
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u16, cycles: u64) {
+        if offset == 2 {
+            self.countdown = (value, cycles);
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        "timer"
+    }
+
+    fn save(&self) -> Vec<u16> {
+        let (value, set) = self.countdown;
+        let mut state = vec![value];
+        state.extend(words(set));
+        state
+    }
+
+    fn restore(&mut self, state: &[u16]) -> Result<(), String> {
+        let &[value, a, b, c, d] = state else {
+            return Err("Invalid timer state".to_string());
+        };
+        self.countdown = (value, u64_from_words([a, b, c, d]));
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+/// Gives a new pseudorandom word on each read, from a xorshift generator. Writing a word reseeds
+/// it, so programs can mix in something like how long the player took to press a key.
+#[derive(Clone, Debug)]
+pub struct Random {
+    state: u32,
+}
+
+impl Random {
+    pub const SEED: u32 = 0x2545_F491;
+
+    /// A generator starting from `seed`, using only its low 32 bits.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn new(seed: u64) -> Self {
+        let mut random = Random { state: 0 };
+        random.seed(seed as u32);
+        random
+    }
+
+    fn seed(&mut self, seed: u32) {
+        // Xorshift never leaves 0
+        self.state = if seed == 0 { Random::SEED } else { seed };
+    }
+}
+
+impl Device for Random {
+    fn size(&self) -> u16 {
+        1
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn peek(&self, _: u16, _: u64) -> u16 {
+        (self.state >> 16) as u16
+    }
+
+    fn read(&mut self, offset: u16, cycles: u64) -> u16 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.peek(offset, cycles)
+    }
+
+    fn write(&mut self, _: u16, value: u16, _: u64) {
+        self.seed(self.state ^ u32::from(value));
+    }
+
+    fn kind(&self) -> &'static str {
+        "rng"
+    }
+
+    fn save(&self) -> Vec<u16> {
+        words(self.state.into())[..2].to_vec()
+    }
+
+    fn restore(&mut self, state: &[u16]) -> Result<(), String> {
+        let &[low, high] = state else {
+            return Err("Invalid rng state".to_string());
+        };
+        self.seed(u32::from(low) | u32::from(high) << 16);
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+/// Prints each character written to it on the host's standard output, in the Hack character set
+/// where 128 is a newline. Reads give 0.
+#[derive(Clone, Debug)]
+pub struct Console;
+
+impl Device for Console {
+    fn size(&self) -> u16 {
+        1
+    }
+
+    fn peek(&self, _: u16, _: u64) -> u16 {
+        0
+    }
+
+    fn write(&mut self, _: u16, value: u16, _: u64) {
+        let character = match value {
+            128 => '\n',
+            _ => char::from_u32(value.into()).unwrap_or(char::REPLACEMENT_CHARACTER),
+        };
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "{character}").and_then(|()| stdout.flush());
+    }
+
+    fn kind(&self) -> &'static str {
+        "console"
+    }
+
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+/// Up to 64K words kept in a file of little-endian words, which persists between runs.
+///
+/// Offset 0 is the address within the storage, and offset 1 reads or writes the word there and
+/// moves the address on to the next word. Words past the end of the file read as 0, and writing
+/// them grows it.
+#[derive(Clone, Debug)]
+pub struct Storage {
+    path: PathBuf,
+    words: Vec<u16>,
+    address: u16,
+}
+
+impl Storage {
+    /// Storage kept in `path`, which doesn't need to exist yet.
+    ///
+    /// # Errors
+    /// If the file exists but can't be read.
+    pub fn open(path: PathBuf) -> Result<Self, String> {
+        let words = match fs::read(&path) {
+            Ok(bytes) => bytes
+                .chunks(2)
+                .map(|word| u16::from_le_bytes([word[0], word.get(1).copied().unwrap_or(0)]))
+                .collect(),
+            Err(why) if why.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(why) => return Err(format!("couldn't open {}: {why}", path.display())),
+        };
+        Ok(Storage {
+            path,
+            words,
+            address: 0,
+        })
+    }
+
+    fn save(&self, address: u16, value: u16) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?;
+        file.seek(SeekFrom::Start(u64::from(address) * 2))?;
+        file.write_all(&value.to_le_bytes())
+    }
+}
+
+impl Device for Storage {
+    fn size(&self) -> u16 {
+        2
+    }
+
+    fn peek(&self, offset: u16, _: u64) -> u16 {
+        match offset {
+            0 => self.address,
+            _ => self
+                .words
+                .get(usize::from(self.address))
+                .copied()
+                .unwrap_or(0),
+        }
+    }
+
+    fn read(&mut self, offset: u16, cycles: u64) -> u16 {
+        let value = self.peek(offset, cycles);
+        if offset == 1 {
+            self.address = self.address.wrapping_add(1);
+        }
+        value
+    }
+
+    /// # Panics
+    /// If the file can't be written.
+    fn write(&mut self, offset: u16, value: u16, _: u64) {
+        if offset == 0 {
+            self.address = value;
+            return;
+        }
+        let address = usize::from(self.address);
+        if address >= self.words.len() {
+            self.words.resize(address + 1, 0);
+        }
+        self.words[address] = value;
+        if let Err(why) = self.save(self.address, value) {
+            panic!("couldn't write {}: {why}", self.path.display())
+        }
+        self.address = self.address.wrapping_add(1);
+    }
+
+    fn kind(&self) -> &'static str {
+        "storage"
+    }
+
+    /// Only the address, since the words themselves live on in the file.
+    fn save(&self) -> Vec<u16> {
+        vec![self.address]
+    }
+
+    fn restore(&mut self, state: &[u16]) -> Result<(), String> {
+        let &[address] = state else {
+            return Err("Invalid storage state".to_string());
+        };
+        self.address = address;
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+/// The bank register of a banked `HackMachine`, holding the ROM bank shown from `WINDOW` up.
+#[derive(Clone, Debug, Default)]
+pub struct Bank {
+    selected: u16,
+}
+
+impl Device for Bank {
+    fn size(&self) -> u16 {
+        1
+    }
+
+    fn peek(&self, _: u16, _: u64) -> u16 {
+        self.selected
+    }
+
+    fn write(&mut self, _: u16, value: u16, _: u64) {
+        self.selected = value;
+    }
+
+    fn kind(&self) -> &'static str {
+        "bank"
+    }
+
+    fn save(&self) -> Vec<u16> {
+        vec![self.selected]
+    }
+
+    fn restore(&mut self, state: &[u16]) -> Result<(), String> {
+        let &[selected] = state else {
+            return Err("Invalid bank state".to_string());
+        };
+        self.selected = selected;
+        Ok(())
+    }
+
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+}
+
+/// The words of a `u64`, least significant first.
+fn words(value: u64) -> [u16; 4] {
+    let bytes = value.to_le_bytes();
+    [0, 2, 4, 6].map(|i| u16::from_le_bytes([bytes[i], bytes[i + 1]]))
+}
+
+fn u64_from_words(words: [u16; 4]) -> u64 {
+    words
+        .iter()
+        .rev()
+        .fold(0, |value, &word| value << 16 | u64::from(word))
+}
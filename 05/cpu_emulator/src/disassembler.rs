@@ -0,0 +1,62 @@
+use std::fmt::Write;
+
+/// The zx, nx, zy, ny, f and no bits of each computation the assembler knows, for a = 0. The same
+/// bits with a = 1 compute the M form, which swaps A for M.
+pub(crate) const COMPUTATIONS: [(u16, &str); 18] = [
+    (0b10_1010, "0"),
+    (0b11_1111, "1"),
+    (0b11_1010, "-1"),
+    (0b00_1100, "D"),
+    (0b11_0000, "A"),
+    (0b00_1101, "!D"),
+    (0b11_0001, "!A"),
+    (0b00_1111, "-D"),
+    (0b11_0011, "-A"),
+    (0b01_1111, "D+1"),
+    (0b11_0111, "A+1"),
+    (0b00_1110, "D-1"),
+    (0b11_0010, "A-1"),
+    (0b00_0010, "D+A"),
+    (0b01_0011, "D-A"),
+    (0b00_0111, "A-D"),
+    (0b00_0000, "D&A"),
+    (0b01_0101, "D|A"),
+];
+
+const JUMPS: [&str; 8] = ["", "JGT", "JEQ", "JGE", "JLT", "JNE", "JLE", "JMP"];
+
+/// An instruction in assembly syntax, like `@17` or `AM=M-1;JNE`. ALU settings the assembler has
+/// no mnemonic for print as their seven a and c bits.
+#[must_use]
+pub fn disassemble(instruction: u16) -> String {
+    if instruction & 0x8000 == 0 {
+        return format!("@{instruction}");
+    }
+
+    let mut text = String::new();
+    for (bit, register) in [(0b10_0000, 'A'), (0b00_1000, 'M'), (0b01_0000, 'D')] {
+        if instruction & bit != 0 {
+            text.push(register);
+        }
+    }
+    if !text.is_empty() {
+        text.push('=');
+    }
+
+    let control = instruction >> 6 & 0b11_1111;
+    let uses_m = instruction & 0x1000 != 0;
+    match COMPUTATIONS.iter().find(|(bits, _)| *bits == control) {
+        Some((_, computation)) if !uses_m => text.push_str(computation),
+        Some((_, computation)) if computation.contains('A') => {
+            text.push_str(&computation.replace('A', "M"));
+        }
+        _ => write!(text, "{:#09b}", instruction >> 6 & 0b111_1111).unwrap(),
+    }
+
+    let jump = JUMPS[usize::from(instruction & 0b111)];
+    if !jump.is_empty() {
+        text.push(';');
+        text.push_str(jump);
+    }
+    text
+}
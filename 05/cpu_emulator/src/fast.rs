@@ -0,0 +1,382 @@
+use crate::disassembler::COMPUTATIONS;
+use crate::machine::is_unconditional_jump;
+use crate::{alu, jumps, HackMachine, RunResult, Stop, ROM_SIZE, SCREEN};
+
+/// A computation decoded from its zx, nx, zy, ny, f and no bits, with Y standing for A or M.
+#[derive(Clone, Copy, Debug)]
+enum Computation {
+    Zero,
+    One,
+    MinusOne,
+    D,
+    Y,
+    NotD,
+    NotY,
+    NegateD,
+    NegateY,
+    DPlusOne,
+    YPlusOne,
+    DMinusOne,
+    YMinusOne,
+    DPlusY,
+    DMinusY,
+    YMinusD,
+    DAndY,
+    DOrY,
+    /// Control bits no mnemonic covers, left to the ALU
+    Other(u16),
+}
+
+impl Computation {
+    fn decode(control: u16) -> Self {
+        let mnemonic = COMPUTATIONS
+            .iter()
+            .find(|(bits, _)| *bits == control)
+            .map_or("", |(_, mnemonic)| *mnemonic);
+        match mnemonic {
+            "0" => Computation::Zero,
+            "1" => Computation::One,
+            "-1" => Computation::MinusOne,
+            "D" => Computation::D,
+            "A" => Computation::Y,
+            "!D" => Computation::NotD,
+            "!A" => Computation::NotY,
+            "-D" => Computation::NegateD,
+            "-A" => Computation::NegateY,
+            "D+1" => Computation::DPlusOne,
+            "A+1" => Computation::YPlusOne,
+            "D-1" => Computation::DMinusOne,
+            "A-1" => Computation::YMinusOne,
+            "D+A" => Computation::DPlusY,
+            "D-A" => Computation::DMinusY,
+            "A-D" => Computation::YMinusD,
+            "D&A" => Computation::DAndY,
+            "D|A" => Computation::DOrY,
+            _ => Computation::Other(control),
+        }
+    }
+
+    fn compute(self, d: u16, y: u16) -> u16 {
+        match self {
+            Computation::Zero => 0,
+            Computation::One => 1,
+            Computation::MinusOne => 0xFFFF,
+            Computation::D => d,
+            Computation::Y => y,
+            Computation::NotD => !d,
+            Computation::NotY => !y,
+            Computation::NegateD => d.wrapping_neg(),
+            Computation::NegateY => y.wrapping_neg(),
+            Computation::DPlusOne => d.wrapping_add(1),
+            Computation::YPlusOne => y.wrapping_add(1),
+            Computation::DMinusOne => d.wrapping_sub(1),
+            Computation::YMinusOne => y.wrapping_sub(1),
+            Computation::DPlusY => d.wrapping_add(y),
+            Computation::DMinusY => d.wrapping_sub(y),
+            Computation::YMinusD => y.wrapping_sub(d),
+            Computation::DAndY => d & y,
+            Computation::DOrY => d | y,
+            Computation::Other(control) => alu(control, d, y),
+        }
+    }
+}
+
+/// A C-instruction taken apart once.
+#[derive(Clone, Copy, Debug)]
+struct Compute {
+    computation: Computation,
+    /// Whether Y is M rather than A
+    reads_m: bool,
+    /// The instruction itself, for its destination and jump bits
+    instruction: u16,
+    /// Whether it's a `0;JMP` that halts when A points at it
+    unconditional: bool,
+}
+
+impl Compute {
+    fn decode(instruction: u16) -> Self {
+        Compute {
+            computation: Computation::decode(instruction >> 6 & 0b11_1111),
+            reads_m: instruction & 0x1000 != 0,
+            instruction,
+            unconditional: is_unconditional_jump(instruction),
+        }
+    }
+
+    fn jumps(self) -> bool {
+        self.instruction & 0b111 != 0
+    }
+
+    /// Runs the instruction, returning where it jumps if it does.
+    fn execute(self, machine: &mut HackMachine) -> Option<u16> {
+        let address = machine.a & 0x7FFF;
+        let y = if !self.reads_m {
+            machine.a
+        } else if address < SCREEN {
+            machine.ram[usize::from(address)]
+        } else {
+            machine.load(address)
+        };
+        let out = self.computation.compute(machine.d, y);
+
+        if self.instruction & 0b00_1000 != 0 {
+            if address < SCREEN {
+                machine.ram[usize::from(address)] = out;
+            } else {
+                machine.write(address, out);
+            }
+        }
+        let jump_target = address;
+        if self.instruction & 0b10_0000 != 0 {
+            machine.a = out;
+        }
+        if self.instruction & 0b01_0000 != 0 {
+            machine.d = out;
+        }
+        (self.jumps() && jumps(self.instruction, out)).then_some(jump_target)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    /// `@value`
+    Load(u16),
+    Compute(Compute),
+    /// `@value` and the C-instruction after it, run as one
+    LoadCompute(u16, Compute),
+}
+
+/// An op and what it takes to notice the program halting as `HackMachine::halted` would.
+#[derive(Clone, Copy, Debug)]
+struct Step {
+    op: Op,
+    /// Address of its first instruction
+    pc: u16,
+    /// The program halts on reaching this op
+    halts: bool,
+    /// Fused ops only: the program halts between the op's two instructions
+    halts_within: bool,
+}
+
+/// Runs a `HackMachine` faster than stepping it, with the same results.
+///
+/// Code is decoded the first time it runs into basic blocks, which run from where a jump lands to
+/// the next jump, and cached. Within a block each `@value` is fused with the C-instruction after it
+/// into one op, which covers the VM translator's common `@SP AM=M-1`, `@R13 M=D` and `@LABEL 0;JMP`
+/// sequences. Runs end exactly where `HackMachine::run` would end them, taking the last instruction
+/// before a cycle limit one at a time if need be. Banked machines run on `HackMachine::run`, since
+/// their code at an address changes with the bank.
+pub struct FastCore {
+    /// Decoded blocks by starting address
+    blocks: Vec<Option<Vec<Step>>>,
+}
+
+impl Default for FastCore {
+    fn default() -> Self {
+        FastCore {
+            blocks: vec![None; ROM_SIZE],
+        }
+    }
+}
+
+impl FastCore {
+    /// Like `HackMachine::run`.
+    pub fn run(&mut self, machine: &mut HackMachine, limit: u64) -> RunResult {
+        if machine.is_banked() {
+            return machine.run(limit);
+        }
+        let mut cycles = 0;
+        loop {
+            let start = usize::from(machine.pc);
+            let block = match &self.blocks[start] {
+                Some(block) => block,
+                None => self.blocks[start].insert(decode_block(machine, machine.pc)),
+            };
+
+            for step in block {
+                machine.pc = step.pc;
+                if step.halts
+                    || matches!(step.op, Op::Compute(compute) if compute.unconditional && machine.a & 0x7FFF == step.pc)
+                {
+                    return RunResult {
+                        cycles,
+                        stop: Stop::Halted,
+                    };
+                }
+                let cost: u16 = if matches!(step.op, Op::LoadCompute(..)) {
+                    2
+                } else {
+                    1
+                };
+                if cycles + u64::from(cost) > limit {
+                    let rest = machine.run(limit - cycles);
+                    return RunResult {
+                        cycles: cycles + rest.cycles,
+                        stop: rest.stop,
+                    };
+                }
+
+                // Devices see the cycle count as it is while their instruction runs
+                let target = match step.op {
+                    Op::Load(value) => {
+                        machine.cycles += 1;
+                        machine.a = value;
+                        None
+                    }
+                    Op::Compute(compute) => {
+                        machine.cycles += 1;
+                        compute.execute(machine)
+                    }
+                    Op::LoadCompute(value, compute) => {
+                        machine.cycles += 1;
+                        machine.a = value;
+                        if step.halts_within {
+                            machine.pc = (step.pc + 1) & 0x7FFF;
+                            return RunResult {
+                                cycles: cycles + 1,
+                                stop: Stop::Halted,
+                            };
+                        }
+                        machine.cycles += 1;
+                        compute.execute(machine)
+                    }
+                };
+                cycles += u64::from(cost);
+                machine.pc = match target {
+                    Some(target) => target,
+                    None => (step.pc + cost) & 0x7FFF,
+                };
+            }
+        }
+    }
+}
+
+/// The ops from `start` up to and including the first that can jump, or up to the end of ROM.
+fn decode_block(machine: &HackMachine, start: u16) -> Vec<Step> {
+    // Same as HackMachine::halted for the first of the two instructions at an address
+    let halts_at = |pc: u16| {
+        machine.fetch(pc) == pc && is_unconditional_jump(machine.fetch((pc + 1) & 0x7FFF))
+    };
+
+    let mut block = Vec::new();
+    let mut pc = start;
+    loop {
+        let instruction = machine.fetch(pc);
+        let next = pc
+            .checked_add(1)
+            .filter(|&next| usize::from(next) < ROM_SIZE);
+        let following = next.map(|next| machine.fetch(next));
+
+        let (op, halts_within, length) = match following {
+            Some(following) if instruction & 0x8000 == 0 && following & 0x8000 != 0 => {
+                let compute = Compute::decode(following);
+                let halts_within = compute.unconditional && instruction == pc + 1;
+                (Op::LoadCompute(instruction, compute), halts_within, 2)
+            }
+            _ if instruction & 0x8000 == 0 => (Op::Load(instruction), false, 1),
+            _ => (Op::Compute(Compute::decode(instruction)), false, 1),
+        };
+        block.push(Step {
+            op,
+            pc,
+            halts: halts_at(pc),
+            halts_within,
+        });
+
+        let jumps = match op {
+            Op::Load(_) => false,
+            Op::Compute(compute) | Op::LoadCompute(_, compute) => compute.jumps(),
+        };
+        match pc.checked_add(length) {
+            Some(end) if !jumps && usize::from(end) < ROM_SIZE => pc = end,
+            _ => return block,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// R2 = R0 * R1 by repeated addition, ending on the `@END 0;JMP` halt idiom.
+    const MULTIPLY: [u16; 16] = [
+        2, 0xEA88, // @R2 M=0
+        0, 0xFC10, // (LOOP) @R0 D=M
+        14, 0xE302, // @END D;JEQ
+        1, 0xFC10, // @R1 D=M
+        2, 0xF088, // @R2 M=D+M
+        0, 0xFC88, // @R0 M=M-1
+        2, 0xEA87, // @LOOP 0;JMP
+        14, 0xEA87, // (END) @END 0;JMP
+    ];
+
+    /// Fills a screen word, then copies the keyboard to the screen through the mirror above it.
+    const SCREEN_AND_KEYBOARD: [u16; 8] = [
+        0x4000, 0xEE88, // @SCREEN M=-1
+        0x6000, 0xFC10, // @KBD D=M
+        0x6001, 0xE308, // @24577 M=D
+        6, 0xEA87, // @6 0;JMP
+    ];
+
+    fn assert_same(fast: &HackMachine, simple: &HackMachine) {
+        assert_eq!(
+            (fast.a, fast.d, fast.pc, fast.cycles),
+            (simple.a, simple.d, simple.pc, simple.cycles)
+        );
+        assert_eq!(fast.ram, simple.ram);
+        assert_eq!(fast.screen, simple.screen);
+    }
+
+    /// Runs the program to every cycle limit up to past where it halts on both cores, then on
+    /// again from there, comparing results and machine state each time.
+    fn assert_equivalent(machine: &HackMachine) {
+        let total = machine.clone().run(1_000_000).cycles;
+        for limit in 0..=total + 2 {
+            let mut simple = machine.clone();
+            let mut fast = machine.clone();
+            let mut core = FastCore::default();
+            assert_eq!(
+                core.run(&mut fast, limit),
+                simple.run(limit),
+                "limit {limit}"
+            );
+            assert_same(&fast, &simple);
+            assert_eq!(core.run(&mut fast, 5), simple.run(5), "limit {limit}");
+            assert_same(&fast, &simple);
+        }
+    }
+
+    #[test]
+    fn multiplies_like_the_interpreter() {
+        let mut machine = HackMachine::new(MULTIPLY.to_vec());
+        machine.ram[0] = 7;
+        machine.ram[1] = 6;
+        assert_equivalent(&machine);
+
+        let mut fast = machine.clone();
+        FastCore::default().run(&mut fast, 1_000_000);
+        assert_eq!(fast.ram[2], 42);
+    }
+
+    #[test]
+    fn maps_memory_like_the_interpreter() {
+        let mut machine = HackMachine::new(SCREEN_AND_KEYBOARD.to_vec());
+        machine.keyboard = 65;
+        assert_equivalent(&machine);
+    }
+
+    #[test]
+    fn wraps_past_the_end_of_rom_like_the_interpreter() {
+        // D=D+1 twice at the top of ROM, then the halt idiom at 0
+        let mut rom = vec![0; ROM_SIZE];
+        rom[1] = 0xEA87;
+        rom[ROM_SIZE - 2..].fill(0xE7D0);
+        let mut machine = HackMachine::new(rom);
+        machine.pc = 0x7FFE;
+        assert_equivalent(&machine);
+
+        let mut fast = machine.clone();
+        FastCore::default().run(&mut fast, 1_000_000);
+        assert_eq!((fast.d, fast.pc), (2, 0));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::profile::percent;
+use crate::{HackMachine, Symbols};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// A frame of the call tree: one function called along one path from the root.
+struct Node {
+    function: usize,
+    parent: Option<usize>,
+    /// Cycles spent in this frame itself, not counting the calls it makes
+    cycles: u64,
+}
+
+/// A call or return, by function number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transfer {
+    Call(usize),
+    Return(usize),
+}
+
+/// Follows the calls and returns of code from the VM translator.
+///
+/// Functions are the labels without a `$` in them, which the translator only gives to `function`
+/// commands. A taken jump to one is a call returning to the instruction after the jump, where the
+/// translator puts the `$ret.N` label, and a taken jump to the innermost call's return address is
+/// its return. Call `before_step` ahead of each instruction.
+#[derive(Clone, Debug)]
+pub struct CallStack {
+    /// Function names, indexed by their number, with 0 for code before any function
+    functions: Vec<String>,
+    /// Entry address of each function
+    entries: HashMap<u16, usize>,
+    /// Function entries sorted by address, for finding the function code belongs to
+    sorted: Vec<(u16, usize)>,
+    /// Active functions, innermost last, with the addresses they return to
+    stack: Vec<(usize, u16)>,
+    /// Address of the last instruction and whether it's a jump, and then whether it always jumps
+    previous: Option<(u16, Option<bool>)>,
+}
+
+impl CallStack {
+    #[must_use]
+    pub fn new(symbols: &Symbols) -> Self {
+        let mut functions = vec!["(start)".to_string()];
+        let mut entries = HashMap::new();
+        for (name, address) in symbols.labels().filter(|(name, _)| !name.contains('$')) {
+            entries.insert(address, functions.len());
+            functions.push(name.to_string());
+        }
+        let mut sorted: Vec<(u16, usize)> = entries.iter().map(|(&a, &f)| (a, f)).collect();
+        sorted.sort_unstable();
+        CallStack {
+            functions,
+            entries,
+            sorted,
+            stack: Vec::new(),
+            previous: None,
+        }
+    }
+
+    /// Notes the call or return that brought the machine to its current instruction, if any.
+    pub fn before_step(&mut self, machine: &HackMachine) -> Option<Transfer> {
+        let pc = machine.pc;
+        let mut transfer = None;
+        match self.previous {
+            None => {
+                // The run starts in whatever function holds the first instruction
+                let i = self.sorted.partition_point(|&(address, _)| address <= pc);
+                let function = i.checked_sub(1).map_or(0, |i| self.sorted[i].1);
+                self.stack.push((function, u16::MAX));
+            }
+            // A call can land on the very next address, as the bootstrap's does, so an
+            // unconditional jump counts as taken wherever it goes
+            Some((previous, Some(always))) if always || pc != (previous + 1) & 0x7FFF => {
+                if let Some(&function) = self.entries.get(&pc) {
+                    self.stack.push((function, (previous + 1) & 0x7FFF));
+                    transfer = Some(Transfer::Call(function));
+                } else if self.stack.len() > 1
+                    && self.stack.last().is_some_and(|&(_, address)| address == pc)
+                {
+                    transfer = self
+                        .stack
+                        .pop()
+                        .map(|(function, _)| Transfer::Return(function));
+                }
+            }
+            Some(_) => {}
+        }
+
+        let instruction = machine.fetch(pc);
+        let jump = instruction & 0x8000 != 0 && instruction & 0b111 != 0;
+        self.previous = Some((pc, jump.then_some(instruction & 0b111 == 0b111)));
+        transfer
+    }
+
+    /// The number of the function running, once `before_step` has seen the first instruction.
+    #[must_use]
+    pub fn innermost(&self) -> Option<usize> {
+        self.stack.last().map(|&(function, _)| function)
+    }
+
+    /// The active functions, outermost first.
+    pub fn active(&self) -> impl Iterator<Item = &str> {
+        self.stack
+            .iter()
+            .map(|&(function, _)| self.functions[function].as_str())
+    }
+
+    #[must_use]
+    pub fn name(&self, function: usize) -> &str {
+        &self.functions[function]
+    }
+
+    /// How many functions there are, counting the 0 for code outside them.
+    #[must_use]
+    pub fn functions(&self) -> usize {
+        self.functions.len()
+    }
+}
+
+/// Follows the calls and returns of code from the VM translator with a `CallStack` and counts the
+/// cycles spent under each call stack, for flame graphs. Call `before_step` ahead of each
+/// instruction.
+pub struct FlameGraph {
+    calls: CallStack,
+    nodes: Vec<Node>,
+    children: HashMap<(usize, usize), usize>,
+    /// Nodes of the active calls, innermost last
+    stack: Vec<usize>,
+    /// Times each function was called, by number
+    call_counts: Vec<u64>,
+}
+
+impl FlameGraph {
+    #[must_use]
+    pub fn new(symbols: &Symbols) -> Self {
+        let calls = CallStack::new(symbols);
+        FlameGraph {
+            call_counts: vec![0; calls.functions()],
+            calls,
+            nodes: Vec::new(),
+            children: HashMap::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    pub fn before_step(&mut self, machine: &HackMachine) {
+        match self.calls.before_step(machine) {
+            Some(Transfer::Call(function)) => self.call(function),
+            Some(Transfer::Return(_)) => {
+                self.stack.pop();
+            }
+            None if self.nodes.is_empty() => {
+                self.nodes.push(Node {
+                    function: self.calls.innermost().unwrap_or(0),
+                    parent: None,
+                    cycles: 0,
+                });
+                self.stack.push(0);
+            }
+            None => {}
+        }
+
+        let node = self.current();
+        self.nodes[node].cycles += 1;
+    }
+
+    /// The innermost call's node in the tree.
+    fn current(&self) -> usize {
+        self.stack.last().copied().unwrap_or(0)
+    }
+
+    fn call(&mut self, function: usize) {
+        let parent = self.current();
+        let next = self.nodes.len();
+        let node = *self.children.entry((parent, function)).or_insert(next);
+        if node == next {
+            self.nodes.push(Node {
+                function,
+                parent: Some(parent),
+                cycles: 0,
+            });
+        }
+        self.stack.push(node);
+        self.call_counts[function] += 1;
+    }
+
+    /// One line per call stack with the cycles spent in its innermost function, outermost
+    /// function first, in the folded format flame graph tools read:
+    ///
+    /// ```text
+    /// Sys.init;Main.fib;Main.fib 1234
+    /// ```
+    #[must_use]
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = (0..self.nodes.len())
+            .filter(|&node| self.nodes[node].cycles > 0)
+            .map(|node| {
+                let mut names = Vec::new();
+                let mut current = Some(node);
+                while let Some(frame) = current {
+                    names.push(self.calls.name(self.nodes[frame].function));
+                    current = self.nodes[frame].parent;
+                }
+                names.reverse();
+                format!("{} {}", names.join(";"), self.nodes[node].cycles)
+            })
+            .collect();
+        lines.sort_unstable();
+        lines.iter().fold(String::new(), |mut folded, line| {
+            writeln!(folded, "{line}").unwrap();
+            folded
+        })
+    }
+
+    /// Calls and cycles of each function that ran, busiest first. Inclusive cycles count the
+    /// functions it calls, and recursive calls only once.
+    #[must_use]
+    pub fn report(&self) -> String {
+        // Children always come after their parents, so one pass backwards totals every subtree
+        let mut inclusive: Vec<u64> = self.nodes.iter().map(|node| node.cycles).collect();
+        for node in (0..self.nodes.len()).rev() {
+            if let Some(parent) = self.nodes[node].parent {
+                inclusive[parent] += inclusive[node];
+            }
+        }
+
+        let mut totals = vec![(0, 0); self.calls.functions()];
+        for (node, frame) in self.nodes.iter().enumerate() {
+            totals[frame.function].1 += frame.cycles;
+            let mut ancestor = frame.parent;
+            let mut recursive = false;
+            while let Some(parent) = ancestor {
+                recursive |= self.nodes[parent].function == frame.function;
+                ancestor = self.nodes[parent].parent;
+            }
+            if !recursive {
+                totals[frame.function].0 += inclusive[node];
+            }
+        }
+
+        let total = inclusive.first().copied().unwrap_or(0);
+        let mut rows: Vec<usize> = (0..self.calls.functions())
+            .filter(|&function| totals[function].0 > 0)
+            .collect();
+        rows.sort_by_key(|&function| (std::cmp::Reverse(totals[function]), function));
+
+        let mut report = format!(
+            "{:>12} {:>6} {:>12} {:>6} {:>10}  function\n",
+            "inclusive", "", "exclusive", "", "calls"
+        );
+        for function in rows {
+            let (inclusive, exclusive) = totals[function];
+            writeln!(
+                report,
+                "{inclusive:12} {:>6} {exclusive:12} {:>6} {:10}  {}",
+                percent(inclusive, total),
+                percent(exclusive, total),
+                self.call_counts[function],
+                self.calls.name(function)
+            )
+            .unwrap();
+        }
+        report
+    }
+}
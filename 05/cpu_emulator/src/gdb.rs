@@ -0,0 +1,593 @@
+use crate::HackMachine;
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+/// ROM appears to the debugger after RAM, which takes the first 64K bytes.
+const ROM_BASE: u32 = 0x1_0000;
+/// Cycles run between checks for an interrupt from the debugger.
+const SLICE: u64 = 100_000;
+/// Sent outside packets to stop a running target, as Ctrl-C in the debugger does.
+const INTERRUPT: u8 = 0x03;
+/// Signal numbers for stop replies.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// Target description naming the registers, in the order `g` packets carry them.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nand2tetris.hack">
+    <reg name="a" bitsize="16" type="int16" regnum="0"/>
+    <reg name="d" bitsize="16" type="int16"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// A connection the server can talk over.
+pub trait Connection: Read + Write {
+    /// # Errors
+    /// If the socket refuses the mode change.
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Watchpoint {
+    kind: WatchKind,
+    /// Bytes watched, as the debugger addresses them
+    address: u32,
+    length: u32,
+}
+
+/// Serves the GDB remote serial protocol for one debugger session, until it detaches or kills the
+/// target.
+///
+/// The registers are A, D and PC, 16 bits each. Memory is addressed in bytes with each word
+/// little-endian, so RAM word N is at byte 2N and ROM word N at 0x10000 + 2N. Breakpoints take ROM
+/// word addresses, the same units as PC. Write, read and access watchpoints cover RAM words.
+///
+/// # Errors
+/// If the connection fails.
+pub fn serve(machine: &mut HackMachine, connection: &mut impl Connection) -> io::Result<()> {
+    Server {
+        machine,
+        connection,
+        breakpoints: HashSet::new(),
+        watchpoints: Vec::new(),
+        acknowledge: true,
+    }
+    .serve()
+}
+
+struct Server<'a, C: Connection> {
+    machine: &'a mut HackMachine,
+    connection: &'a mut C,
+    breakpoints: HashSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+    /// Whether packets are still acknowledged, until the debugger asks for no-ack mode
+    acknowledge: bool,
+}
+
+impl<C: Connection> Server<'_, C> {
+    fn serve(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.receive()? {
+            let reply = match packet.as_bytes().first() {
+                Some(b'?') => format!("S{SIGTRAP:02x}"),
+                Some(b'g') => [self.machine.a, self.machine.d, self.machine.pc]
+                    .iter()
+                    .fold(String::new(), |mut hex, &register| {
+                        hex.push_str(&word_hex(register));
+                        hex
+                    }),
+                Some(b'G') => self.write_registers(&packet[1..]),
+                Some(b'p') => match usize::from_str_radix(&packet[1..], 16) {
+                    Ok(register @ 0..=2) => word_hex(self.register(register)),
+                    _ => "E01".to_string(),
+                },
+                Some(b'P') => self.write_register(&packet[1..]),
+                Some(b'm') => self.read_memory(&packet[1..]),
+                Some(b'M') => self.write_memory(&packet[1..]),
+                Some(b's') => self.step(),
+                Some(b'c') => self.resume()?,
+                Some(b'Z') => self.breakpoint(&packet[1..], true),
+                Some(b'z') => self.breakpoint(&packet[1..], false),
+                Some(b'H') => "OK".to_string(),
+                Some(b'D') => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                Some(b'k') => return Ok(()),
+                Some(b'q' | b'Q') => self.query(&packet),
+                _ => String::new(),
+            };
+            self.send(&reply)?;
+        }
+        Ok(())
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+;swbreak+".to_string();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = parse_range(range) else {
+                return "E01".to_string();
+            };
+            let rest = TARGET_XML.get(offset as usize..).unwrap_or_default();
+            return if rest.len() > length as usize {
+                format!("m{}", &rest[..length as usize])
+            } else {
+                format!("l{rest}")
+            };
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                // Only this reply is acknowledged, and nothing after it
+                self.acknowledge = false;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            "qC" => "QC1".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn register(&self, register: usize) -> u16 {
+        match register {
+            0 => self.machine.a,
+            1 => self.machine.d,
+            _ => self.machine.pc,
+        }
+    }
+
+    fn set_register(&mut self, register: usize, value: u16) {
+        match register {
+            0 => self.machine.a = value,
+            1 => self.machine.d = value,
+            _ => self.machine.pc = value & 0x7FFF,
+        }
+    }
+
+    fn write_registers(&mut self, hex: &str) -> String {
+        let Some(bytes) = parse_hex(hex).filter(|bytes| bytes.len() == 6) else {
+            return "E01".to_string();
+        };
+        for (register, value) in bytes.chunks(2).enumerate() {
+            self.set_register(register, u16::from_le_bytes([value[0], value[1]]));
+        }
+        "OK".to_string()
+    }
+
+    fn write_register(&mut self, assignment: &str) -> String {
+        let parsed = assignment.split_once('=').and_then(|(register, value)| {
+            let register = usize::from_str_radix(register, 16).ok()?;
+            let bytes = parse_hex(value).filter(|bytes| bytes.len() == 2)?;
+            Some((register, u16::from_le_bytes([bytes[0], bytes[1]])))
+        });
+        match parsed {
+            Some((register @ 0..=2, value)) => {
+                self.set_register(register, value);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    /// The byte at a debugger address, or `None` outside RAM and ROM.
+    fn read_byte(&self, address: u32) -> Option<u8> {
+        let word = if address < ROM_BASE {
+            self.machine.read(u16::try_from(address / 2).ok()?)
+        } else {
+            let word = u16::try_from((address - ROM_BASE) / 2).ok()?;
+            if word > 0x7FFF {
+                return None;
+            }
+            self.machine.fetch(word)
+        };
+        Some(word.to_le_bytes()[(address % 2) as usize])
+    }
+
+    fn read_memory(&self, range: &str) -> String {
+        let Some((address, length)) = parse_range(range) else {
+            return "E01".to_string();
+        };
+        let mut hex = String::new();
+        for address in address..address.saturating_add(length) {
+            match self.read_byte(address) {
+                Some(byte) => write!(hex, "{byte:02x}").unwrap(),
+                // A short read is fine as long as something came back
+                None if hex.is_empty() => return "E14".to_string(),
+                None => break,
+            }
+        }
+        hex
+    }
+
+    /// Writes to RAM. ROM stays read-only, as on the real machine.
+    fn write_memory(&mut self, packet: &str) -> String {
+        let parsed = packet.split_once(':').and_then(|(range, data)| {
+            let (address, length) = parse_range(range)?;
+            let bytes = parse_hex(data).filter(|bytes| bytes.len() == length as usize)?;
+            Some((address, bytes))
+        });
+        let Some((address, bytes)) = parsed else {
+            return "E01".to_string();
+        };
+        if address.saturating_add(u32::try_from(bytes.len()).unwrap_or(u32::MAX)) > ROM_BASE {
+            return "E14".to_string();
+        }
+        for (byte_address, byte) in (address..).zip(bytes) {
+            let word = u16::try_from(byte_address / 2).unwrap();
+            let mut value = self.machine.read(word).to_le_bytes();
+            value[(byte_address % 2) as usize] = byte;
+            self.machine.write(word, u16::from_le_bytes(value));
+        }
+        "OK".to_string()
+    }
+
+    fn breakpoint(&mut self, packet: &str, insert: bool) -> String {
+        let Some((kind, rest)) = packet.split_once(',') else {
+            return "E01".to_string();
+        };
+        let Some((address, length)) = parse_range(rest) else {
+            return "E01".to_string();
+        };
+        let watch = match kind {
+            // Software and hardware breakpoints work the same on an emulator
+            "0" | "1" => {
+                let Ok(address) = u16::try_from(address) else {
+                    return "E01".to_string();
+                };
+                if insert {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+        if insert {
+            self.watchpoints.push(Watchpoint {
+                kind: watch,
+                address,
+                length,
+            });
+        } else if let Some(i) = self.watchpoints.iter().position(|watchpoint| {
+            watchpoint.kind == watch && watchpoint.address == address && watchpoint.length == length
+        }) {
+            self.watchpoints.remove(i);
+        }
+        "OK".to_string()
+    }
+
+    /// Executes one instruction, returning the stop reply for a watchpoint it triggers.
+    fn execute(&mut self) -> Option<String> {
+        let instruction = self.machine.fetch(self.machine.pc);
+        let address = self.machine.a & 0x7FFF;
+        let is_c = instruction & 0x8000 != 0;
+        let reads = is_c && instruction & 0x1000 != 0;
+        let writes = is_c && instruction & 0b00_1000 != 0;
+        self.machine.step();
+
+        let first = u32::from(address) * 2;
+        self.watchpoints.iter().find_map(|watchpoint| {
+            let overlaps = watchpoint.address < first + 2
+                && first < watchpoint.address.saturating_add(watchpoint.length);
+            let (hit, name) = match watchpoint.kind {
+                WatchKind::Write => (writes, "watch"),
+                WatchKind::Read => (reads, "rwatch"),
+                WatchKind::Access => (reads || writes, "awatch"),
+            };
+            (overlaps && hit).then(|| format!("T{SIGTRAP:02x}{name}:{first:x};"))
+        })
+    }
+
+    fn step(&mut self) -> String {
+        self.execute().unwrap_or_else(|| format!("S{SIGTRAP:02x}"))
+    }
+
+    /// Runs until a breakpoint, a watchpoint, the program halting or an interrupt from the debugger.
+    fn resume(&mut self) -> io::Result<String> {
+        loop {
+            for _ in 0..SLICE {
+                if let Some(reply) = self.execute() {
+                    return Ok(reply);
+                }
+                if self.breakpoints.contains(&self.machine.pc) {
+                    return Ok(format!("T{SIGTRAP:02x}swbreak:;"));
+                }
+                if self.machine.halted() {
+                    return Ok(format!("S{SIGTRAP:02x}"));
+                }
+            }
+            if self.interrupted()? {
+                return Ok(format!("S{SIGINT:02x}"));
+            }
+        }
+    }
+
+    /// Whether the debugger has sent an interrupt while the target ran.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.connection.set_nonblocking(true)?;
+        let mut byte = [0];
+        let read = self.connection.read(&mut byte);
+        self.connection.set_nonblocking(false)?;
+        match read {
+            Ok(1) => Ok(byte[0] == INTERRUPT),
+            Ok(_) => Err(ErrorKind::UnexpectedEof.into()),
+            Err(why) if why.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(why) => Err(why),
+        }
+    }
+
+    /// Waits for the next packet, acknowledging it. `None` when the debugger hangs up.
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0];
+        loop {
+            // Skip acknowledgements and interrupts that arrive while stopped
+            loop {
+                if self.connection.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                if self.connection.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.connection.read_exact(&mut checksum)?;
+
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if !self.acknowledge {
+                return Ok(Some(String::from_utf8_lossy(&data).to_string()));
+            }
+            if expected == Some(sum(&data)) {
+                self.connection.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).to_string()));
+            }
+            self.connection.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        let packet = format!("${reply}#{:02x}", sum(reply.as_bytes()));
+        self.connection.write_all(packet.as_bytes())?;
+        self.connection.flush()
+    }
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// A 16-bit value as the little-endian hex `g` and `p` packets use.
+fn word_hex(value: u16) -> String {
+    let [low, high] = value.to_le_bytes();
+    format!("{low:02x}{high:02x}")
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// `ADDRESS,LENGTH` in hex.
+fn parse_range(range: &str) -> Option<(u32, u32)> {
+    let (address, length) = range.split_once(',')?;
+    Some((
+        u32::from_str_radix(address, 16).ok()?,
+        u32::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io::Cursor;
+
+    /// A debugger session played back from a script, recording what the server sends.
+    struct Script {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+        nonblocking: Cell<bool>,
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            // A socket with nothing to read would block rather than report the end
+            if self.nonblocking.get() && self.input.position() == self.input.get_ref().len() as u64
+            {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            self.input.read(buffer)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            self.output.write(buffer)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Script {
+        fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+            self.nonblocking.set(nonblocking);
+            Ok(())
+        }
+    }
+
+    /// Stores 1 in RAM[5], reads it back into D, then halts.
+    const PROGRAM: [u16; 6] = [
+        5, 0xEFC8, // @5 M=1
+        5, 0xFC10, // @5 D=M
+        4, 0xEA87, // (END) @END 0;JMP
+    ];
+
+    fn packet(data: &str) -> String {
+        format!("${data}#{:02x}", sum(data.as_bytes()))
+    }
+
+    /// Serves the packets in `input` and returns everything sent back.
+    fn session(machine: &mut HackMachine, input: &str) -> String {
+        let mut script = Script {
+            input: Cursor::new(input.as_bytes().to_vec()),
+            output: Vec::new(),
+            nonblocking: Cell::new(false),
+        };
+        serve(machine, &mut script).unwrap();
+        String::from_utf8(script.output).unwrap()
+    }
+
+    /// What the server sends back for each request while acknowledging packets.
+    fn replies(replies: &[&str]) -> String {
+        replies.iter().fold(String::new(), |mut sent, reply| {
+            write!(sent, "+{}", packet(reply)).unwrap();
+            sent
+        })
+    }
+
+    #[test]
+    fn frames_and_checks_packets() {
+        let mut machine = HackMachine::new(PROGRAM.to_vec());
+        machine.d = 0x1234;
+        let input = format!("+{}$g#00{}{}", packet("?"), packet("g"), packet("p2"));
+        assert_eq!(
+            session(&mut machine, &input),
+            format!(
+                "+{}-+{}+{}",
+                packet("S05"),
+                packet("000034120000"),
+                packet("0000")
+            )
+        );
+    }
+
+    #[test]
+    fn stops_acknowledging_in_no_ack_mode() {
+        let mut machine = HackMachine::new(PROGRAM.to_vec());
+        let input = format!("{}+$qC#00{}", packet("QStartNoAckMode"), packet("s"));
+        assert_eq!(
+            session(&mut machine, &input),
+            format!("+{}{}{}", packet("OK"), packet("QC1"), packet("S05"))
+        );
+        assert_eq!(machine.pc, 1);
+    }
+
+    #[test]
+    fn reads_and_writes_memory() {
+        let mut machine = HackMachine::new(PROGRAM.to_vec());
+        let requests = [
+            "Ma,2:3412",
+            "m8,6",
+            "m10000,4",
+            "M10000,2:0000",
+            "m1fffe,4",
+            "m20000,2",
+            "Ma,2:34",
+        ];
+        let input: String = requests.iter().map(|request| packet(request)).collect();
+        assert_eq!(
+            session(&mut machine, &input),
+            replies(&[
+                "OK",
+                "000034120000",
+                "0500c8ef",
+                "E14",
+                "0000",
+                "E14",
+                "E01"
+            ])
+        );
+        assert_eq!(machine.read(5), 0x1234);
+        assert_eq!(machine.fetch(0), 5);
+    }
+
+    #[test]
+    fn stops_on_watchpoints() {
+        let requests = ["Z2,a,2", "Z3,a,2", "c", "c", "z2,a,2", "z3,a,2", "c"];
+        let input: String = requests.iter().map(|request| packet(request)).collect();
+        let mut machine = HackMachine::new(PROGRAM.to_vec());
+        assert_eq!(
+            session(&mut machine, &input),
+            replies(&["OK", "OK", "T05watch:a;", "T05rwatch:a;", "OK", "OK", "S05"])
+        );
+        assert_eq!(machine.pc, 4);
+
+        // An access watchpoint fires on the write and the read, here over a range around the word
+        let input = packet("Z4,8,4") + &packet("c") + &packet("s") + &packet("s");
+        let mut machine = HackMachine::new(PROGRAM.to_vec());
+        assert_eq!(
+            session(&mut machine, &input),
+            replies(&["OK", "T05awatch:a;", "S05", "T05awatch:a;"])
+        );
+        assert_eq!(machine.pc, 4);
+    }
+
+    #[test]
+    fn stops_on_breakpoints_and_interrupts() {
+        let input = packet("Z0,2,1") + &packet("c") + &packet("z0,2,1") + &packet("c");
+        let mut machine = HackMachine::new(PROGRAM.to_vec());
+        assert_eq!(
+            session(&mut machine, &input),
+            replies(&["OK", "T05swbreak:;", "OK", "S05"])
+        );
+
+        // @0 M=M+1 @0 0;JMP never halts, so it runs until the debugger interrupts it
+        let mut machine = HackMachine::new(vec![0, 0xFDC8, 0, 0xEA87]);
+        let input = packet("c") + "\x03";
+        assert_eq!(session(&mut machine, &input), replies(&["S02"]));
+        assert_eq!(machine.cycles, SLICE);
+    }
+}
@@ -0,0 +1,279 @@
+use crate::devices::Device;
+use crate::{HackMachine, KBD, SCREEN};
+use std::collections::VecDeque;
+
+/// Steps recorded after each checkpoint before the next one.
+const SEGMENT_STEPS: usize = 100_000;
+/// Checkpoints kept, which bounds the history at about a million steps.
+const MAX_SEGMENTS: usize = 10;
+
+/// What a step changed, so it can be undone.
+struct Entry {
+    a: u16,
+    d: u16,
+    pc: u16,
+    /// The memory word the step wrote and what it held before
+    write: Option<(u16, u16)>,
+    /// Every device as it was, for steps that read or write one
+    devices: Option<Vec<Box<dyn Device>>>,
+}
+
+/// The steps since a checkpoint of the whole machine.
+struct Segment {
+    checkpoint: HackMachine,
+    entries: Vec<Entry>,
+}
+
+/// A write found in the history.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LastWrite {
+    /// The instruction that wrote
+    pub pc: u16,
+    /// The cycle it ran on
+    pub cycle: u64,
+    /// Steps back from the present
+    pub steps_ago: usize,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// An undo log of the steps a machine takes, for running it backwards.
+///
+/// Call `record` ahead of each step and `undo` to take back the latest. The log starts a new
+/// segment with a checkpoint of the machine every `SEGMENT_STEPS` steps and forgets the oldest
+/// segment past `MAX_SEGMENTS`. Undoing the last step of a segment puts the machine back to its
+/// checkpoint exactly.
+#[derive(Default)]
+pub struct History {
+    segments: VecDeque<Segment>,
+}
+
+impl History {
+    /// Notes what the step `machine` is about to take will change.
+    pub fn record(&mut self, machine: &HackMachine) {
+        if self
+            .segments
+            .back()
+            .is_none_or(|segment| segment.entries.len() == SEGMENT_STEPS)
+        {
+            if self.segments.len() == MAX_SEGMENTS {
+                self.segments.pop_front();
+            }
+            self.segments.push_back(Segment {
+                checkpoint: machine.clone(),
+                entries: Vec::with_capacity(SEGMENT_STEPS),
+            });
+        }
+
+        let instruction = machine.fetch(machine.pc);
+        let address = machine.a & 0x7FFF;
+        let is_c = instruction & 0x8000 != 0;
+        let writes = is_c && instruction & 0b00_1000 != 0;
+        let reads = is_c && instruction & 0x1000 != 0;
+        let device = address > KBD && machine.device(address).is_some();
+        let entry = Entry {
+            a: machine.a,
+            d: machine.d,
+            pc: machine.pc,
+            write: (writes && !device).then(|| (word_address(address), word(machine, address))),
+            devices: ((reads || writes) && device).then(|| {
+                machine
+                    .devices()
+                    .map(|(_, device)| device.clone_box())
+                    .collect()
+            }),
+        };
+        if let Some(segment) = self.segments.back_mut() {
+            segment.entries.push(entry);
+        }
+    }
+
+    /// Takes back the latest step, returning whether there was one.
+    pub fn undo(&mut self, machine: &mut HackMachine) -> bool {
+        let Some(segment) = self.segments.back_mut() else {
+            return false;
+        };
+        let Some(entry) = segment.entries.pop() else {
+            return false;
+        };
+        if segment.entries.is_empty() {
+            if let Some(segment) = self.segments.pop_back() {
+                *machine = segment.checkpoint;
+            }
+            return true;
+        }
+
+        (machine.a, machine.d, machine.pc) = (entry.a, entry.d, entry.pc);
+        machine.cycles -= 1;
+        if let Some((address, old)) = entry.write {
+            if address < SCREEN {
+                machine.ram[usize::from(address)] = old;
+            } else {
+                machine.screen[usize::from(address & 0x1FFF)] = old;
+            }
+        }
+        if let Some(devices) = entry.devices {
+            for ((_, device), saved) in machine.devices_mut().zip(devices) {
+                *device = saved;
+            }
+        }
+        true
+    }
+
+    /// Steps that can be undone.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|segment| segment.entries.len())
+            .sum()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The latest step that wrote a RAM or screen address, given the machine as it is now.
+    #[must_use]
+    pub fn last_write(&self, machine: &HackMachine, address: u16) -> Option<LastWrite> {
+        let address = word_address(address);
+        let entries = self
+            .segments
+            .iter()
+            .rev()
+            .flat_map(|segment| segment.entries.iter().rev());
+        entries
+            .enumerate()
+            .find_map(|(i, entry)| match entry.write {
+                Some((at, old)) if at == address => Some(LastWrite {
+                    pc: entry.pc,
+                    cycle: machine.cycles - i as u64 - 1,
+                    steps_ago: i + 1,
+                    old,
+                    new: word(machine, address),
+                }),
+                _ => None,
+            })
+    }
+}
+
+/// Where a write to an address lands: itself in RAM, or its word of the screen, which the
+/// addresses from the keyboard up mirror.
+pub(crate) fn word_address(address: u16) -> u16 {
+    let address = address & 0x7FFF;
+    if address < SCREEN {
+        address
+    } else {
+        SCREEN | (address & 0x1FFF)
+    }
+}
+
+/// The RAM or screen word behind an address.
+fn word(machine: &HackMachine, address: u16) -> u16 {
+    if address < SCREEN {
+        machine.ram[usize::from(address)]
+    } else {
+        machine.screen[usize::from(address & 0x1FFF)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sets R2 once, then counts in R0 forever, copying the count to the bank register and the
+    /// screen.
+    const COUNTER: [u16; 11] = [
+        2, 0xEE88, // @R2 M=-1
+        0, 0xFDC8, // (LOOP) @R0 M=M+1
+        0xFC10, // D=M
+        0x6001, 0xE308, // @BANK_SELECT M=D
+        0x4000, 0xE308, // @SCREEN M=D
+        2, 0xEA87, // @LOOP 0;JMP
+    ];
+
+    fn run(machine: &mut HackMachine, history: &mut History, steps: usize) {
+        for _ in 0..steps {
+            history.record(machine);
+            machine.step();
+        }
+    }
+
+    fn assert_same(machine: &HackMachine, expected: &HackMachine) {
+        assert_eq!(
+            (machine.a, machine.d, machine.pc, machine.cycles),
+            (expected.a, expected.d, expected.pc, expected.cycles)
+        );
+        assert!(machine.ram == expected.ram && machine.screen == expected.screen);
+        let devices = |machine: &HackMachine| {
+            machine
+                .devices()
+                .map(|(base, device)| (base, device.save()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(devices(machine), devices(expected));
+    }
+
+    #[test]
+    fn undoes_across_checkpoints() {
+        let mut machine = HackMachine::banked(COUNTER.to_vec());
+        let mut history = History::default();
+        run(&mut machine, &mut history, SEGMENT_STEPS - 10);
+
+        // Keep every state either side of the second segment's checkpoint
+        let mut states = Vec::new();
+        for _ in 0..20 {
+            states.push(machine.clone());
+            run(&mut machine, &mut history, 1);
+        }
+        assert_eq!(history.len(), SEGMENT_STEPS + 10);
+        let end = machine.clone();
+        for state in states.iter().rev() {
+            assert!(history.undo(&mut machine));
+            assert_same(&machine, state);
+        }
+
+        // Running forward again across the checkpoint gets back to the same place
+        run(&mut machine, &mut history, 20);
+        assert_same(&machine, &end);
+    }
+
+    #[test]
+    fn undoes_to_the_start() {
+        let start = HackMachine::banked(COUNTER.to_vec());
+        let mut machine = start.clone();
+        let mut history = History::default();
+        run(&mut machine, &mut history, 30);
+        while history.undo(&mut machine) {}
+        assert_same(&machine, &start);
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn finds_last_write_after_eviction() {
+        let mut machine = HackMachine::banked(COUNTER.to_vec());
+        let mut history = History::default();
+        let steps = SEGMENT_STEPS * MAX_SEGMENTS + SEGMENT_STEPS / 2;
+        run(&mut machine, &mut history, steps);
+        assert_eq!(history.len(), steps - SEGMENT_STEPS);
+
+        // R2's only write went with the oldest segment
+        assert_eq!(history.last_write(&machine, 2), None);
+
+        let write = history.last_write(&machine, 0).unwrap();
+        assert_eq!(write.pc, 3);
+        assert_eq!(write.new, machine.read(0));
+        assert_eq!(write.old, machine.read(0).wrapping_sub(1));
+        // The screen's mirror above the keyboard names the same word
+        let screen = history.last_write(&machine, KBD).unwrap();
+        assert_eq!(screen.pc, 8);
+        assert_eq!(screen.old, screen.new.wrapping_sub(1));
+
+        // Undoing that many steps lands on the write, on the cycle it reports
+        for _ in 0..write.steps_ago {
+            history.undo(&mut machine);
+        }
+        assert_eq!((machine.pc, machine.cycles), (write.pc, write.cycle));
+    }
+}
@@ -0,0 +1,403 @@
+use crate::{HackMachine, RunResult, Stop, Symbols};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal;
+use std::fmt::Write;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Names for the keys the Hack keyboard reports with codes of their own.
+const KEY_NAMES: [(&str, u16); 14] = [
+    ("space", 32),
+    ("newline", 128),
+    ("backspace", 129),
+    ("left", 130),
+    ("up", 131),
+    ("right", 132),
+    ("down", 133),
+    ("home", 134),
+    ("end", 135),
+    ("pageup", 136),
+    ("pagedown", 137),
+    ("insert", 138),
+    ("delete", 139),
+    ("esc", 140),
+];
+const F1: u16 = 141;
+
+/// Terminals only report presses, repeating them while a key is held, so a key counts as released
+/// once it hasn't repeated for longer than the usual delay before repeats start.
+pub(crate) const RELEASE_AFTER: Duration = Duration::from_millis(600);
+/// How often the recorder checks for keys.
+const SLICES_PER_SECOND: u64 = 100;
+
+/// When a scripted key event happens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// Once the machine has run this many cycles
+    Cycle(u64),
+    /// The next time the program counter reaches the label
+    Label(String, u16),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub trigger: Trigger,
+    /// Key code held from then on, 0 for releasing every key
+    pub code: u16,
+}
+
+/// Keyboard input for a run, one event per line:
+///
+/// ```text
+/// at 10000 press A
+/// at 20000 release
+/// when WAIT press newline
+/// when WAIT release
+/// ```
+///
+/// Events fire in order, each one waiting until the one before it has happened. A `when` line
+/// waits for a visit to its label after the previous event, so two in a row act on separate
+/// visits. Keys are single characters, the names in the Hack character set (`newline`,
+/// `backspace`, `left`, `f1` and so on) or a code written as `#65`. `//` starts a comment.
+#[derive(Clone, Debug, Default)]
+pub struct KeyScript {
+    pub events: Vec<KeyEvent>,
+    next: usize,
+    /// Cycle the last event fired on
+    fired: Option<u64>,
+}
+
+impl KeyScript {
+    /// # Errors
+    /// On lines that don't follow the format, keys without a Hack code and unknown labels.
+    pub fn parse(source: &str, symbols: &Symbols) -> Result<Self, String> {
+        let mut script = KeyScript::default();
+        for (i, line) in source.lines().enumerate() {
+            let line = line.split_once("//").map_or(line, |(code, _)| code).trim();
+            let error = |why: &str| format!("{why} on line {}: {line}", i + 1);
+            let words: Vec<&str> = line.split_whitespace().collect();
+
+            let (trigger, action) = match words[..] {
+                [] => continue,
+                ["at", cycle, ref action @ ..] => (
+                    Trigger::Cycle(cycle.parse().map_err(|_| error("Invalid cycle"))?),
+                    action,
+                ),
+                ["when", label, ref action @ ..] => {
+                    let address = symbols
+                        .rom_address(label)
+                        .ok_or_else(|| error("Unknown label"))?;
+                    (Trigger::Label(label.to_string(), address), action)
+                }
+                _ => return Err(error("Expected at or when")),
+            };
+            let code = match action {
+                ["press", key] => key_code(key).ok_or_else(|| error("Unknown key"))?,
+                ["release"] => 0,
+                _ => return Err(error("Expected press KEY or release")),
+            };
+            script.events.push(KeyEvent { trigger, code });
+        }
+        Ok(script)
+    }
+
+    /// Fires every event that's due, setting the machine's keyboard register. Meant to be called
+    /// before each instruction.
+    pub fn apply(&mut self, machine: &mut HackMachine) {
+        while let Some(event) = self.events.get(self.next) {
+            let due = match event.trigger {
+                Trigger::Cycle(cycle) => machine.cycles >= cycle,
+                Trigger::Label(_, address) => {
+                    machine.pc == address && self.fired.is_none_or(|fired| machine.cycles > fired)
+                }
+            };
+            if !due {
+                break;
+            }
+            machine.keyboard = event.code;
+            self.next += 1;
+            self.fired = Some(machine.cycles);
+        }
+    }
+
+    /// Writes the script back out in the format `parse` reads.
+    #[must_use]
+    pub fn to_source(&self) -> String {
+        let mut source = String::new();
+        for event in &self.events {
+            match &event.trigger {
+                Trigger::Cycle(cycle) => write!(source, "at {cycle}").unwrap(),
+                Trigger::Label(label, _) => write!(source, "when {label}").unwrap(),
+            }
+            if event.code == 0 {
+                writeln!(source, " release").unwrap();
+            } else {
+                writeln!(source, " press {}", key_name(event.code)).unwrap();
+            }
+        }
+        source
+    }
+}
+
+/// The Hack code for a key as written in a script.
+#[must_use]
+pub fn key_code(key: &str) -> Option<u16> {
+    let lower = key.to_lowercase();
+    if let Some(&(_, code)) = KEY_NAMES.iter().find(|(name, _)| *name == lower) {
+        return Some(code);
+    }
+    if let Some(number) = lower.strip_prefix('f').and_then(|n| n.parse::<u16>().ok()) {
+        return (1..=12).contains(&number).then_some(F1 + number - 1);
+    }
+    // A lone # is the key itself
+    if let Some(code) = key.strip_prefix('#').filter(|code| !code.is_empty()) {
+        return code.parse().ok();
+    }
+
+    let key = key
+        .strip_prefix('\'')
+        .and_then(|key| key.strip_suffix('\''))
+        .unwrap_or(key);
+    let mut chars = key.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_graphic() => u16::try_from(u32::from(c)).ok(),
+        _ => None,
+    }
+}
+
+/// How a script spells the key with this code.
+#[must_use]
+pub fn key_name(code: u16) -> String {
+    if let Some((name, _)) = KEY_NAMES.iter().find(|(_, named)| *named == code) {
+        name.to_string()
+    } else if (F1..F1 + 12).contains(&code) {
+        format!("f{}", code - F1 + 1)
+    } else {
+        match char::from_u32(code.into()) {
+            Some(c) if c.is_ascii_graphic() => c.to_string(),
+            _ => format!("#{code}"),
+        }
+    }
+}
+
+/// The Hack code for a key read from the terminal, if the Hack keyboard has it.
+#[must_use]
+pub fn terminal_key_code(key: KeyCode) -> Option<u16> {
+    let name = match key {
+        KeyCode::Char(c) => return (c.is_ascii_graphic() || c == ' ').then_some(c as u16),
+        KeyCode::F(number) => {
+            return (1..=12)
+                .contains(&number)
+                .then(|| F1 + u16::from(number) - 1)
+        }
+        KeyCode::Enter => "newline",
+        KeyCode::Backspace => "backspace",
+        KeyCode::Left => "left",
+        KeyCode::Up => "up",
+        KeyCode::Right => "right",
+        KeyCode::Down => "down",
+        KeyCode::Home => "home",
+        KeyCode::End => "end",
+        KeyCode::PageUp => "pageup",
+        KeyCode::PageDown => "pagedown",
+        KeyCode::Insert => "insert",
+        KeyCode::Delete => "delete",
+        KeyCode::Esc => "esc",
+        _ => return None,
+    };
+    key_code(name)
+}
+
+/// Runs the machine in real time at `cycles_per_second` with keys typed into the terminal held
+/// on its keyboard, and returns what was typed as a script timed in cycles. Ctrl-C ends the
+/// recording.
+///
+/// # Errors
+/// If the terminal can't be switched to raw mode or read from.
+pub fn record(
+    machine: &mut HackMachine,
+    limit: u64,
+    cycles_per_second: u64,
+) -> io::Result<(RunResult, KeyScript)> {
+    terminal::enable_raw_mode()?;
+    let recording = record_raw(machine, limit, cycles_per_second);
+    terminal::disable_raw_mode()?;
+    recording
+}
+
+fn record_raw(
+    machine: &mut HackMachine,
+    limit: u64,
+    cycles_per_second: u64,
+) -> io::Result<(RunResult, KeyScript)> {
+    let mut script = KeyScript::default();
+    let mut press = |machine: &mut HackMachine, code: u16| {
+        if machine.keyboard != code {
+            machine.keyboard = code;
+            script.events.push(KeyEvent {
+                trigger: Trigger::Cycle(machine.cycles),
+                code,
+            });
+        }
+    };
+
+    let start = Instant::now();
+    let slice = (cycles_per_second / SLICES_PER_SECOND).max(1);
+    let mut last_press = start;
+    let mut cycles = 0;
+    let stop = loop {
+        while event::poll(Duration::ZERO)? {
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind == KeyEventKind::Release {
+                continue;
+            }
+            if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+                press(machine, 0);
+                return Ok((
+                    RunResult {
+                        cycles,
+                        stop: Stop::Interrupted,
+                    },
+                    script,
+                ));
+            }
+            if let Some(code) = terminal_key_code(key.code) {
+                press(machine, code);
+                last_press = Instant::now();
+            }
+        }
+        if machine.keyboard != 0 && last_press.elapsed() > RELEASE_AFTER {
+            press(machine, 0);
+        }
+
+        let result = machine.run(slice.min(limit - cycles));
+        cycles += result.cycles;
+        if result.stop == Stop::Halted {
+            break Stop::Halted;
+        }
+        if cycles == limit {
+            break Stop::CycleLimit;
+        }
+
+        // Hold back to the requested speed
+        let nanos = u128::from(cycles) * 1_000_000_000 / u128::from(cycles_per_second);
+        let due = Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX));
+        if let Some(ahead) = due.checked_sub(start.elapsed()) {
+            thread::sleep(ahead);
+        }
+    };
+    Ok((RunResult { cycles, stop }, script))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = "\
+// a comment line
+at 10000 press A
+at 20000 release  // trailing comment
+when WAIT press newline
+when WAIT release
+when 3 press #200
+at 30000 press f12
+at 30001 press space
+";
+
+    fn symbols() -> Symbols {
+        Symbols::parse("label WAIT 0\n").unwrap()
+    }
+
+    #[test]
+    fn parses_scripts() {
+        let script = KeyScript::parse(SCRIPT, &symbols()).unwrap();
+        let events: Vec<(Trigger, u16)> = script
+            .events
+            .iter()
+            .map(|event| (event.trigger.clone(), event.code))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (Trigger::Cycle(10000), 65),
+                (Trigger::Cycle(20000), 0),
+                (Trigger::Label("WAIT".to_string(), 0), 128),
+                (Trigger::Label("WAIT".to_string(), 0), 0),
+                (Trigger::Label("3".to_string(), 3), 200),
+                (Trigger::Cycle(30000), 152),
+                (Trigger::Cycle(30001), 32),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_scripts() {
+        let symbols = symbols();
+        for source in [
+            "at soon press A",
+            "when NOWHERE press A",
+            "at 10 press",
+            "at 10 press f13",
+            "at 10 hold A",
+            "press A",
+        ] {
+            assert!(KeyScript::parse(source, &symbols).is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn writes_scripts_back_out() {
+        let script = KeyScript::parse(SCRIPT, &symbols()).unwrap();
+        let source = script.to_source();
+        assert_eq!(
+            source,
+            "at 10000 press A\nat 20000 release\nwhen WAIT press newline\nwhen WAIT release\n\
+             when 3 press #200\nat 30000 press f12\nat 30001 press space\n"
+        );
+        assert_eq!(
+            KeyScript::parse(&source, &symbols()).unwrap().events,
+            script.events
+        );
+    }
+
+    #[test]
+    fn names_keys_both_ways() {
+        for (name, code) in [
+            ("a", 97),
+            ("'/'", 47),
+            ("newline", 128),
+            ("ESC", 140),
+            ("f1", 141),
+            ("#65", 65),
+        ] {
+            assert_eq!(key_code(name), Some(code), "{name}");
+        }
+        assert_eq!(key_code("f0"), None);
+        assert_eq!(key_code("ab"), None);
+
+        for code in (32..=126).chain(128..=152).chain([0, 200]) {
+            assert_eq!(key_code(&key_name(code)), Some(code), "{code}");
+        }
+    }
+
+    #[test]
+    fn fires_when_events_on_separate_visits() {
+        // WAIT: @WAIT 0;JMP, visiting address 0 every other cycle
+        let mut machine = HackMachine::new(vec![0, 0xEA87]);
+        let mut script = KeyScript::parse(
+            "when WAIT press A\nwhen WAIT release\nat 5 press B",
+            &symbols(),
+        )
+        .unwrap();
+        let mut keys = Vec::new();
+        for _ in 0..8 {
+            script.apply(&mut machine);
+            keys.push(machine.keyboard);
+            machine.step();
+        }
+        assert_eq!(keys, [65, 65, 0, 0, 0, 66, 66, 66]);
+    }
+}
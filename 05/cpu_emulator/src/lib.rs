@@ -0,0 +1,29 @@
+#![warn(clippy::pedantic)]
+
+pub mod coverage;
+pub mod debugger;
+pub mod devices;
+mod disassembler;
+pub mod fast;
+pub mod flame;
+pub mod gdb;
+pub mod history;
+pub mod keyboard;
+mod machine;
+pub mod profile;
+pub mod sanitizer;
+pub mod screen;
+pub mod snapshot;
+mod source_map;
+mod symbols;
+pub mod terminal;
+pub mod test_script;
+pub mod trace_diff;
+
+pub use disassembler::disassemble;
+pub use machine::{
+    alu, jumps, HackMachine, RunResult, Stop, BANK_SELECT, BANK_SIZE, KBD, RAM_SIZE, ROM_SIZE,
+    SCREEN, SCREEN_SIZE, WINDOW,
+};
+pub use source_map::{SourceMap, VmMap};
+pub use symbols::{parse_number, Symbols, HEAP_BASE, PREDEFINED, RETURN_LABEL, STACK_BASE};
@@ -0,0 +1,386 @@
+use crate::devices::{Bank, Device};
+
+/// Start of the screen memory map.
+pub const SCREEN: u16 = 0x4000;
+/// The keyboard memory map.
+pub const KBD: u16 = 0x6000;
+pub const RAM_SIZE: usize = 0x4000;
+pub const SCREEN_SIZE: usize = 0x2000;
+pub const ROM_SIZE: usize = 0x8000;
+/// Where a banked machine's bank register sits, just past the keyboard.
+pub const BANK_SELECT: u16 = 0x6001;
+/// ROM addresses from here up are served out of the selected bank on a banked machine.
+pub const WINDOW: u16 = 0x4000;
+pub const BANK_SIZE: usize = 0x4000;
+
+/// Why a run came to an end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// The program reached a jump to itself that nothing can break out of
+    Halted,
+    CycleLimit,
+    /// Stopped from outside the program, like Ctrl-C while recording keys
+    Interrupted,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RunResult {
+    /// Cycles executed by this run
+    pub cycles: u64,
+    pub stop: Stop,
+}
+
+/// The Hack computer of 05/Computer.hdl: the CPU of 05/CPU.hdl running a ROM against the address
+/// space of 05/Memory.hdl.
+///
+/// Memory follows that chip exactly, including its shortcuts: writes anywhere from 0x4000 up land
+/// in the screen, so 0x6000-0x7FFF mirror it for writes, and reads anywhere from 0x6000 up return
+/// the keyboard. Devices attached above the keyboard take over their addresses from that.
+///
+/// A banked machine runs the images the assembler builds with `--banked`: the ROM holds a fixed
+/// 16K region followed by 16K banks, and the upper half of the address space shows whichever bank
+/// was last written to `BANK_SELECT`.
+#[derive(Clone, Debug)]
+pub struct HackMachine {
+    pub a: u16,
+    pub d: u16,
+    pub pc: u16,
+    /// Data memory, 0x0000-0x3FFF
+    pub ram: Vec<u16>,
+    /// Screen memory, 0x4000-0x5FFF
+    pub screen: Vec<u16>,
+    /// Code of the key currently held down, 0 for none
+    pub keyboard: u16,
+    /// Cycles executed since the machine was created
+    pub cycles: u64,
+    rom: Vec<u16>,
+    /// Attached devices and the addresses they start at
+    devices: Vec<(u16, Box<dyn Device>)>,
+    /// Whether ROM from `WINDOW` up is switched by `BANK_SELECT`
+    banked: bool,
+    /// Words of code in the fixed region and then each bank of a banked machine, not counting the
+    /// padding after them
+    code_lengths: Vec<usize>,
+}
+
+impl HackMachine {
+    /// # Panics
+    /// If the program doesn't fit in the 32K ROM.
+    #[must_use]
+    pub fn new(rom: Vec<u16>) -> Self {
+        assert!(
+            rom.len() <= ROM_SIZE,
+            "Program doesn't fit in ROM without banking"
+        );
+        HackMachine {
+            a: 0,
+            d: 0,
+            pc: 0,
+            ram: vec![0; RAM_SIZE],
+            screen: vec![0; SCREEN_SIZE],
+            keyboard: 0,
+            cycles: 0,
+            rom,
+            devices: Vec::new(),
+            banked: false,
+            code_lengths: Vec::new(),
+        }
+    }
+
+    /// A machine with banked ROM and its bank register attached at `BANK_SELECT`, starting on
+    /// bank 0.
+    #[must_use]
+    pub fn banked(rom: Vec<u16>) -> Self {
+        let mut machine = HackMachine::new(Vec::new());
+        // Regions are padded out with @0, which no program ends on since it would run on
+        machine.code_lengths = rom
+            .chunks(BANK_SIZE)
+            .map(|region| {
+                region
+                    .iter()
+                    .rposition(|&word| word != 0)
+                    .map_or(0, |last| last + 1)
+            })
+            .collect();
+        machine.rom = rom;
+        machine.banked = true;
+        machine
+            .devices
+            .push((BANK_SELECT, Box::new(Bank::default())));
+        machine
+    }
+
+    /// Loads the contents of a `.hack` file, one 16-digit binary word per line.
+    ///
+    /// # Panics
+    /// On lines that aren't binary numbers, or if the program doesn't fit in ROM.
+    #[must_use]
+    pub fn from_hack(source: &str) -> Self {
+        HackMachine::new(parse_hack(source))
+    }
+
+    /// Like `from_hack`, for an image from the assembler's `--banked`.
+    ///
+    /// # Panics
+    /// On lines that aren't binary numbers.
+    #[must_use]
+    pub fn from_banked_hack(source: &str) -> Self {
+        HackMachine::banked(parse_hack(source))
+    }
+
+    #[must_use]
+    pub fn rom(&self) -> &[u16] {
+        &self.rom
+    }
+
+    #[must_use]
+    pub fn is_banked(&self) -> bool {
+        self.banked
+    }
+
+    /// Words past the end of the program read as 0, which executes as `@0`.
+    #[must_use]
+    pub fn fetch(&self, address: u16) -> u16 {
+        let mut location = usize::from(address);
+        if self.banked && address >= WINDOW {
+            location += usize::from(self.read(BANK_SELECT)) * BANK_SIZE;
+        }
+        self.rom.get(location).copied().unwrap_or(0)
+    }
+
+    /// Whether `address` holds part of the program with ROM mapped as it is now: on a banked
+    /// machine, the code in the fixed region below `WINDOW` or in the selected bank above it.
+    #[must_use]
+    pub fn in_program(&self, address: u16) -> bool {
+        if !self.banked {
+            return usize::from(address) < self.rom.len();
+        }
+        let (region, offset) = if address < WINDOW {
+            (0, address)
+        } else {
+            (usize::from(self.read(BANK_SELECT)) + 1, address - WINDOW)
+        };
+        self.code_lengths
+            .get(region)
+            .is_some_and(|&length| usize::from(offset) < length)
+    }
+
+    /// Maps `device` into memory from `base`, somewhere above the keyboard.
+    ///
+    /// # Errors
+    /// If the device wouldn't fit between the keyboard and the end of memory, or overlaps another.
+    pub fn attach(&mut self, base: u16, device: Box<dyn Device>) -> Result<(), String> {
+        let end = u32::from(base) + u32::from(device.size());
+        if base <= KBD || end > 0x8000 {
+            return Err(format!(
+                "Device at {base} doesn't fit between the keyboard and the end of memory"
+            ));
+        }
+        if let Some((other, _)) = self
+            .devices
+            .iter()
+            .find(|(other, attached)| u32::from(*other) < end && base < other + attached.size())
+        {
+            return Err(format!("Device at {base} overlaps the one at {other}"));
+        }
+        self.devices.push((base, device));
+        Ok(())
+    }
+
+    /// The device mapped at an address, and the address's offset into it.
+    #[must_use]
+    pub fn device(&self, address: u16) -> Option<(&dyn Device, u16)> {
+        let address = address & 0x7FFF;
+        self.devices
+            .iter()
+            .find(|(base, device)| (*base..base + device.size()).contains(&address))
+            .map(|(base, device)| (device.as_ref(), address - base))
+    }
+
+    /// Every attached device with the address it starts at, in the order they were attached.
+    pub fn devices(&self) -> impl Iterator<Item = (u16, &dyn Device)> {
+        self.devices
+            .iter()
+            .map(|(base, device)| (*base, device.as_ref()))
+    }
+
+    /// Like `devices`, for changing their state.
+    pub fn devices_mut(&mut self) -> impl Iterator<Item = (u16, &mut Box<dyn Device>)> {
+        self.devices
+            .iter_mut()
+            .map(|(base, device)| (*base, device))
+    }
+
+    fn device_mut(&mut self, address: u16) -> Option<(&mut Box<dyn Device>, u16)> {
+        self.devices
+            .iter_mut()
+            .find(|(base, device)| (*base..base + device.size()).contains(&address))
+            .map(|(base, device)| (device, address - *base))
+    }
+
+    /// Reads memory without side effects, peeking at devices rather than reading them.
+    #[must_use]
+    pub fn read(&self, address: u16) -> u16 {
+        let address = address & 0x7FFF;
+        if address > KBD {
+            if let Some((device, offset)) = self.device(address) {
+                return device.peek(offset, self.cycles);
+            }
+        }
+        if address < SCREEN {
+            self.ram[usize::from(address)]
+        } else if address < KBD {
+            self.screen[usize::from(address - SCREEN)]
+        } else {
+            self.keyboard
+        }
+    }
+
+    /// Reads memory the way the CPU does, so devices see the read.
+    pub(crate) fn load(&mut self, address: u16) -> u16 {
+        let cycles = self.cycles;
+        match self.device_mut(address & 0x7FFF) {
+            Some((device, offset)) => device.read(offset, cycles),
+            None => self.read(address),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u16) {
+        let address = address & 0x7FFF;
+        let cycles = self.cycles;
+        if let Some((device, offset)) = self.device_mut(address) {
+            device.write(offset, value, cycles);
+        } else if address < SCREEN {
+            self.ram[usize::from(address)] = value;
+        } else {
+            self.screen[usize::from(address & 0x1FFF)] = value;
+        }
+    }
+
+    /// Executes one instruction.
+    pub fn step(&mut self) {
+        let instruction = self.fetch(self.pc);
+        self.cycles += 1;
+
+        if instruction & 0x8000 == 0 {
+            self.a = instruction;
+            self.pc = (self.pc + 1) & 0x7FFF;
+            return;
+        }
+
+        // M is read from and written to the address A held before this instruction
+        let address = self.a;
+        let y = if instruction & 0x1000 == 0 {
+            self.a
+        } else {
+            self.load(address)
+        };
+        let out = alu(instruction >> 6, self.d, y);
+
+        if instruction & 0b00_1000 != 0 {
+            self.write(address, out);
+        }
+        let jump_target = self.a;
+        if instruction & 0b10_0000 != 0 {
+            self.a = out;
+        }
+        if instruction & 0b01_0000 != 0 {
+            self.d = out;
+        }
+
+        self.pc = if jumps(instruction, out) {
+            jump_target & 0x7FFF
+        } else {
+            (self.pc + 1) & 0x7FFF
+        };
+    }
+
+    /// Whether the program is stuck in the usual halt idiom: `@N 0;JMP` at address N, or a jump
+    /// back to itself with A already pointing there. Either loops forever without changing state.
+    #[must_use]
+    pub fn halted(&self) -> bool {
+        let instruction = self.fetch(self.pc);
+        if instruction == self.pc {
+            let next = self.fetch((self.pc + 1) & 0x7FFF);
+            is_unconditional_jump(next)
+        } else {
+            self.a & 0x7FFF == self.pc && is_unconditional_jump(instruction)
+        }
+    }
+
+    /// Runs until the program halts or `limit` cycles have passed.
+    pub fn run(&mut self, limit: u64) -> RunResult {
+        self.run_with(limit, |_| {})
+    }
+
+    /// Like `run`, calling `before_step` ahead of every instruction so callers can watch the
+    /// machine or feed it input as it goes.
+    pub fn run_with(&mut self, limit: u64, mut before_step: impl FnMut(&mut Self)) -> RunResult {
+        let mut cycles = 0;
+        loop {
+            if self.halted() {
+                return RunResult {
+                    cycles,
+                    stop: Stop::Halted,
+                };
+            }
+            if cycles == limit {
+                return RunResult {
+                    cycles,
+                    stop: Stop::CycleLimit,
+                };
+            }
+            before_step(self);
+            self.step();
+            cycles += 1;
+        }
+    }
+}
+
+/// The words of a `.hack` file, one 16-digit binary number per line.
+fn parse_hack(source: &str) -> Vec<u16> {
+    source
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| {
+            u16::from_str_radix(line, 2)
+                .unwrap_or_else(|_| panic!("Invalid machine code on line {}: {line}", i + 1))
+        })
+        .collect()
+}
+
+/// A C-instruction with no destination that always jumps, like `0;JMP`.
+pub(crate) fn is_unconditional_jump(instruction: u16) -> bool {
+    instruction & 0x8000 != 0 && instruction & 0b11_1000 == 0 && instruction & 0b111 == 0b111
+}
+
+/// 02/ALU.hdl driven by the zx, nx, zy, ny, f and no bits at the bottom of `control`.
+#[must_use]
+pub fn alu(control: u16, x: u16, y: u16) -> u16 {
+    let x = if control & 0b10_0000 != 0 { 0 } else { x };
+    let x = if control & 0b01_0000 != 0 { !x } else { x };
+    let y = if control & 0b00_1000 != 0 { 0 } else { y };
+    let y = if control & 0b00_0100 != 0 { !y } else { y };
+    let out = if control & 0b10 != 0 {
+        x.wrapping_add(y)
+    } else {
+        x & y
+    };
+    if control & 0b1 != 0 {
+        !out
+    } else {
+        out
+    }
+}
+
+/// Whether a C-instruction's jump bits fire for the ALU output.
+#[must_use]
+pub fn jumps(instruction: u16, out: u16) -> bool {
+    let negative = out & 0x8000 != 0;
+    let zero = out == 0;
+    (instruction & 0b100 != 0 && negative)
+        || (instruction & 0b010 != 0 && zero)
+        || (instruction & 0b001 != 0 && !negative && !zero)
+}
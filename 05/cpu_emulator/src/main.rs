@@ -0,0 +1,632 @@
+#![warn(clippy::pedantic)]
+
+use clap::Parser;
+use cpu_emulator::coverage::Coverage;
+use cpu_emulator::debugger::Debugger;
+use cpu_emulator::devices;
+use cpu_emulator::fast::FastCore;
+use cpu_emulator::flame::FlameGraph;
+use cpu_emulator::keyboard::{self, KeyScript};
+use cpu_emulator::profile::Profiler;
+use cpu_emulator::sanitizer::Sanitizer;
+use cpu_emulator::screen::Image;
+use cpu_emulator::terminal::{self, Mode};
+use cpu_emulator::{
+    gdb, parse_number, snapshot, test_script, HackMachine, RunResult, SourceMap, Stop, Symbols,
+    VmMap, SCREEN,
+};
+use std::fs;
+use std::io;
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Instant;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+#[allow(clippy::struct_excessive_bools)]
+/// Runs Hack machine code on a simulated Hack computer.
+struct Args {
+    /// A .hack file to run, or a .tst test script to run and compare against its .cmp file
+    input_path: String,
+
+    /// Stops after this many cycles if the program hasn't halted by then
+    #[clap(short, long, default_value_t = 10_000_000)]
+    cycles: u64,
+
+    /// Stores a value in memory before running, as ADDRESS=VALUE
+    #[clap(long = "set", value_name = "ADDRESS=VALUE", action = clap::ArgAction::Append)]
+    sets: Vec<String>,
+
+    /// Attaches a memory-mapped device above the keyboard, as KIND@ADDRESS[:OPTION]: timer (option: cycles per countdown tick), rng (seed), console or storage (file). None by default, like the stock Hack computer
+    #[clap(long = "device", value_name = "SPEC", action = clap::ArgAction::Append)]
+    devices: Vec<String>,
+
+    /// Runs an image the assembler built with --banked, switching the ROM from 0x4000 up between 16K banks through a register at 0x6001
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    banked: bool,
+
+    /// Starts from a save state instead of a freshly reset machine, with the same program and devices
+    #[clap(long, value_name = "FILE")]
+    load_state: Option<PathBuf>,
+
+    /// Saves the machine's state when the run ends
+    #[clap(long, value_name = "FILE")]
+    save_state: Option<PathBuf>,
+
+    /// Saves the state the first time the program reaches this label or ROM address instead
+    #[clap(long, value_name = "LABEL", requires = "save-state")]
+    save_at: Option<String>,
+
+    /// Memory to print once the run ends, as FIRST-LAST
+    #[clap(long, default_value = "0-15")]
+    dump: String,
+
+    /// The assembler's .sym file for the program, used to resolve labels [default: the input with .sym, if present]
+    #[clap(long)]
+    symbols: Option<PathBuf>,
+
+    /// Saves the screen when the run ends, as PNG or, for a .pbm path, PBM
+    #[clap(long)]
+    screen: Option<PathBuf>,
+
+    /// Also saves the screen every N cycles, numbering the files after the --screen path
+    #[clap(long, value_name = "N")]
+    screen_every: Option<u64>,
+
+    /// Also saves the screen each time the program reaches this label or ROM address
+    #[clap(long, value_name = "LABEL")]
+    screen_at: Option<String>,
+
+    /// Presses and releases keys as a keyboard script describes
+    #[clap(long, value_name = "FILE")]
+    keys: Option<PathBuf>,
+
+    /// Runs in real time taking keys from the terminal, and saves them as a keyboard script
+    #[clap(long, value_name = "FILE", conflicts_with = "keys")]
+    record_keys: Option<PathBuf>,
+
+    /// Runs showing the screen in the terminal and taking keys from it, for use without a display
+    #[clap(long, action = clap::ArgAction::SetTrue, conflicts_with_all = &["debug", "gdb", "record-keys"])]
+    terminal: bool,
+
+    /// How --terminal draws the screen: braille for 2x4 pixels to a character, or half for 1x2
+    #[clap(long, value_name = "MODE", default_value = "braille")]
+    terminal_mode: Mode,
+
+    /// Shrinks the screen in the terminal by this factor, drawing a pixel black if any it covers are
+    #[clap(long, value_name = "N", default_value_t = 1)]
+    terminal_scale: usize,
+
+    /// Instructions --terminal runs between redraws
+    #[clap(long, value_name = "N", default_value_t = 50_000)]
+    instructions_per_frame: u64,
+
+    /// Redraws per second with --terminal, which with --instructions-per-frame sets the speed
+    #[clap(long, value_name = "N", default_value_t = 30)]
+    fps: u64,
+
+    /// Cycles per second when recording keys
+    #[clap(long, default_value_t = 1_000_000, value_parser = clap::value_parser!(u64).range(1..))]
+    speed: u64,
+
+    /// Starts an interactive debugger instead of running the program; --cycles limits each continue
+    #[clap(long, action = clap::ArgAction::SetTrue, conflicts_with = "record-keys")]
+    debug: bool,
+
+    /// Waits for a GDB connection on a TCP address like localhost:1234, or on Unix a socket path
+    #[clap(long, value_name = "ADDRESS", conflicts_with_all = &["debug", "record-keys"])]
+    gdb: Option<String>,
+
+    /// Counts how often each instruction runs and prints the busiest addresses, labels and source lines
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    profile: bool,
+
+    /// Rows in each table of the profile
+    #[clap(long, value_name = "N", default_value_t = 20)]
+    top: usize,
+
+    /// The assembler's .map file for the program, used to profile by source line [default: the input with .map, if present]
+    #[clap(long, value_name = "FILE")]
+    source_map: Option<PathBuf>,
+
+    /// Writes every instruction executed to a file, as text for a .txt path and compact binary otherwise
+    #[clap(long, value_name = "FILE")]
+    trace: Option<PathBuf>,
+
+    /// Only traces instructions at these ROM addresses, as FIRST-LAST
+    #[clap(long, value_name = "FIRST-LAST", default_value = "0-32767")]
+    trace_range: String,
+
+    /// Follows the VM calling convention to write folded call stacks for flame graph tools, and prints cycles per VM function
+    #[clap(long, value_name = "FILE")]
+    flame: Option<PathBuf>,
+
+    /// Writes an lcov report of the instructions and conditional jumps that ran, by .asm line and, given a .vmmap, by .vm line
+    #[clap(long, value_name = "FILE")]
+    coverage: Option<PathBuf>,
+
+    /// The VM translator's .vmmap file for the program, used for coverage of .vm files [default: the input with .vmmap, if present]
+    #[clap(long, value_name = "FILE")]
+    vm_map: Option<PathBuf>,
+
+    /// Runs the program on both the simple interpreter and the fast core, printing their speeds and exiting with 1 if they disagree
+    #[clap(long, action = clap::ArgAction::SetTrue, conflicts_with_all = &["debug", "gdb", "record-keys", "terminal"])]
+    bench: bool,
+
+    /// Reports reads of unwritten RAM, writes past the screen, jumps past the program and, for VM translator output, SP leaving the stack and stray R13/R14 writes; exits with 1 if any happen
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    sanitize: bool,
+}
+
+#[allow(clippy::too_many_lines)]
+fn main() {
+    let args = Args::parse();
+
+    let input_path = PathBuf::from(&args.input_path);
+    if input_path
+        .extension()
+        .is_some_and(|extension| extension == "tst")
+    {
+        match test_script::run(&input_path) {
+            Ok(()) => println!("End of script - Comparison ended successfully"),
+            Err(why) => {
+                eprintln!("{why}");
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let in_file = match fs::read_to_string(&input_path) {
+        Err(why) => panic!("couldn't open {}: {}", input_path.display(), why),
+        Ok(file) => file,
+    };
+    let mut machine = if args.banked {
+        HackMachine::from_banked_hack(&in_file)
+    } else {
+        HackMachine::from_hack(&in_file)
+    };
+    let symbols =
+        Symbols::load(&input_path, args.symbols.as_deref()).unwrap_or_else(|why| panic!("{why}"));
+    for spec in &args.devices {
+        if let Err(why) =
+            devices::parse(spec).and_then(|(address, device)| machine.attach(address, device))
+        {
+            panic!("{why}")
+        }
+    }
+
+    if let Some(path) = &args.load_state {
+        let state = match fs::read(path) {
+            Err(why) => panic!("couldn't open {}: {}", path.display(), why),
+            Ok(file) => file,
+        };
+        if let Err(why) = snapshot::restore(&mut machine, &state) {
+            panic!("{}: {why}", path.display())
+        }
+    }
+
+    let vm = symbols.is_vm();
+    let mut sanitizer = args.sanitize.then(|| {
+        let mut sanitizer = Sanitizer::new(vm);
+        // Nothing says which words of a save state were ever written, so trust them all
+        if args.load_state.is_some() {
+            (0..SCREEN).for_each(|address| sanitizer.initialize(address));
+        }
+        sanitizer
+    });
+    for set in &args.sets {
+        let Some((address, value)) = set.split_once('=') else {
+            panic!("Invalid --set {set}, expected ADDRESS=VALUE")
+        };
+        let address = number(address);
+        machine.write(address, number(value));
+        if let Some(sanitizer) = &mut sanitizer {
+            sanitizer.initialize(address);
+        }
+    }
+
+    if args.debug {
+        let mut debugger = Debugger::new(machine, symbols, args.cycles);
+        if let Err(why) = debugger.run(io::stdin().lock(), io::stdout()) {
+            panic!("couldn't talk to the terminal: {why}")
+        }
+        return;
+    }
+
+    if let Some(address) = &args.gdb {
+        serve_gdb(&mut machine, address);
+        return;
+    }
+
+    let mut keys = load_keys(args.keys.as_deref(), &symbols);
+
+    let screen_path = args
+        .screen
+        .clone()
+        .unwrap_or_else(|| input_path.with_extension("png"));
+    let screen_at = args.screen_at.as_deref().map(|label| {
+        symbols
+            .rom_address(label)
+            .unwrap_or_else(|| panic!("Unknown label {label}"))
+    });
+    let snapshot = |machine: &HackMachine, tag: &str| {
+        let path = numbered(&screen_path, tag, machine.cycles);
+        if let Err(why) = Image::from_screen(&machine.screen).save(&path) {
+            panic!("{why}")
+        }
+    };
+
+    let mut profiler = (args.profile || args.trace.is_some()).then(Profiler::default);
+    if let (Some(profiler), Some(path)) = (&mut profiler, &args.trace) {
+        let (first, last) = parse_range(&args.trace_range, "--trace-range");
+        *profiler = std::mem::take(profiler)
+            .trace(path, first..=last)
+            .unwrap_or_else(|why| panic!("couldn't create {}: {why}", path.display()));
+    }
+
+    let mut flame = args.flame.as_ref().map(|_| {
+        assert!(
+            symbols.labels().next().is_some(),
+            "--flame needs the program's labels, from a .sym file"
+        );
+        FlameGraph::new(&symbols)
+    });
+
+    let mut coverage = args.coverage.as_ref().map(|_| Coverage::default());
+
+    let save_at = args.save_at.as_deref().map(|label| {
+        symbols
+            .rom_address(label)
+            .unwrap_or_else(|| panic!("Unknown label {label}"))
+    });
+    let mut saved = false;
+    let save_state = |machine: &HackMachine| {
+        if let Some(path) = &args.save_state {
+            if let Err(why) = fs::write(path, snapshot::save(machine)) {
+                panic!("couldn't write {}: {}", path.display(), why)
+            }
+        }
+    };
+
+    // The fast core can't stop for anything between instructions
+    let per_step = args.keys.is_some()
+        || profiler.is_some()
+        || flame.is_some()
+        || coverage.is_some()
+        || sanitizer.is_some()
+        || args.screen_every.is_some()
+        || screen_at.is_some()
+        || save_at.is_some();
+    assert!(
+        !(args.bench && per_step),
+        "--bench can't be used with options that watch every instruction"
+    );
+
+    let before_step = |machine: &mut HackMachine| {
+        keys.apply(machine);
+        if let Some(profiler) = &mut profiler {
+            if let Err(why) = profiler.before_step(machine) {
+                panic!("couldn't write the trace: {why}")
+            }
+        }
+        if let Some(flame) = &mut flame {
+            flame.before_step(machine);
+        }
+        if let Some(coverage) = &mut coverage {
+            coverage.before_step(machine);
+        }
+        if let Some(sanitizer) = &mut sanitizer {
+            sanitizer.before_step(machine);
+        }
+        if args
+            .screen_every
+            .is_some_and(|every| machine.cycles > 0 && machine.cycles.is_multiple_of(every))
+        {
+            snapshot(machine, "");
+        }
+        if screen_at == Some(machine.pc) {
+            snapshot(machine, args.screen_at.as_deref().unwrap());
+        }
+        if !saved && save_at == Some(machine.pc) {
+            save_state(machine);
+            saved = true;
+        }
+    };
+    let result = if let Some(path) = &args.record_keys {
+        let (result, script) = match keyboard::record(&mut machine, args.cycles, args.speed) {
+            Err(why) => panic!("couldn't read the terminal: {why}"),
+            Ok(recording) => recording,
+        };
+        if let Err(why) = fs::write(path, script.to_source()) {
+            panic!("couldn't write {}: {}", path.display(), why)
+        }
+        result
+    } else if args.terminal {
+        let options = terminal::Options {
+            mode: args.terminal_mode,
+            scale: args.terminal_scale,
+            instructions_per_frame: args.instructions_per_frame,
+            frames_per_second: args.fps,
+        };
+        match terminal::run(&mut machine, args.cycles, options, before_step) {
+            Err(why) => panic!("couldn't use the terminal: {why}"),
+            Ok(result) => result,
+        }
+    } else if args.bench {
+        bench(&mut machine, args.cycles)
+    } else if per_step {
+        machine.run_with(args.cycles, before_step)
+    } else {
+        FastCore::default().run(&mut machine, args.cycles)
+    };
+    match result.stop {
+        Stop::Halted => println!("Halted after {} cycles", result.cycles),
+        Stop::CycleLimit => println!("Stopped after {} cycles", result.cycles),
+        Stop::Interrupted => println!("Interrupted after {} cycles", result.cycles),
+    }
+
+    if save_at.is_none() {
+        save_state(&machine);
+    } else if !saved {
+        eprintln!(
+            "Never reached {}, so didn't save the state",
+            args.save_at.as_deref().unwrap()
+        );
+    }
+
+    if let Some(path) = &args.screen {
+        if let Err(why) = Image::from_screen(&machine.screen).save(path) {
+            panic!("{why}")
+        }
+    }
+
+    if let Some(profiler) = &mut profiler {
+        if let Err(why) = profiler.finish(&machine) {
+            panic!("couldn't write the trace: {why}")
+        }
+        if args.profile {
+            let (source_map, source) = load_source_map(&input_path, args.source_map.as_deref());
+            print!(
+                "{}",
+                profiler.report(
+                    machine.rom(),
+                    &symbols,
+                    source_map.as_ref(),
+                    source.as_deref(),
+                    args.top
+                )
+            );
+        }
+    }
+
+    if let (Some(flame), Some(path)) = (&flame, &args.flame) {
+        if let Err(why) = fs::write(path, flame.folded()) {
+            panic!("couldn't write {}: {}", path.display(), why)
+        }
+        print!("{}", flame.report());
+    }
+
+    if let (Some(coverage), Some(path)) = (&mut coverage, &args.coverage) {
+        coverage.finish(&machine);
+        write_coverage(coverage, &machine, &input_path, &args, path);
+    }
+
+    let (first, last) = parse_range(&args.dump, "--dump");
+    for address in first..=last {
+        println!("RAM[{address}] = {}", machine.read(address).cast_signed());
+    }
+
+    if let Some(sanitizer) = &sanitizer {
+        if !sanitizer.reports().is_empty() {
+            eprint!(
+                "Sanitizer found {} problems:\n{}",
+                sanitizer.reports().len(),
+                sanitizer.summary(machine.rom(), &symbols)
+            );
+            process::exit(1);
+        }
+    }
+}
+
+/// Runs a copy of the machine on the simple interpreter and the machine itself on the fast core,
+/// printing how fast each went and exiting if they end up different.
+fn bench(machine: &mut HackMachine, limit: u64) -> RunResult {
+    let mut simple = machine.clone();
+    let start = Instant::now();
+    let expected = simple.run(limit);
+    let simple_time = start.elapsed();
+    let start = Instant::now();
+    let result = FastCore::default().run(machine, limit);
+    let fast_time = start.elapsed();
+
+    #[allow(clippy::cast_precision_loss)]
+    let mips = |cycles: u64, seconds: f64| cycles as f64 / seconds / 1e6;
+    println!(
+        "Simple: {} cycles in {:.3}s, {:.1} MIPS",
+        expected.cycles,
+        simple_time.as_secs_f64(),
+        mips(expected.cycles, simple_time.as_secs_f64())
+    );
+    println!(
+        "Fast:   {} cycles in {:.3}s, {:.1} MIPS, {:.1}x",
+        result.cycles,
+        fast_time.as_secs_f64(),
+        mips(result.cycles, fast_time.as_secs_f64()),
+        simple_time.as_secs_f64() / fast_time.as_secs_f64()
+    );
+
+    let mut differences = Vec::new();
+    if result != expected {
+        differences.push(format!("result {result:?} vs {expected:?}"));
+    }
+    let registers = [
+        ("A", machine.a, simple.a),
+        ("D", machine.d, simple.d),
+        ("PC", machine.pc, simple.pc),
+    ];
+    for (name, fast, simple) in registers {
+        if fast != simple {
+            differences.push(format!("{name} {fast} vs {simple}"));
+        }
+    }
+    if machine.cycles != simple.cycles {
+        differences.push(format!("cycles {} vs {}", machine.cycles, simple.cycles));
+    }
+    let memory = machine.ram.iter().chain(&machine.screen);
+    if let Some((address, (fast, simple))) = memory
+        .zip(simple.ram.iter().chain(&simple.screen))
+        .enumerate()
+        .find(|(_, (fast, simple))| fast != simple)
+    {
+        differences.push(format!("RAM[{address}] {fast} vs {simple}"));
+    }
+    for ((base, fast), (_, simple)) in machine.devices().zip(simple.devices()) {
+        if fast.save() != simple.save() {
+            differences.push(format!("{} at {base}", fast.kind()));
+        }
+    }
+    if !differences.is_empty() {
+        eprintln!(
+            "The fast core disagrees with the simple interpreter (fast vs simple): {}",
+            differences.join(", ")
+        );
+        process::exit(1);
+    }
+    result
+}
+
+/// Serves one debugger session. On Unix, addresses with a `/` in them are socket paths.
+fn serve_gdb(machine: &mut HackMachine, address: &str) {
+    println!("Waiting for GDB on {address}");
+    #[cfg(unix)]
+    let served = address
+        .contains('/')
+        .then(|| serve_unix_socket(machine, address));
+    #[cfg(not(unix))]
+    let served = None;
+    let served = served.unwrap_or_else(|| {
+        let listener = TcpListener::bind(address)
+            .unwrap_or_else(|why| panic!("couldn't listen on {address}: {why}"));
+        listener.accept().and_then(|(mut connection, _)| {
+            connection.set_nodelay(true)?;
+            gdb::serve(machine, &mut connection)
+        })
+    });
+    if let Err(why) = served {
+        panic!("GDB connection failed: {why}")
+    }
+}
+
+#[cfg(unix)]
+fn serve_unix_socket(machine: &mut HackMachine, path: &str) -> io::Result<()> {
+    let listener =
+        UnixListener::bind(path).unwrap_or_else(|why| panic!("couldn't listen on {path}: {why}"));
+    let served = listener
+        .accept()
+        .and_then(|(mut connection, _)| gdb::serve(machine, &mut connection));
+    let _ = fs::remove_file(path);
+    served
+}
+
+fn write_coverage(
+    coverage: &Coverage,
+    machine: &HackMachine,
+    input_path: &Path,
+    args: &Args,
+    path: &Path,
+) {
+    let map_path = args
+        .source_map
+        .clone()
+        .unwrap_or_else(|| input_path.with_extension("map"));
+    assert!(
+        map_path.exists(),
+        "--coverage needs the source map from assembling with --source-map, but {} doesn't exist",
+        map_path.display()
+    );
+    let (Some(map), _) = load_source_map(input_path, Some(&map_path)) else {
+        unreachable!()
+    };
+    let vm_map_path = args
+        .vm_map
+        .clone()
+        .unwrap_or_else(|| input_path.with_extension("vmmap"));
+    let vm_map = (args.vm_map.is_some() || vm_map_path.exists()).then(|| {
+        let map = match fs::read_to_string(&vm_map_path) {
+            Err(why) => panic!("couldn't open {}: {}", vm_map_path.display(), why),
+            Ok(file) => file,
+        };
+        VmMap::parse(&map).unwrap_or_else(|why| panic!("{}: {why}", vm_map_path.display()))
+    });
+
+    let directory = |path: &Path| path.parent().unwrap_or(Path::new("")).to_path_buf();
+    let vm_directory = directory(&vm_map_path);
+    let lcov = coverage.lcov(
+        machine.rom(),
+        &map,
+        &directory(&map_path),
+        vm_map.as_ref().map(|map| (map, vm_directory.as_path())),
+    );
+    if let Err(why) = fs::write(path, lcov) {
+        panic!("couldn't write {}: {}", path.display(), why)
+    }
+
+    let ((executed, instructions), (both, jumps)) = coverage.summary(machine.rom());
+    println!("Ran {executed} of {instructions} instructions, and {both} of {jumps} conditional jumps went both ways");
+}
+
+/// The source map and, if it can be read, the source it maps to.
+fn load_source_map(input_path: &Path, path: Option<&Path>) -> (Option<SourceMap>, Option<String>) {
+    let default_path = input_path.with_extension("map");
+    let path = match path {
+        Some(path) => path,
+        None if default_path.exists() => &default_path,
+        None => return (None, None),
+    };
+    let map = match fs::read_to_string(path) {
+        Err(why) => panic!("couldn't open {}: {}", path.display(), why),
+        Ok(file) => file,
+    };
+    let map = SourceMap::parse(&map).unwrap_or_else(|why| panic!("{}: {why}", path.display()));
+    let source = fs::read_to_string(path.with_file_name(&map.source)).ok();
+    (Some(map), source)
+}
+
+fn load_keys(path: Option<&Path>, symbols: &Symbols) -> KeyScript {
+    let Some(path) = path else {
+        return KeyScript::default();
+    };
+    let source = match fs::read_to_string(path) {
+        Err(why) => panic!("couldn't open {}: {}", path.display(), why),
+        Ok(file) => file,
+    };
+    KeyScript::parse(&source, symbols).unwrap_or_else(|why| panic!("{}: {why}", path.display()))
+}
+
+/// `Fill.png` becomes `Fill-LOOP-001234567.png` for a snapshot at label LOOP on cycle 1234567.
+fn numbered(path: &Path, tag: &str, cycle: u64) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    let tag = if tag.is_empty() {
+        String::new()
+    } else {
+        format!("-{tag}")
+    };
+    path.with_file_name(format!("{stem}{tag}-{cycle:09}.{extension}"))
+}
+
+/// Reads a FIRST-LAST range of addresses given to `option`.
+fn parse_range(range: &str, option: &str) -> (u16, u16) {
+    let Some((first, last)) = range.split_once('-') else {
+        panic!("Invalid {option} {range}, expected FIRST-LAST")
+    };
+    (number(first), number(last))
+}
+
+fn number(text: &str) -> u16 {
+    parse_number(text).unwrap_or_else(|why| panic!("{why}"))
+}
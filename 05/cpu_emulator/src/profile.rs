@@ -0,0 +1,243 @@
+use crate::{disassemble, HackMachine, SourceMap, Symbols, ROM_SIZE, SCREEN};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// Starts binary traces, ahead of their records.
+pub const TRACE_MAGIC: &[u8; 8] = b"HACKTRC1";
+/// Written as the address of trace records for instructions that don't write memory.
+pub const NO_WRITE: u16 = 0xFFFF;
+
+/// Counts how often each ROM address runs, and optionally traces every instruction to a file.
+///
+/// Call `before_step` ahead of each instruction, for example from `HackMachine::run_with`, and
+/// `finish` once the run ends.
+pub struct Profiler {
+    /// Executions of each ROM address
+    pub counts: Vec<u64>,
+    trace: Option<Trace>,
+    /// The traced instruction that's executing, finished once its results are known
+    pending: Option<Pending>,
+}
+
+struct Trace {
+    writer: BufWriter<File>,
+    binary: bool,
+    range: RangeInclusive<u16>,
+}
+
+struct Pending {
+    pc: u16,
+    instruction: u16,
+    /// RAM address the instruction writes
+    write: Option<u16>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler {
+            counts: vec![0; ROM_SIZE],
+            trace: None,
+            pending: None,
+        }
+    }
+}
+
+impl Profiler {
+    /// Also traces the instructions at ROM addresses in `range`. A `.txt` path gets one line per
+    /// instruction:
+    ///
+    /// ```text
+    /// 17 M=D+M A=3 D=5 RAM[3]=12
+    /// ```
+    ///
+    /// Any other path gets `TRACE_MAGIC` followed by a 12-byte record per instruction: PC,
+    /// instruction, A, D, the address written and the value written, each a little-endian u16.
+    /// The address is `NO_WRITE` for instructions that don't write memory. A and D are their
+    /// values after the instruction.
+    ///
+    /// # Errors
+    /// If the file can't be created.
+    pub fn trace(mut self, path: &Path, range: RangeInclusive<u16>) -> io::Result<Self> {
+        let binary = path.extension().is_none_or(|extension| extension != "txt");
+        let mut writer = BufWriter::new(File::create(path)?);
+        if binary {
+            writer.write_all(TRACE_MAGIC)?;
+        }
+        self.trace = Some(Trace {
+            writer,
+            binary,
+            range,
+        });
+        Ok(self)
+    }
+
+    /// # Errors
+    /// If writing the trace fails.
+    pub fn before_step(&mut self, machine: &HackMachine) -> io::Result<()> {
+        self.record_pending(machine)?;
+        let pc = machine.pc;
+        self.counts[usize::from(pc)] += 1;
+
+        if let Some(trace) = &self.trace {
+            if trace.range.contains(&pc) {
+                let instruction = machine.fetch(pc);
+                let writes = instruction & 0x8000 != 0 && instruction & 0b00_1000 != 0;
+                self.pending = Some(Pending {
+                    pc,
+                    instruction,
+                    write: writes.then_some(machine.a & 0x7FFF),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out the last traced instruction and flushes the trace.
+    ///
+    /// # Errors
+    /// If writing the trace fails.
+    pub fn finish(&mut self, machine: &HackMachine) -> io::Result<()> {
+        self.record_pending(machine)?;
+        match &mut self.trace {
+            Some(trace) => trace.writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes out the traced instruction that just ran, now that its results are in.
+    fn record_pending(&mut self, machine: &HackMachine) -> io::Result<()> {
+        let (Some(pending), Some(trace)) = (self.pending.take(), &mut self.trace) else {
+            return Ok(());
+        };
+        let write = pending.write.map(|address| {
+            // Writes at the keyboard's address land in the screen, which reads can't see
+            let value = if address < SCREEN {
+                machine.ram[usize::from(address)]
+            } else {
+                machine.screen[usize::from(address & 0x1FFF)]
+            };
+            (address, value)
+        });
+
+        if trace.binary {
+            let (address, value) = write.unwrap_or((NO_WRITE, 0));
+            for word in [
+                pending.pc,
+                pending.instruction,
+                machine.a,
+                machine.d,
+                address,
+                value,
+            ] {
+                trace.writer.write_all(&word.to_le_bytes())?;
+            }
+        } else {
+            write!(
+                trace.writer,
+                "{} {} A={} D={}",
+                pending.pc,
+                disassemble(pending.instruction),
+                machine.a.cast_signed(),
+                machine.d.cast_signed()
+            )?;
+            if let Some((address, value)) = write {
+                write!(trace.writer, " RAM[{address}]={}", value.cast_signed())?;
+            }
+            writeln!(trace.writer)?;
+        }
+        Ok(())
+    }
+
+    /// Tables of the busiest addresses of `rom`, labels and source lines, `top` rows each.
+    /// Addresses count towards the closest label before them. `source` is the text of the source
+    /// map's file.
+    #[must_use]
+    pub fn report(
+        &self,
+        rom: &[u16],
+        symbols: &Symbols,
+        source_map: Option<&SourceMap>,
+        source: Option<&str>,
+        top: usize,
+    ) -> String {
+        let total: u64 = self.counts.iter().sum();
+        let mut labels: Vec<(u16, &str)> = symbols
+            .labels()
+            .map(|(name, address)| (address, name))
+            .collect();
+        labels.sort_unstable();
+        let label_at = |address: u16| {
+            let i = labels.partition_point(|&(at, _)| at <= address);
+            i.checked_sub(1).map_or("(start)", |i| labels[i].1)
+        };
+
+        let executed = || {
+            (0..=u16::MAX)
+                .zip(&self.counts)
+                .filter(|(_, &count)| count > 0)
+        };
+        let by_address: Vec<(u64, String)> = executed()
+            .map(|(address, &count)| {
+                let instruction = rom.get(usize::from(address)).copied().unwrap_or(0);
+                let name = format!(
+                    "{address:5}  {:24}  {}",
+                    label_at(address),
+                    disassemble(instruction)
+                );
+                (count, name)
+            })
+            .collect();
+
+        let mut by_label: HashMap<&str, u64> = HashMap::new();
+        let mut by_line: HashMap<usize, u64> = HashMap::new();
+        for (address, &count) in executed() {
+            *by_label.entry(label_at(address)).or_default() += count;
+            if let Some(line) = source_map.and_then(|map| map.line(address)) {
+                *by_line.entry(line).or_default() += count;
+            }
+        }
+
+        let mut report = format!("{total} cycles\n");
+        let mut table = |title: &str, mut rows: Vec<(u64, String)>| {
+            rows.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+            writeln!(report, "\n{title}:").unwrap();
+            for (count, name) in rows.into_iter().take(top) {
+                writeln!(report, "{count:12} {:>6}  {name}", percent(count, total)).unwrap();
+            }
+        };
+        table("By address", by_address);
+        table(
+            "By label",
+            by_label
+                .into_iter()
+                .map(|(label, count)| (count, label.to_string()))
+                .collect(),
+        );
+        if let Some(map) = source_map {
+            let text: Vec<&str> = source
+                .map(|source| source.lines().collect())
+                .unwrap_or_default();
+            table(
+                "By source line",
+                by_line
+                    .into_iter()
+                    .map(|(line, count)| {
+                        let code = text.get(line - 1).map_or("", |code| code.trim());
+                        (count, format!("{}:{line:<6} {code}", map.source))
+                    })
+                    .collect(),
+            );
+        }
+        report
+    }
+}
+
+/// `count` as a percentage of `total` to one decimal place.
+pub(crate) fn percent(count: u64, total: u64) -> String {
+    let tenths = (count * 1000).checked_div(total).unwrap_or(0);
+    format!("{}.{}%", tenths / 10, tenths % 10)
+}
@@ -0,0 +1,309 @@
+use crate::{
+    alu, disassemble, jumps, HackMachine, Symbols, HEAP_BASE, KBD, RAM_SIZE, SCREEN, STACK_BASE,
+};
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::mem::{self, Discriminant};
+
+/// Something a program did that's almost certainly a bug.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// Read a RAM address that nothing has written
+    UninitializedRead(u16),
+    KeyboardWrite,
+    /// Wrote an address past the keyboard with no device there, which lands in the screen
+    OutOfBoundsWrite(u16),
+    /// Set SP outside the stack
+    StackPointer(u16),
+    /// Jumped or ran on to an address past the last instruction
+    PastRom(u16),
+    /// Wrote R13 or R14 other than through an `@R13` or `@R14` right before
+    ScratchWrite(u16),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::UninitializedRead(address) => {
+                write!(f, "read RAM[{address}] before anything wrote it")
+            }
+            Problem::KeyboardWrite => write!(f, "wrote to the keyboard register"),
+            Problem::OutOfBoundsWrite(address) => {
+                write!(f, "wrote to {address}, past the end of memory")
+            }
+            Problem::StackPointer(sp) => write!(
+                f,
+                "moved SP to {sp}, outside the stack at {STACK_BASE}-{}",
+                HEAP_BASE - 1
+            ),
+            Problem::PastRom(address) => {
+                write!(f, "went to ROM[{address}], past the end of the program")
+            }
+            Problem::ScratchWrite(register) => write!(
+                f,
+                "wrote R{register} outside the VM translator's call and return code"
+            ),
+        }
+    }
+}
+
+/// A problem along with where and how often it happened.
+#[derive(Clone, Debug)]
+pub struct Report {
+    /// Address of the instruction at fault
+    pub pc: u16,
+    /// Cycle it first happened on
+    pub cycle: u64,
+    /// The first occurrence
+    pub problem: Problem,
+    pub count: u64,
+}
+
+/// Checks each instruction for memory bugs before it runs: reads of RAM that was never written,
+/// writes to the keyboard or past the end of memory, other than to devices, and jumps past the end
+/// of the program.
+///
+/// Code from the VM translator also gets checked for SP leaving the stack and writes to its R13
+/// and R14 scratch registers that don't come from its own call, return and pop code, which always
+/// sets A with `@R13` or `@R14` just before. Call `before_step` ahead of each instruction.
+pub struct Sanitizer {
+    initialized: Vec<bool>,
+    vm: bool,
+    reports: Vec<Report>,
+    /// Where each instruction's report of each kind of problem is
+    seen: HashMap<(u16, Discriminant<Problem>), usize>,
+}
+
+impl Sanitizer {
+    /// A sanitizer for a program that starts with RAM all unwritten, with the VM checks if `vm`.
+    #[must_use]
+    pub fn new(vm: bool) -> Self {
+        let mut initialized = vec![false; RAM_SIZE];
+        if vm {
+            // The bootstrap's call to Sys.init saves the segment pointers before anything sets them
+            initialized[1..=4].fill(true);
+        }
+        Sanitizer {
+            initialized,
+            vm,
+            reports: Vec::new(),
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Counts a RAM address as written, for values set before the run.
+    pub fn initialize(&mut self, address: u16) {
+        if let Some(cell) = self.initialized.get_mut(usize::from(address & 0x7FFF)) {
+            *cell = true;
+        }
+    }
+
+    pub fn before_step(&mut self, machine: &HackMachine) {
+        let pc = machine.pc;
+        let instruction = machine.fetch(pc);
+        if !machine.in_program(pc) {
+            // Already reported on the way out
+            return;
+        }
+        let next = (pc + 1) & 0x7FFF;
+
+        if instruction & 0x8000 == 0 {
+            if !machine.in_program(next) {
+                self.report(machine, Problem::PastRom(next));
+            }
+            return;
+        }
+
+        let address = machine.a & 0x7FFF;
+        let y = if instruction & 0x1000 == 0 {
+            machine.a
+        } else {
+            if address < SCREEN && !self.initialized[usize::from(address)] {
+                self.report(machine, Problem::UninitializedRead(address));
+            }
+            machine.read(address)
+        };
+        let out = alu(instruction >> 6, machine.d, y);
+
+        if instruction & 0b00_1000 != 0 {
+            match address {
+                KBD => self.report(machine, Problem::KeyboardWrite),
+                _ if address > KBD && machine.device(address).is_none() => {
+                    self.report(machine, Problem::OutOfBoundsWrite(address));
+                }
+                _ if address < SCREEN => self.initialized[usize::from(address)] = true,
+                _ => {}
+            }
+            if self.vm && address == 0 && !(STACK_BASE..HEAP_BASE).contains(&out) {
+                self.report(machine, Problem::StackPointer(out));
+            }
+            let set_just_before = pc > 0 && machine.fetch(pc - 1) == address;
+            if self.vm && matches!(address, 13 | 14) && !set_just_before {
+                self.report(machine, Problem::ScratchWrite(address));
+            }
+        }
+
+        let target = if jumps(instruction, out) {
+            machine.a & 0x7FFF
+        } else {
+            next
+        };
+        if !machine.in_program(target) {
+            self.report(machine, Problem::PastRom(target));
+        }
+    }
+
+    fn report(&mut self, machine: &HackMachine, problem: Problem) {
+        let key = (machine.pc, mem::discriminant(&problem));
+        if let Some(&i) = self.seen.get(&key) {
+            self.reports[i].count += 1;
+            return;
+        }
+        self.seen.insert(key, self.reports.len());
+        self.reports.push(Report {
+            pc: machine.pc,
+            cycle: machine.cycles,
+            problem,
+            count: 1,
+        });
+    }
+
+    /// Problems found so far, in the order they first happened.
+    #[must_use]
+    pub fn reports(&self) -> &[Report] {
+        &self.reports
+    }
+
+    /// One line per problem giving the instruction at fault, under the closest label before it.
+    #[must_use]
+    pub fn summary(&self, rom: &[u16], symbols: &Symbols) -> String {
+        let mut labels: Vec<(u16, &str)> = symbols
+            .labels()
+            .map(|(name, address)| (address, name))
+            .collect();
+        labels.sort_unstable();
+
+        let mut summary = String::new();
+        for report in &self.reports {
+            let i = labels.partition_point(|&(at, _)| at <= report.pc);
+            let label = i.checked_sub(1).map_or("(start)", |i| labels[i].1);
+            let instruction = rom.get(usize::from(report.pc)).copied().unwrap_or(0);
+            writeln!(
+                summary,
+                "{:5}  {label:24}  {:12}  {} on cycle {}{}",
+                report.pc,
+                disassemble(instruction),
+                report.problem,
+                report.cycle,
+                match report.count {
+                    1 => String::new(),
+                    count => format!(", {count} times in all"),
+                }
+            )
+            .unwrap();
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BANK_SELECT, BANK_SIZE, WINDOW};
+
+    /// Runs the machine for `cycles` instructions under a sanitizer and returns what it found.
+    fn problems(mut machine: HackMachine, vm: bool, cycles: usize) -> Vec<Problem> {
+        let mut sanitizer = Sanitizer::new(vm);
+        for _ in 0..cycles {
+            sanitizer.before_step(&machine);
+            machine.step();
+        }
+        sanitizer
+            .reports()
+            .iter()
+            .map(|report| report.problem)
+            .collect()
+    }
+
+    #[test]
+    fn finds_memory_problems() {
+        let rom = vec![
+            1, 0xFC10, // @R1 D=M
+            1, 0xE308, // @R1 M=D
+            1, 0xFC10, // @R1 D=M
+            0x6000, 0xEA88, // @KBD M=0
+            0x6005, 0xEA88, // @24581 M=0
+        ];
+        assert_eq!(
+            problems(HackMachine::new(rom), false, 10),
+            [
+                Problem::UninitializedRead(1),
+                Problem::KeyboardWrite,
+                Problem::OutOfBoundsWrite(0x6005),
+                Problem::PastRom(10),
+            ]
+        );
+
+        // Writing a device isn't out of bounds
+        let rom = vec![BANK_SELECT, 0xEA88, 2, 0xEA87]; // @BANK_SELECT M=0, then halt
+        assert_eq!(problems(HackMachine::banked(rom), false, 4), []);
+    }
+
+    #[test]
+    fn finds_vm_problems() {
+        let rom = vec![
+            100, 0xEC10, // @100 D=A
+            0, 0xE308, // @SP M=D
+            13, 0xE308, // @R13 M=D
+            0xE308, // M=D
+            7, 0xEA87, // (END) @END 0;JMP
+        ];
+        assert_eq!(
+            problems(HackMachine::new(rom.clone()), true, 10),
+            [Problem::StackPointer(100), Problem::ScratchWrite(13)]
+        );
+        assert_eq!(problems(HackMachine::new(rom), false, 10), []);
+    }
+
+    #[test]
+    fn reports_each_instruction_once_with_a_count() {
+        let rom = vec![
+            0x4005, 0xEA88, // @SCREEN+5 M=0
+            KBD, 0xEA88, // @KBD M=0
+            0, 0xEA87, // @0 0;JMP
+        ];
+        let mut machine = HackMachine::new(rom);
+        let mut sanitizer = Sanitizer::new(false);
+        for _ in 0..12 {
+            sanitizer.before_step(&machine);
+            machine.step();
+        }
+        let [report] = sanitizer.reports() else {
+            panic!("{:?}", sanitizer.reports());
+        };
+        assert_eq!(
+            (report.pc, report.cycle, report.problem, report.count),
+            (3, 3, Problem::KeyboardWrite, 2)
+        );
+    }
+
+    #[test]
+    fn checks_banked_code_against_the_selected_bank() {
+        // The fixed region jumps into bank 0, which runs off the end of its code into padding
+        // even though bank 1 follows it in the image
+        let mut rom = vec![WINDOW, 0xEA87]; // @WINDOW 0;JMP
+        rom.resize(usize::from(WINDOW), 0);
+        rom.extend([0, 0xEA88]); // @0 M=0
+        rom.resize(usize::from(WINDOW) + BANK_SIZE, 0);
+        rom.extend([WINDOW + 4, 0xEA87]); // @WINDOW+4 0;JMP
+        assert_eq!(
+            problems(HackMachine::banked(rom.clone()), false, 4),
+            [Problem::PastRom(WINDOW + 2)]
+        );
+
+        // On bank 1 the same addresses are code, and the jump past them is caught
+        let mut machine = HackMachine::banked(rom);
+        machine.write(BANK_SELECT, 1);
+        assert_eq!(problems(machine, false, 4), [Problem::PastRom(WINDOW + 4)]);
+    }
+}
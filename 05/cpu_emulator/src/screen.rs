@@ -0,0 +1,325 @@
+use std::fs;
+use std::path::Path;
+
+pub const WIDTH: usize = 512;
+pub const HEIGHT: usize = 256;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// Largest width or height read from a PBM file, well past anything compared with the screen
+const MAX_PBM_SIDE: usize = 4096;
+
+/// A black and white picture, such as the Hack screen. `true` pixels are black.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<bool>,
+}
+
+/// How two images differ.
+#[derive(Clone, Debug)]
+pub struct Diff {
+    /// Number of pixels that don't match
+    pub pixels: usize,
+    /// Smallest rectangle holding every mismatch, as left, top, right and bottom inclusive
+    pub bounds: Option<(usize, usize, usize, usize)>,
+    /// The mismatching pixels drawn black on white
+    pub image: Image,
+}
+
+impl Image {
+    #[must_use]
+    pub fn new(width: usize, height: usize) -> Self {
+        Image {
+            width,
+            height,
+            pixels: vec![false; width * height],
+        }
+    }
+
+    /// Pixels of the 512x256 screen memory map. Each row is 32 words and the lowest bit of a word
+    /// is its leftmost pixel.
+    #[must_use]
+    pub fn from_screen(screen: &[u16]) -> Self {
+        let mut image = Image::new(WIDTH, HEIGHT);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let word = screen[y * WIDTH / 16 + x / 16];
+                image.set(x, y, word >> (x % 16) & 1 != 0);
+            }
+        }
+        image
+    }
+
+    #[must_use]
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, black: bool) {
+        self.pixels[y * self.width + x] = black;
+    }
+
+    /// Encodes the image as a binary (P4) PBM.
+    #[must_use]
+    pub fn to_pbm(&self) -> Vec<u8> {
+        let mut pbm = format!("P4\n{} {}\n", self.width, self.height).into_bytes();
+        pbm.extend(self.packed_rows(true));
+        pbm
+    }
+
+    /// Encodes the image as a 1-bit grayscale PNG.
+    ///
+    /// # Panics
+    /// If the PNG encoder fails, which it only does for images with no pixels.
+    #[must_use]
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut png = Vec::new();
+        let width = u32::try_from(self.width).expect("Image too wide");
+        let height = u32::try_from(self.height).expect("Image too tall");
+        let mut encoder = png::Encoder::new(&mut png, width, height);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::One);
+        let mut writer = encoder.write_header().expect("Couldn't encode PNG");
+        // In grayscale 0 is black, the opposite of PBM
+        writer
+            .write_image_data(&self.packed_rows(false))
+            .expect("Couldn't encode PNG");
+        writer.finish().expect("Couldn't encode PNG");
+        png
+    }
+
+    /// Rows packed eight pixels to a byte, leftmost pixel in the highest bit, with `black` as the
+    /// bit value for black pixels.
+    fn packed_rows(&self, black: bool) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.width.div_ceil(8) * self.height);
+        for row in self.pixels.chunks(self.width) {
+            for byte in row.chunks(8) {
+                let mut packed = 0;
+                for (i, &pixel) in byte.iter().enumerate() {
+                    if pixel == black {
+                        packed |= 0x80 >> i;
+                    }
+                }
+                bytes.push(packed);
+            }
+        }
+        bytes
+    }
+
+    /// Decodes a PNG or a plain (P1) or binary (P4) PBM. Colour PNGs count pixels darker than
+    /// middle grey as black.
+    ///
+    /// # Errors
+    /// If the data isn't a well-formed image in one of those formats.
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.starts_with(PNG_SIGNATURE) {
+            parse_png(bytes)
+        } else if bytes.starts_with(b"P1") || bytes.starts_with(b"P4") {
+            parse_pbm(bytes)
+        } else {
+            Err("Not a PNG or PBM image".to_string())
+        }
+    }
+
+    /// # Errors
+    /// If the file can't be read or isn't an image `parse` understands.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes =
+            fs::read(path).map_err(|why| format!("couldn't open {}: {why}", path.display()))?;
+        Image::parse(&bytes).map_err(|why| format!("{}: {why}", path.display()))
+    }
+
+    /// Writes a PNG, or a PBM when the path ends in `.pbm`.
+    ///
+    /// # Errors
+    /// If the file can't be written.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let bytes = if path.extension().is_some_and(|extension| extension == "pbm") {
+            self.to_pbm()
+        } else {
+            self.to_png()
+        };
+        fs::write(path, bytes).map_err(|why| format!("couldn't write {}: {why}", path.display()))
+    }
+
+    /// Compares two images pixel by pixel. Images of different sizes are compared over the
+    /// larger of the two, with the missing area counting as white.
+    #[must_use]
+    pub fn diff(&self, other: &Image) -> Diff {
+        let width = self.width.max(other.width);
+        let height = self.height.max(other.height);
+        let pixel = |image: &Image, x: usize, y: usize| {
+            x < image.width && y < image.height && image.get(x, y)
+        };
+
+        let mut diff = Diff {
+            pixels: 0,
+            bounds: None,
+            image: Image::new(width, height),
+        };
+        for y in 0..height {
+            for x in 0..width {
+                if pixel(self, x, y) != pixel(other, x, y) {
+                    diff.pixels += 1;
+                    diff.image.set(x, y, true);
+                    diff.bounds = Some(match diff.bounds {
+                        None => (x, y, x, y),
+                        Some((left, top, right, bottom)) => {
+                            (left.min(x), top.min(y), right.max(x), bottom.max(y))
+                        }
+                    });
+                }
+            }
+        }
+        diff
+    }
+}
+
+fn parse_png(bytes: &[u8]) -> Result<Image, String> {
+    let mut decoder = png::Decoder::new(bytes);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(|why| why.to_string())?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let frame = reader
+        .next_frame(&mut buffer)
+        .map_err(|why| why.to_string())?;
+
+    let channels = frame.color_type.samples();
+    let mut image = Image::new(frame.width as usize, frame.height as usize);
+    for y in 0..image.height {
+        let row = &buffer[y * frame.line_size..];
+        for x in 0..image.width {
+            let sample = &row[x * channels..(x + 1) * channels];
+            // Alpha, if any, is the last channel and doesn't affect brightness
+            let colours = if channels == 2 || channels == 4 {
+                &sample[..channels - 1]
+            } else {
+                sample
+            };
+            let brightness = colours.iter().map(|&c| usize::from(c)).sum::<usize>() / colours.len();
+            image.set(x, y, brightness < 128);
+        }
+    }
+    Ok(image)
+}
+
+fn parse_pbm(bytes: &[u8]) -> Result<Image, String> {
+    // Header fields are separated by whitespace, and # starts a comment running to the end of the line
+    let mut position = 2;
+    let mut field = || -> Result<&[u8], String> {
+        loop {
+            match bytes.get(position) {
+                Some(b'#') => {
+                    while bytes.get(position).is_some_and(|&byte| byte != b'\n') {
+                        position += 1;
+                    }
+                }
+                Some(byte) if byte.is_ascii_whitespace() => position += 1,
+                Some(_) => break,
+                None => return Err("PBM header ends early".to_string()),
+            }
+        }
+        let start = position;
+        while bytes
+            .get(position)
+            .is_some_and(|byte| !byte.is_ascii_whitespace())
+        {
+            position += 1;
+        }
+        Ok(&bytes[start..position])
+    };
+    let mut number = || -> Result<usize, String> {
+        std::str::from_utf8(field()?)
+            .ok()
+            .and_then(|text| text.parse().ok())
+            .ok_or_else(|| "Invalid PBM size".to_string())
+    };
+
+    let binary = bytes.starts_with(b"P4");
+    let width = number()?;
+    let height = number()?;
+    if width > MAX_PBM_SIDE || height > MAX_PBM_SIDE {
+        return Err(format!(
+            "PBM size {width}x{height} is larger than {MAX_PBM_SIDE}x{MAX_PBM_SIDE}"
+        ));
+    }
+    let mut image = Image::new(width, height);
+
+    if binary {
+        // Exactly one whitespace byte separates the header from the pixels
+        let data = bytes
+            .get(position + 1..)
+            .ok_or("PBM pixel data ends early")?;
+        let row_bytes = width.div_ceil(8);
+        if data.len() < row_bytes * height {
+            return Err("PBM pixel data ends early".to_string());
+        }
+        for y in 0..height {
+            for x in 0..width {
+                image.set(x, y, data[y * row_bytes + x / 8] & (0x80 >> (x % 8)) != 0);
+            }
+        }
+    } else {
+        let digits = bytes[position..]
+            .split(|&byte| byte == b'\n')
+            .flat_map(|line| line.split(|&byte| byte == b'#').next().unwrap_or_default())
+            .filter(|byte| matches!(byte, b'0' | b'1'));
+        let mut count = 0;
+        for (i, &digit) in digits.take(width * height).enumerate() {
+            image.pixels[i] = digit == b'1';
+            count += 1;
+        }
+        if count < width * height {
+            return Err("PBM pixel data ends early".to_string());
+        }
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plain_pbm_with_comments() {
+        let image = Image::parse(b"P1\n# a comment\n3 2\n1 0 1\n0 1 0 # trailing\n").unwrap();
+        assert_eq!((image.width, image.height), (3, 2));
+        let pixels: Vec<bool> = (0..2)
+            .flat_map(|y| (0..3).map(move |x| (x, y)))
+            .map(|(x, y)| image.get(x, y))
+            .collect();
+        assert_eq!(pixels, [true, false, true, false, true, false]);
+    }
+
+    #[test]
+    fn parses_binary_pbm() {
+        let image = Image::parse(b"P4\n10 2\n\xC0\x40\x00\x80").unwrap();
+        assert!(image.get(0, 0) && image.get(1, 0) && image.get(9, 0));
+        assert!(!image.get(2, 0) && !image.get(8, 0));
+        assert!(image.get(8, 1) && !image.get(0, 1) && !image.get(9, 1));
+    }
+
+    #[test]
+    fn round_trips_through_to_pbm() {
+        let mut image = Image::new(13, 3);
+        image.set(0, 0, true);
+        image.set(12, 2, true);
+        image.set(7, 1, true);
+        assert_eq!(Image::parse(&image.to_pbm()).unwrap(), image);
+    }
+
+    #[test]
+    fn rejects_truncated_pbm() {
+        assert!(Image::parse(b"P4 8 1").is_err());
+        assert!(Image::parse(b"P4\n8 2\n\xFF").is_err());
+        assert!(Image::parse(b"P1\n2 2\n1 0 1").is_err());
+        assert!(Image::parse(b"P1\n2").is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_pbm() {
+        assert!(Image::parse(b"P4\n100000 100000\n").is_err());
+        assert!(Image::parse(b"P1\n18446744073709551615 2\n").is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use crate::{HackMachine, RAM_SIZE, SCREEN_SIZE};
+
+/// Starts save state files, ahead of the state.
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"HACKSAV1";
+
+/// The whole state of a machine, as the contents of a save state file. After `SNAPSHOT_MAGIC`
+/// come, all little-endian:
+///
+/// - the ROM's `rom_hash` and the cycle count, as u64s
+/// - A, D, PC and the keyboard, as u16s
+/// - RAM then screen memory, a u16 per word
+/// - the number of devices as a u16, then for each its base address as a u16, its kind as a u8
+///   length and that many bytes, and its saved state as a u16 count and that many words
+///
+/// The ROM itself isn't saved, so the state has to be restored into a machine running the same
+/// program.
+#[must_use]
+pub fn save(machine: &HackMachine) -> Vec<u8> {
+    let mut state = SNAPSHOT_MAGIC.to_vec();
+    state.extend(rom_hash(machine.rom()).to_le_bytes());
+    state.extend(machine.cycles.to_le_bytes());
+    let registers = [machine.a, machine.d, machine.pc, machine.keyboard];
+    for word in registers.iter().chain(&machine.ram).chain(&machine.screen) {
+        state.extend(word.to_le_bytes());
+    }
+
+    let devices: Vec<_> = machine.devices().collect();
+    state.extend(u16::try_from(devices.len()).unwrap_or(0).to_le_bytes());
+    for (base, device) in devices {
+        let kind = device.kind();
+        let words = device.save();
+        state.extend(base.to_le_bytes());
+        state.push(u8::try_from(kind.len()).unwrap_or(0));
+        state.extend(kind.as_bytes());
+        state.extend(u16::try_from(words.len()).unwrap_or(0).to_le_bytes());
+        for word in words {
+            state.extend(word.to_le_bytes());
+        }
+    }
+    state
+}
+
+/// Puts `machine` back in the state `save` gave. It needs the same program loaded and the same
+/// kinds of devices attached at the same addresses.
+///
+/// # Errors
+/// If the state is cut short or isn't a save state, came from a different program or saved
+/// devices the machine doesn't have. The machine is only changed if the state is restored.
+pub fn restore(machine: &mut HackMachine, state: &[u8]) -> Result<(), String> {
+    let mut reader = Reader { state, at: 0 };
+    if reader.bytes(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+        return Err("Not a save state".to_string());
+    }
+    if reader.u64()? != rom_hash(machine.rom()) {
+        return Err("Save state is from a different program".to_string());
+    }
+    let cycles = reader.u64()?;
+    let registers = reader.words(4)?;
+    let ram = reader.words(RAM_SIZE)?;
+    let screen = reader.words(SCREEN_SIZE)?;
+
+    let count = reader.u16()?;
+    let mut devices = Vec::with_capacity(count.into());
+    for _ in 0..count {
+        let base = reader.u16()?;
+        let length = reader.bytes(1)?[0];
+        let kind = String::from_utf8_lossy(reader.bytes(length.into())?).into_owned();
+        let length = reader.u16()?;
+        devices.push((base, kind, reader.words(length.into())?));
+    }
+    if reader.at != state.len() {
+        return Err("Save state has extra data at the end".to_string());
+    }
+    if devices.len() != machine.devices().count() {
+        return Err(format!(
+            "Save state has {} devices, but the machine has {}",
+            devices.len(),
+            machine.devices().count()
+        ));
+    }
+
+    // Devices can refuse their state, so put them back before touching anything else
+    let mut restored: Vec<_> = machine
+        .devices()
+        .map(|(base, device)| (base, device.clone_box()))
+        .collect();
+    for ((base, device), (saved_base, kind, words)) in restored.iter_mut().zip(&devices) {
+        if *base != *saved_base || device.kind() != kind {
+            return Err(format!(
+                "Save state has a {kind} at {saved_base} where the machine has a {} at {base}",
+                device.kind()
+            ));
+        }
+        device.restore(words)?;
+    }
+    for ((_, device), (_, restored)) in machine.devices_mut().zip(restored) {
+        *device = restored;
+    }
+
+    [machine.a, machine.d, machine.pc, machine.keyboard] =
+        [registers[0], registers[1], registers[2], registers[3]];
+    machine.ram = ram;
+    machine.screen = screen;
+    machine.cycles = cycles;
+    Ok(())
+}
+
+/// FNV-1a over the ROM's words, which tells programs apart without saving the ROM.
+#[must_use]
+pub fn rom_hash(rom: &[u16]) -> u64 {
+    rom.iter()
+        .flat_map(|word| word.to_le_bytes())
+        .fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01B3)
+        })
+}
+
+struct Reader<'a> {
+    state: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .state
+            .get(self.at..self.at + count)
+            .ok_or_else(|| "Save state is cut short".to_string())?;
+        self.at += count;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn words(&mut self, count: usize) -> Result<Vec<u16>, String> {
+        (0..count).map(|_| self.u16()).collect()
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+/// The source line of each ROM address, from the `.map` file the assembler writes with
+/// `--source-map`:
+///
+/// ```text
+/// source Mult.asm
+/// 0 12
+/// 1 13
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    /// The assembly file, relative to the map
+    pub source: String,
+    lines: HashMap<u16, usize>,
+}
+
+impl SourceMap {
+    /// # Errors
+    /// If the first line doesn't name the source or a later one isn't an address and line number.
+    pub fn parse(map: &str) -> Result<Self, String> {
+        let mut lines = map.lines();
+        let source = lines
+            .next()
+            .and_then(|line| line.strip_prefix("source "))
+            .ok_or_else(|| "Source map doesn't start with its source file".to_string())?;
+
+        let mut source_map = SourceMap {
+            source: source.trim().to_string(),
+            lines: HashMap::new(),
+        };
+        for (i, line) in lines.enumerate() {
+            let invalid = || format!("Invalid mapping on line {}: {line}", i + 2);
+            let Some((address, source_line)) = line.split_once(' ') else {
+                return Err(invalid());
+            };
+            let address = address.parse().map_err(|_| invalid())?;
+            let source_line = source_line.trim().parse().map_err(|_| invalid())?;
+            source_map.lines.insert(address, source_line);
+        }
+        Ok(source_map)
+    }
+
+    /// The 1-based source line of the instruction at a ROM address.
+    #[must_use]
+    pub fn line(&self, address: u16) -> Option<usize> {
+        self.lines.get(&address).copied()
+    }
+
+    /// Every ROM address with its source line, in no particular order.
+    pub fn lines(&self) -> impl Iterator<Item = (u16, usize)> + '_ {
+        self.lines.iter().map(|(&address, &line)| (address, line))
+    }
+}
+
+/// The `.vm` line each range of `.asm` lines came from, from the `.vmmap` file the VM translator
+/// writes with `--source-map`:
+///
+/// ```text
+/// 41 Sys.vm 1
+/// 42 Sys.vm 2
+/// 48 Sys.vm 3
+/// ```
+///
+/// Each line gives the first `.asm` line of a VM command's code, then its file, relative to the
+/// map, and line. The code runs up to the next entry.
+#[derive(Clone, Debug, Default)]
+pub struct VmMap {
+    /// First `.asm` line, file and line of each command, in order
+    ranges: Vec<(usize, String, usize)>,
+}
+
+impl VmMap {
+    /// # Errors
+    /// On lines that aren't an `.asm` line, a file and a `.vm` line, or that go backwards.
+    pub fn parse(map: &str) -> Result<Self, String> {
+        let mut ranges: Vec<(usize, String, usize)> = Vec::new();
+        for (i, line) in map.lines().enumerate() {
+            let invalid = || format!("Invalid mapping on line {}: {line}", i + 1);
+            let (asm_line, rest) = line.split_once(' ').ok_or_else(invalid)?;
+            let (file, vm_line) = rest.rsplit_once(' ').ok_or_else(invalid)?;
+            let asm_line = asm_line.parse().map_err(|_| invalid())?;
+            if ranges.last().is_some_and(|&(last, _, _)| last > asm_line) {
+                return Err(invalid());
+            }
+            ranges.push((
+                asm_line,
+                file.to_string(),
+                vm_line.parse().map_err(|_| invalid())?,
+            ));
+        }
+        Ok(VmMap { ranges })
+    }
+
+    /// The `.vm` file and line an `.asm` line came from.
+    #[must_use]
+    pub fn line(&self, asm_line: usize) -> Option<(&str, usize)> {
+        let i = self
+            .ranges
+            .partition_point(|&(first, _, _)| first <= asm_line);
+        let (_, file, line) = &self.ranges[i.checked_sub(1)?];
+        Some((file, *line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_source_map() {
+        let map = SourceMap::parse("source Mult.asm\n0 12\n1 13\n7 20\n").unwrap();
+        assert_eq!(map.source, "Mult.asm");
+        assert_eq!(map.line(0), Some(12));
+        assert_eq!(map.line(7), Some(20));
+        assert_eq!(map.line(2), None);
+        assert_eq!(map.lines().count(), 3);
+    }
+
+    #[test]
+    fn rejects_invalid_source_maps() {
+        assert!(SourceMap::parse("0 12\n").is_err());
+        assert!(SourceMap::parse("source Mult.asm\n0\n").is_err());
+        assert!(SourceMap::parse("source Mult.asm\n0 twelve\n").is_err());
+        assert!(SourceMap::parse("source Mult.asm\n70000 1\n").is_err());
+    }
+
+    #[test]
+    fn parses_vm_map() {
+        let map = VmMap::parse("41 Sys.vm 1\n42 Sys.vm 2\n48 My Game.vm 3\n").unwrap();
+        assert_eq!(map.line(40), None);
+        assert_eq!(map.line(41), Some(("Sys.vm", 1)));
+        assert_eq!(map.line(47), Some(("Sys.vm", 2)));
+        assert_eq!(map.line(48), Some(("My Game.vm", 3)));
+        assert_eq!(map.line(1000), Some(("My Game.vm", 3)));
+    }
+
+    #[test]
+    fn rejects_invalid_vm_maps() {
+        assert!(VmMap::parse("41 Sys.vm\n").is_err());
+        assert!(VmMap::parse("41 Sys.vm one\n").is_err());
+        assert!(VmMap::parse("48 Sys.vm 3\n41 Sys.vm 1\n").is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// RAM cells with names in every Hack program.
+pub const PREDEFINED: [(&str, u16); 7] = [
+    ("SP", 0),
+    ("LCL", 1),
+    ("ARG", 2),
+    ("THIS", 3),
+    ("THAT", 4),
+    ("SCREEN", 0x4000),
+    ("KBD", 0x6000),
+];
+/// Where the VM stack starts.
+pub const STACK_BASE: u16 = 256;
+/// Where the VM heap starts, just past the stack.
+pub const HEAP_BASE: u16 = 2048;
+/// The VM translator's return address labels look like `Main.fib$ret.12`.
+pub const RETURN_LABEL: &str = "$ret.";
+
+/// Labels and variables from the `.sym` file the assembler writes with `--symbols`.
+///
+/// Lines look like `label LOOP 4` or `variable i 16`. Labels from a `--banked` build carry their
+/// bank as a fourth column, which is ignored here, so labels in different banks can share an
+/// address.
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    labels: HashMap<String, u16>,
+    variables: HashMap<String, u16>,
+}
+
+impl Symbols {
+    /// # Errors
+    /// On lines that aren't a label or variable with a numeric value.
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut symbols = Symbols::default();
+        for (i, line) in source.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (table, name, value) = match words[..] {
+                [] => continue,
+                ["label", name, value, ..] => (&mut symbols.labels, name, value),
+                ["variable", name, value] => (&mut symbols.variables, name, value),
+                _ => return Err(format!("Invalid symbol on line {}: {line}", i + 1)),
+            };
+            let value = value
+                .parse()
+                .map_err(|_| format!("Invalid address on line {}: {line}", i + 1))?;
+            table.insert(name.to_string(), value);
+        }
+        Ok(symbols)
+    }
+
+    /// The symbols in `path` if given, otherwise in the `.sym` file next to `input_path` if there is
+    /// one, otherwise none.
+    ///
+    /// # Errors
+    /// If the file can't be read or isn't a valid symbol file.
+    pub fn load(input_path: &Path, path: Option<&Path>) -> Result<Self, String> {
+        let default_path = input_path.with_extension("sym");
+        let path = match path {
+            Some(path) => path,
+            None if default_path.exists() => &default_path,
+            None => return Ok(Symbols::default()),
+        };
+        let source = fs::read_to_string(path)
+            .map_err(|why| format!("couldn't open {}: {why}", path.display()))?;
+        Symbols::parse(&source).map_err(|why| format!("{}: {why}", path.display()))
+    }
+
+    #[must_use]
+    pub fn label(&self, name: &str) -> Option<u16> {
+        self.labels.get(name).copied()
+    }
+
+    #[must_use]
+    pub fn variable(&self, name: &str) -> Option<u16> {
+        self.variables.get(name).copied()
+    }
+
+    /// Every label with its ROM address, in no particular order.
+    pub fn labels(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.labels
+            .iter()
+            .map(|(name, &address)| (name.as_str(), address))
+    }
+
+    /// Whether the program came from the VM translator, whose return addresses are the only labels
+    /// with `$ret.` in them.
+    #[must_use]
+    pub fn is_vm(&self) -> bool {
+        self.labels.keys().any(|name| name.contains(RETURN_LABEL))
+    }
+
+    /// A ROM address written either as a number or as a label.
+    #[must_use]
+    pub fn rom_address(&self, text: &str) -> Option<u16> {
+        text.parse().ok().or_else(|| self.label(text))
+    }
+}
+
+/// Reads an address or value in decimal, negative decimal or 0x hexadecimal.
+///
+/// # Errors
+/// If it's none of those or doesn't fit in a word.
+pub fn parse_number(number: &str) -> Result<u16, String> {
+    let number = number.trim();
+    let parsed = match number.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => number
+            .parse::<u16>()
+            .ok()
+            .or_else(|| number.parse::<i16>().ok().map(i16::cast_unsigned)),
+    };
+    parsed.ok_or_else(|| format!("Invalid number: {number}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_labels_and_variables() {
+        let symbols = Symbols::parse("label LOOP 4\nlabel Far 16384 2\n\nvariable i 16\n").unwrap();
+        assert_eq!(symbols.label("LOOP"), Some(4));
+        assert_eq!(symbols.label("Far"), Some(16384));
+        assert_eq!(symbols.variable("i"), Some(16));
+        assert_eq!(symbols.label("i"), None);
+        assert_eq!(symbols.rom_address("LOOP"), Some(4));
+        assert_eq!(symbols.rom_address("9"), Some(9));
+        assert!(!symbols.is_vm());
+        assert!(Symbols::parse("label Main.main$ret.0 20\n")
+            .unwrap()
+            .is_vm());
+    }
+
+    #[test]
+    fn parses_numbers() {
+        assert_eq!(parse_number("42"), Ok(42));
+        assert_eq!(parse_number(" 0x4000 "), Ok(0x4000));
+        assert_eq!(parse_number("-1"), Ok(0xFFFF));
+        assert!(parse_number("65536").is_err());
+        assert!(parse_number("LOOP").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_symbols() {
+        assert!(Symbols::parse("constant X 1\n").is_err());
+        assert!(Symbols::parse("label LOOP\n").is_err());
+        assert!(Symbols::parse("variable i 16 2\n").is_err());
+        assert!(Symbols::parse("label LOOP -1\n").is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use crate::keyboard::{terminal_key_code, RELEASE_AFTER};
+use crate::screen::{HEIGHT, WIDTH};
+use crate::{HackMachine, RunResult, Stop};
+use crossterm::event::{
+    self, Event, KeyCode, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::{cursor, queue, style, terminal};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How screen pixels become characters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Braille patterns, 2x4 pixels to a character
+    Braille,
+    /// Half blocks, 1x2 pixels to a character
+    HalfBlock,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        match text {
+            "braille" => Ok(Mode::Braille),
+            "half" | "half-block" => Ok(Mode::HalfBlock),
+            _ => Err(format!(
+                "Unknown terminal mode {text}, expected braille or half"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    pub mode: Mode,
+    /// Each character pixel covers this many screen pixels across and down, and shows black if
+    /// any of them are
+    pub scale: usize,
+    pub instructions_per_frame: u64,
+    pub frames_per_second: u64,
+}
+
+/// The screen as lines of text, black pixels drawn and white ones blank.
+#[must_use]
+pub fn render(screen: &[u16], mode: Mode, scale: usize) -> Vec<String> {
+    let scale = scale.max(1);
+    let (width, height) = (WIDTH.div_ceil(scale), HEIGHT.div_ceil(scale));
+    let pixel = |x: usize, y: usize| {
+        (y * scale..((y + 1) * scale).min(HEIGHT)).any(|y| {
+            (x * scale..((x + 1) * scale).min(WIDTH))
+                .any(|x| screen[y * WIDTH / 16 + x / 16] >> (x % 16) & 1 != 0)
+        })
+    };
+
+    match mode {
+        Mode::Braille => (0..height.div_ceil(4))
+            .map(|row| {
+                (0..width.div_ceil(2))
+                    .map(|column| {
+                        let (x, y) = (column * 2, row * 4);
+                        // Braille numbers its dots down the left column, then the right, then the
+                        // bottom row
+                        let dots = [
+                            (0, 0, 0x01),
+                            (0, 1, 0x02),
+                            (0, 2, 0x04),
+                            (1, 0, 0x08),
+                            (1, 1, 0x10),
+                            (1, 2, 0x20),
+                            (0, 3, 0x40),
+                            (1, 3, 0x80),
+                        ];
+                        let bits = dots
+                            .iter()
+                            .filter(|&&(dx, dy, _)| pixel(x + dx, y + dy))
+                            .fold(0, |bits, &(_, _, bit)| bits | bit);
+                        char::from_u32(0x2800 + bits).unwrap_or(' ')
+                    })
+                    .collect()
+            })
+            .collect(),
+        Mode::HalfBlock => (0..height.div_ceil(2))
+            .map(|row| {
+                (0..width)
+                    .map(|x| match (pixel(x, row * 2), pixel(x, row * 2 + 1)) {
+                        (true, true) => '█',
+                        (true, false) => '▀',
+                        (false, true) => '▄',
+                        (false, false) => ' ',
+                    })
+                    .collect()
+            })
+            .collect(),
+    }
+}
+
+/// Runs the machine in the terminal, drawing its screen after every frame's instructions and
+/// holding keys typed into the terminal on its keyboard, until it halts, `limit` cycles pass or
+/// Ctrl-C. Unless it was Ctrl-C, the last frame stays up until a key is pressed. Calls
+/// `before_step` ahead of every instruction, like `HackMachine::run_with`.
+///
+/// Terminals that report key releases release keys when they come up. Others only repeat presses
+/// while a key is held, so a key counts as released once it stops repeating.
+///
+/// # Errors
+/// If the terminal can't be set up, read from or drawn on.
+pub fn run(
+    machine: &mut HackMachine,
+    limit: u64,
+    options: Options,
+    before_step: impl FnMut(&mut HackMachine),
+) -> io::Result<RunResult> {
+    let mut stdout = io::stdout();
+    terminal::enable_raw_mode()?;
+    let enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false);
+    if enhanced {
+        queue!(
+            stdout,
+            PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+        )?;
+    }
+    queue!(
+        stdout,
+        terminal::EnterAlternateScreen,
+        cursor::Hide,
+        terminal::Clear(terminal::ClearType::All)
+    )?;
+
+    let result = run_raw(machine, limit, options, before_step, &mut stdout);
+
+    if enhanced {
+        queue!(stdout, PopKeyboardEnhancementFlags)?;
+    }
+    queue!(stdout, cursor::Show, terminal::LeaveAlternateScreen)?;
+    stdout.flush()?;
+    terminal::disable_raw_mode()?;
+    result
+}
+
+fn run_raw(
+    machine: &mut HackMachine,
+    limit: u64,
+    options: Options,
+    mut before_step: impl FnMut(&mut HackMachine),
+    stdout: &mut impl Write,
+) -> io::Result<RunResult> {
+    let frame =
+        Duration::from_secs(1) / u32::try_from(options.frames_per_second.max(1)).unwrap_or(1);
+    let mut shown: Vec<String> = Vec::new();
+    let mut last_press = Instant::now();
+    let mut cycles = 0;
+    let stop = 'frames: loop {
+        let start = Instant::now();
+        while event::poll(Duration::ZERO)? {
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+                break 'frames Stop::Interrupted;
+            }
+            let Some(code) = terminal_key_code(key.code) else {
+                continue;
+            };
+            if key.kind == KeyEventKind::Release {
+                if machine.keyboard == code {
+                    machine.keyboard = 0;
+                }
+            } else {
+                machine.keyboard = code;
+                last_press = Instant::now();
+            }
+        }
+        if machine.keyboard != 0 && last_press.elapsed() > RELEASE_AFTER {
+            machine.keyboard = 0;
+        }
+
+        let result = machine.run_with(
+            options.instructions_per_frame.max(1).min(limit - cycles),
+            &mut before_step,
+        );
+        cycles += result.cycles;
+        draw(machine, options, &mut shown, stdout)?;
+        if result.stop == Stop::Halted {
+            break Stop::Halted;
+        }
+        if cycles == limit {
+            break Stop::CycleLimit;
+        }
+        if let Some(rest) = frame.checked_sub(start.elapsed()) {
+            thread::sleep(rest);
+        }
+    };
+    if stop != Stop::Interrupted {
+        // Keep the last frame up until it's been seen
+        let message = match stop {
+            Stop::Halted => "Halted",
+            _ => "Stopped",
+        };
+        queue!(
+            stdout,
+            style::Print(format!("  {message}, press a key to leave"))
+        )?;
+        stdout.flush()?;
+        while !matches!(event::read()?, Event::Key(key) if key.kind == KeyEventKind::Press) {}
+    }
+    Ok(RunResult { cycles, stop })
+}
+
+/// Redraws the lines of the screen that changed since the last frame, then the status line.
+fn draw(
+    machine: &HackMachine,
+    options: Options,
+    shown: &mut Vec<String>,
+    stdout: &mut impl Write,
+) -> io::Result<()> {
+    let lines = render(&machine.screen, options.mode, options.scale);
+    for (row, line) in lines.iter().enumerate() {
+        if shown.get(row) != Some(line) {
+            let row = u16::try_from(row).unwrap_or(u16::MAX);
+            queue!(stdout, cursor::MoveTo(0, row), style::Print(line))?;
+        }
+    }
+    let status = format!(
+        "cycle {}  PC {}  key {}  (Ctrl-C quits)",
+        machine.cycles, machine.pc, machine.keyboard
+    );
+    let row = u16::try_from(lines.len()).unwrap_or(u16::MAX);
+    queue!(
+        stdout,
+        cursor::MoveTo(0, row),
+        style::Print(status),
+        terminal::Clear(terminal::ClearType::UntilNewLine)
+    )?;
+    *shown = lines;
+    stdout.flush()
+}
@@ -0,0 +1,620 @@
+use crate::{HackMachine, KBD};
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command as Process;
+
+/// How a column of the output list prints its variable, as in `RAM[0]%D2.6.2`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Column {
+    variable: String,
+    format: char,
+    pad_left: usize,
+    length: usize,
+    pad_right: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Command {
+    Load(Option<String>),
+    OutputFile(String),
+    CompareTo(String),
+    OutputList(Vec<Column>),
+    Set(String, String),
+    Tick,
+    Tock,
+    TickTock,
+    Output,
+    Echo(String),
+    Repeat(Option<u64>, Vec<Command>),
+    While(String, String, String, Vec<Command>),
+    /// Commands that only matter to the interactive tools, like breakpoint and clear-echo
+    Ignored,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Symbol(char),
+}
+
+/// Runs a script for the official CPU emulator, such as 04/mult/Mult.tst or the VM translator's
+/// test scripts, writing its output file and checking it against the compare file line by line.
+///
+/// `load` takes `.hack` files, or `.asm` files which are assembled by the assembler binary named
+/// in `HACK_ASSEMBLER` (`assembler` on the path by default) unless an up to date `.hack` sits next
+/// to them. `set RAM[24576]` presses a key like the emulator's keyboard does.
+///
+/// # Errors
+/// If the script can't be read or parsed, refers to missing files, or its output differs from the
+/// compare file. The message says which line failed.
+pub fn run(script_path: &Path) -> Result<(), String> {
+    let source = fs::read_to_string(script_path)
+        .map_err(|why| format!("couldn't open {}: {why}", script_path.display()))?;
+    let commands = parse(&tokenize(&source)?)?;
+
+    let mut runner = Runner {
+        directory: script_path.parent().unwrap_or(Path::new("")).to_path_buf(),
+        script_name: script_path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string(),
+        machine: HackMachine::new(Vec::new()),
+        columns: Vec::new(),
+        output_path: None,
+        output: String::new(),
+        compare: None,
+        lines: 0,
+        ticked: false,
+        time: 0,
+    };
+    let result = runner.execute(&commands);
+
+    if let Some(path) = &runner.output_path {
+        fs::write(path, &runner.output)
+            .map_err(|why| format!("couldn't write {}: {why}", path.display()))?;
+    }
+    result
+}
+
+struct Runner {
+    directory: PathBuf,
+    script_name: String,
+    machine: HackMachine,
+    columns: Vec<Column>,
+    output_path: Option<PathBuf>,
+    output: String,
+    compare: Option<Vec<String>>,
+    /// Lines written to the output so far
+    lines: usize,
+    /// Whether a tick is waiting for its tock
+    ticked: bool,
+    time: u64,
+}
+
+impl Runner {
+    fn execute(&mut self, commands: &[Command]) -> Result<(), String> {
+        for command in commands {
+            match command {
+                Command::Load(file) => {
+                    let file = file
+                        .clone()
+                        .unwrap_or_else(|| format!("{}.hack", self.script_name));
+                    self.machine = HackMachine::from_hack(&load(&self.directory.join(file))?);
+                    self.time = 0;
+                }
+                Command::OutputFile(file) => {
+                    self.output_path = Some(self.directory.join(file));
+                }
+                Command::CompareTo(file) => {
+                    let path = self.directory.join(file);
+                    let compare = fs::read_to_string(&path)
+                        .map_err(|why| format!("couldn't open {}: {why}", path.display()))?;
+                    self.compare = Some(compare.lines().map(ToString::to_string).collect());
+                }
+                Command::OutputList(columns) => {
+                    self.columns.clone_from(columns);
+                    let mut header = "|".to_string();
+                    for column in columns {
+                        let width = column.pad_left + column.length + column.pad_right;
+                        let name: String = column.variable.chars().take(width).collect();
+                        let left = (width - name.len()) / 2;
+                        let right = width - left - name.len();
+                        write!(header, "{}{name}{}|", " ".repeat(left), " ".repeat(right)).unwrap();
+                    }
+                    self.write_line(&header)?;
+                }
+                Command::Set(variable, value) => self.set(variable, parse_value(value)?)?,
+                Command::Tick => self.ticked = true,
+                Command::Tock => {
+                    if self.ticked {
+                        self.step();
+                    }
+                }
+                Command::TickTock => self.step(),
+                Command::Output => {
+                    let mut line = "|".to_string();
+                    for column in &self.columns {
+                        let value = self.format(column)?;
+                        let value: String = value
+                            .chars()
+                            .skip(value.len().saturating_sub(column.length))
+                            .collect();
+                        let fill = " ".repeat(column.length - value.len());
+                        let (left, right) = if column.format == 'S' {
+                            (String::new(), fill)
+                        } else {
+                            (fill, String::new())
+                        };
+                        write!(
+                            line,
+                            "{}{left}{value}{right}{}|",
+                            " ".repeat(column.pad_left),
+                            " ".repeat(column.pad_right)
+                        )
+                        .unwrap();
+                    }
+                    self.write_line(&line)?;
+                }
+                Command::Echo(text) => println!("{text}"),
+                Command::Repeat(count, body) => match count {
+                    Some(count) => {
+                        for _ in 0..*count {
+                            self.execute(body)?;
+                        }
+                    }
+                    None => loop {
+                        self.execute(body)?;
+                    },
+                },
+                Command::While(left, operator, right, body) => {
+                    while self.condition(left, operator, right)? {
+                        self.execute(body)?;
+                    }
+                }
+                Command::Ignored => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn step(&mut self) {
+        self.machine.step();
+        self.ticked = false;
+        self.time += 1;
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<(), String> {
+        self.output.push_str(line);
+        self.output.push('\n');
+        self.lines += 1;
+
+        if let Some(compare) = &self.compare {
+            let expected = compare.get(self.lines - 1).map_or("", String::as_str);
+            let matches = expected.len() == line.len()
+                && expected
+                    .chars()
+                    .zip(line.chars())
+                    .all(|(expected, actual)| expected == '*' || expected == actual);
+            if !matches {
+                return Err(format!(
+                    "Comparison failure at line {}\nexpected: {expected}\nactual:   {line}",
+                    self.lines
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, variable: &str) -> Result<u16, String> {
+        let indexed = |name: &str| {
+            variable
+                .strip_prefix(name)
+                .and_then(|rest| rest.strip_prefix('['))
+                .and_then(|rest| rest.strip_suffix(']'))
+                .and_then(|index| index.parse::<u16>().ok())
+        };
+        Ok(match variable {
+            "A" => self.machine.a,
+            "D" => self.machine.d,
+            "PC" => self.machine.pc,
+            "time" => u16::try_from(self.time).unwrap_or(u16::MAX),
+            _ => match (indexed("RAM"), indexed("ROM")) {
+                (Some(address), _) => self.machine.read(address),
+                (_, Some(address)) => self.machine.fetch(address),
+                _ => return Err(format!("Unknown variable {variable}")),
+            },
+        })
+    }
+
+    fn set(&mut self, variable: &str, value: u16) -> Result<(), String> {
+        match variable {
+            "A" => self.machine.a = value,
+            "D" => self.machine.d = value,
+            "PC" => self.machine.pc = value & 0x7FFF,
+            _ => {
+                let Some(address) = variable
+                    .strip_prefix("RAM[")
+                    .and_then(|rest| rest.strip_suffix(']'))
+                    .and_then(|index| index.parse::<u16>().ok())
+                else {
+                    return Err(format!("Can't set {variable}"));
+                };
+                if address == KBD {
+                    self.machine.keyboard = value;
+                } else {
+                    self.machine.write(address, value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn format(&self, column: &Column) -> Result<String, String> {
+        let value = self.get(&column.variable)?;
+        Ok(match column.format {
+            'X' => format!("{value:04X}"),
+            'B' => format!("{value:016b}"),
+            _ => value.cast_signed().to_string(),
+        })
+    }
+
+    fn condition(&self, left: &str, operator: &str, right: &str) -> Result<bool, String> {
+        let value = |operand: &str| match self.get(operand) {
+            Ok(value) => Ok(value.cast_signed()),
+            Err(_) => parse_value(operand).map(u16::cast_signed),
+        };
+        let (left, right) = (value(left)?, value(right)?);
+        Ok(match operator {
+            "=" => left == right,
+            "<>" => left != right,
+            "<" => left < right,
+            "<=" => left <= right,
+            ">" => left > right,
+            ">=" => left >= right,
+            _ => return Err(format!("Unknown comparison {operator}")),
+        })
+    }
+}
+
+/// Reads a program, assembling `.asm` files first unless the `.hack` beside them is newer.
+fn load(path: &Path) -> Result<String, String> {
+    let hack_path = path.with_extension("hack");
+    if path.extension().is_some_and(|extension| extension == "asm") {
+        let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+        let up_to_date = matches!(
+            (modified(&hack_path), modified(path)),
+            (Ok(hack), Ok(asm)) if hack >= asm
+        );
+        if !up_to_date {
+            let assembler = env::var("HACK_ASSEMBLER").unwrap_or_else(|_| "assembler".to_string());
+            let status = Process::new(&assembler)
+                .arg(path)
+                .status()
+                .map_err(|why| format!("couldn't run {assembler}: {why}"))?;
+            if !status.success() {
+                return Err(format!("{assembler} failed on {}", path.display()));
+            }
+        }
+    }
+    fs::read_to_string(&hack_path)
+        .map_err(|why| format!("couldn't open {}: {why}", hack_path.display()))
+}
+
+/// Numbers in scripts are decimal unless prefixed with %X, %B or %D.
+fn parse_value(text: &str) -> Result<u16, String> {
+    let parsed = if let Some(hex) = text.strip_prefix("%X") {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("%B") {
+        u16::from_str_radix(binary, 2).ok()
+    } else {
+        let decimal = text.strip_prefix("%D").unwrap_or(text);
+        decimal
+            .parse::<u16>()
+            .ok()
+            .or_else(|| decimal.parse::<i16>().ok().map(i16::cast_unsigned))
+    };
+    parsed.ok_or_else(|| format!("Invalid value {text}"))
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '/' if chars.peek() == Some(&'/') => while chars.next_if(|&c| c != '\n').is_some() {},
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if previous == '*' => break,
+                        Some(c) => previous = c,
+                        None => return Err("Unterminated comment".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => text.push(c),
+                        None => return Err("Unterminated string".to_string()),
+                    }
+                }
+                tokens.push(Token::Text(text));
+            }
+            ',' | ';' | '!' | '{' | '}' => tokens.push(Token::Symbol(c)),
+            c if c.is_whitespace() => {}
+            c => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && !",;!{}\"".contains(c))
+                {
+                    word.push(c);
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse(tokens: &[Token]) -> Result<Vec<Command>, String> {
+    let mut position = 0;
+    let commands = parse_block(tokens, &mut position)?;
+    match tokens.get(position) {
+        None => Ok(commands),
+        Some(token) => Err(format!("Unexpected {token:?}")),
+    }
+}
+
+/// Parses commands up to the end of the script or a closing brace, which is left in place.
+fn parse_block(tokens: &[Token], position: &mut usize) -> Result<Vec<Command>, String> {
+    let mut commands = Vec::new();
+    while let Some(token) = tokens.get(*position) {
+        if *token == Token::Symbol('}') {
+            break;
+        }
+
+        // A command is everything up to its separator or, for loops, its opening brace
+        let start = *position;
+        while tokens
+            .get(*position)
+            .is_some_and(|token| !matches!(token, Token::Symbol(_)))
+        {
+            *position += 1;
+        }
+        let words: Vec<&str> = tokens[start..*position]
+            .iter()
+            .map(|token| match token {
+                Token::Word(word) | Token::Text(word) => word.as_str(),
+                Token::Symbol(_) => unreachable!(),
+            })
+            .collect();
+
+        if tokens.get(*position) == Some(&Token::Symbol('{')) {
+            *position += 1;
+            let body = parse_block(tokens, position)?;
+            if tokens.get(*position) != Some(&Token::Symbol('}')) {
+                return Err("Missing }".to_string());
+            }
+            *position += 1;
+            commands.push(parse_loop(&words, body)?);
+        } else {
+            // Any separator ends the command, and `!` pauses in the interactive tools
+            *position += 1;
+            if !words.is_empty() {
+                commands.push(parse_command(&words)?);
+            }
+        }
+    }
+    Ok(commands)
+}
+
+fn parse_loop(words: &[&str], body: Vec<Command>) -> Result<Command, String> {
+    match words {
+        ["repeat"] => Ok(Command::Repeat(None, body)),
+        ["repeat", count] => {
+            let count = count
+                .parse()
+                .map_err(|_| format!("Invalid repeat count {count}"))?;
+            Ok(Command::Repeat(Some(count), body))
+        }
+        ["while", condition @ ..] => {
+            let condition = condition.concat();
+            let Some((i, operator)) = ["<>", "<=", ">=", "=", "<", ">"]
+                .iter()
+                .find_map(|operator| condition.find(operator).map(|i| (i, *operator)))
+            else {
+                return Err(format!("Invalid condition {condition}"));
+            };
+            Ok(Command::While(
+                condition[..i].to_string(),
+                operator.to_string(),
+                condition[i + operator.len()..].to_string(),
+                body,
+            ))
+        }
+        _ => Err(format!("Unknown loop {}", words.join(" "))),
+    }
+}
+
+fn parse_command(words: &[&str]) -> Result<Command, String> {
+    Ok(match words {
+        ["load"] => Command::Load(None),
+        ["load", file] => Command::Load(Some((*file).to_string())),
+        ["output-file", file] => Command::OutputFile((*file).to_string()),
+        ["compare-to", file] => Command::CompareTo((*file).to_string()),
+        ["output-list", columns @ ..] => Command::OutputList(
+            columns
+                .iter()
+                .map(|column| parse_column(column))
+                .collect::<Result<_, _>>()?,
+        ),
+        ["set", variable, value] => Command::Set((*variable).to_string(), (*value).to_string()),
+        ["tick"] => Command::Tick,
+        ["tock"] => Command::Tock,
+        ["ticktock"] => Command::TickTock,
+        ["output"] => Command::Output,
+        ["echo", text] => Command::Echo((*text).to_string()),
+        ["clear-echo" | "breakpoint" | "clear-breakpoints", ..] => Command::Ignored,
+        _ => return Err(format!("Unknown command {}", words.join(" "))),
+    })
+}
+
+/// `RAM[0]%D2.6.2` is RAM[0] in decimal, 6 characters wide with 2 spaces either side.
+fn parse_column(column: &str) -> Result<Column, String> {
+    let invalid = || format!("Invalid output column {column}");
+    let Some((variable, format)) = column.split_once('%') else {
+        return Ok(Column {
+            variable: column.to_string(),
+            format: 'D',
+            pad_left: 1,
+            length: 6,
+            pad_right: 1,
+        });
+    };
+    let mut chars = format.chars();
+    let kind = chars
+        .next()
+        .filter(|kind| "DXBS".contains(*kind))
+        .ok_or_else(invalid)?;
+    let sizes: Vec<usize> = chars
+        .as_str()
+        .split('.')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+    let [pad_left, length, pad_right] = sizes[..] else {
+        return Err(invalid());
+    };
+    Ok(Column {
+        variable: variable.to_string(),
+        format: kind,
+        pad_left,
+        length,
+        pad_right,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sets RAM[1] to RAM[0] + 1 and halts.
+    const INCREMENT: &str = "0000000000000000
+1111110000010000
+0000000000000001
+1110011111001000
+0000000000000100
+1110101010000111
+";
+
+    const SCRIPT: &str = "// Runs Inc.hack until RAM[1] is set
+load Inc.hack, output-file Inc.out, compare-to Inc.cmp,
+output-list RAM[0]%D2.6.2 RAM[1]%B1.16.1 time%D1.4.1;
+
+set RAM[0] 5, output;
+while RAM[1] <> 6 {
+    ticktock;
+}
+output;
+";
+
+    const OUTPUT: &str = "|  RAM[0]  |      RAM[1]      | time |
+|       5  | 0000000000000000 |    0 |
+|       5  | 0000000000000110 |    4 |
+";
+
+    /// Writes the program, script and compare file to a fresh directory and runs the script.
+    fn run_with(name: &str, compare: &str) -> (Result<(), String>, String) {
+        let directory = env::temp_dir().join(format!("test_script_{name}_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("Inc.hack"), INCREMENT).unwrap();
+        fs::write(directory.join("Inc.tst"), SCRIPT).unwrap();
+        fs::write(directory.join("Inc.cmp"), compare).unwrap();
+        let result = run(&directory.join("Inc.tst"));
+        let output = fs::read_to_string(directory.join("Inc.out")).unwrap_or_default();
+        fs::remove_dir_all(&directory).unwrap();
+        (result, output)
+    }
+
+    #[test]
+    fn runs_script_with_official_columns() {
+        let (result, output) = run_with("exact", OUTPUT);
+        assert_eq!(result, Ok(()));
+        assert_eq!(output, OUTPUT);
+    }
+
+    #[test]
+    fn wildcard_matches_any_character() {
+        let compare = OUTPUT.replace("|    4 |", "|    * |");
+        assert_eq!(run_with("wildcard", &compare).0, Ok(()));
+    }
+
+    #[test]
+    fn reports_first_differing_line() {
+        let compare = OUTPUT.replace("|    4 |", "|    5 |");
+        let (result, output) = run_with("mismatch", &compare);
+        assert!(result
+            .unwrap_err()
+            .starts_with("Comparison failure at line 3"));
+        // The output file is still written so the difference can be inspected
+        assert_eq!(output, OUTPUT);
+    }
+
+    #[test]
+    fn parses_columns() {
+        assert_eq!(
+            parse_column("RAM[0]%D2.6.2"),
+            Ok(Column {
+                variable: "RAM[0]".to_string(),
+                format: 'D',
+                pad_left: 2,
+                length: 6,
+                pad_right: 2,
+            })
+        );
+        assert_eq!(parse_column("A%B1.16.1").unwrap().format, 'B');
+        assert_eq!(parse_column("PC").unwrap().length, 6);
+        assert!(parse_column("A%Q1.2.3").is_err());
+        assert!(parse_column("A%D1.2").is_err());
+    }
+
+    #[test]
+    fn parses_while_condition() {
+        let commands = parse(&tokenize("while RAM[1] <> 6 { ticktock; }").unwrap()).unwrap();
+        assert_eq!(
+            commands,
+            vec![Command::While(
+                "RAM[1]".to_string(),
+                "<>".to_string(),
+                "6".to_string(),
+                vec![Command::TickTock],
+            )]
+        );
+    }
+
+    #[test]
+    fn tokenizes_comments_and_strings() {
+        let tokens = tokenize("/* setup */ echo \"a, b\"; // done\nrepeat 2 {tick}").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Word("echo".to_string()),
+                Token::Text("a, b".to_string()),
+                Token::Symbol(';'),
+                Token::Word("repeat".to_string()),
+                Token::Word("2".to_string()),
+                Token::Symbol('{'),
+                Token::Word("tick".to_string()),
+                Token::Symbol('}'),
+            ]
+        );
+        assert!(tokenize("/* open").is_err());
+    }
+}
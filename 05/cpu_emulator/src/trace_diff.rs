@@ -0,0 +1,229 @@
+use crate::flame::{CallStack, Transfer};
+use crate::history::word_address;
+use crate::keyboard::KeyScript;
+use crate::{HackMachine, RunResult, Stop, Symbols};
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// What two runs are lined up by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Align {
+    /// Watched memory writes alone, so the runs can take different paths between them
+    Writes,
+    /// Calls and returns of VM functions as well as watched writes
+    Functions,
+}
+
+impl FromStr for Align {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        match text {
+            "writes" => Ok(Align::Writes),
+            "functions" => Ok(Align::Functions),
+            _ => Err(format!(
+                "Unknown alignment {text}, expected writes or functions"
+            )),
+        }
+    }
+}
+
+/// Something one run did that the other has to do too.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A watched RAM or screen word changed
+    Write {
+        address: u16,
+        value: u16,
+    },
+    Call(String),
+    Return(String),
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Write { address, value } => {
+                write!(f, "RAM[{address}] = {}", value.cast_signed())
+            }
+            Event::Call(function) => write!(f, "call {function}"),
+            Event::Return(function) => write!(f, "return from {function}"),
+        }
+    }
+}
+
+/// An event and where its run was when it happened.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Observed {
+    pub event: Event,
+    /// The instruction that wrote, or the first one of the function called or returned to
+    pub pc: u16,
+    pub cycle: u64,
+    /// The VM functions active, outermost first
+    pub stack: Vec<String>,
+}
+
+/// How two runs compared.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Both ended after the same number of events, all alike
+    Same(usize),
+    Diverged(Box<Divergence>),
+}
+
+/// Where two runs first part ways.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    /// The number of the first event that differs
+    pub index: usize,
+    /// The last events the runs agreed on
+    pub agreed: Option<(Observed, Observed)>,
+    /// Each run's event, `None` for a run that ended before it
+    pub left: Option<Observed>,
+    pub right: Option<Observed>,
+}
+
+/// A program running for a comparison, reporting its events one at a time.
+///
+/// Only writes to the watched addresses that change memory count, since those are what other code
+/// can see; stores of the value already there and the stack traffic an optimization removes are
+/// left out by watching the right ranges. Writes at and above the keyboard land on the screen and
+/// are reported at the screen address. Memory-mapped devices aren't watched.
+///
+/// Calls and returns are followed with a `CallStack`, so they need the labels of code from the VM
+/// translator.
+pub struct Run {
+    machine: HackMachine,
+    keys: KeyScript,
+    limit: u64,
+    watch: Vec<RangeInclusive<u16>>,
+    align: Align,
+    calls: CallStack,
+    /// Events seen but not handed out yet, for steps that both call and write
+    pending: VecDeque<Observed>,
+    cycles: u64,
+    end: Option<RunResult>,
+}
+
+impl Run {
+    #[must_use]
+    pub fn new(
+        machine: HackMachine,
+        symbols: &Symbols,
+        keys: KeyScript,
+        limit: u64,
+        watch: Vec<RangeInclusive<u16>>,
+        align: Align,
+    ) -> Self {
+        Run {
+            machine,
+            keys,
+            limit,
+            watch,
+            align,
+            calls: CallStack::new(symbols),
+            pending: VecDeque::new(),
+            cycles: 0,
+            end: None,
+        }
+    }
+
+    /// Runs to the next event, or `None` once the program has halted or used up its cycles.
+    pub fn next_event(&mut self) -> Option<Observed> {
+        while self.pending.is_empty() {
+            if self.end.is_some() {
+                return None;
+            }
+            let stop = if self.machine.halted() {
+                Some(Stop::Halted)
+            } else if self.cycles == self.limit {
+                Some(Stop::CycleLimit)
+            } else {
+                None
+            };
+            if let Some(stop) = stop {
+                self.end = Some(RunResult {
+                    cycles: self.cycles,
+                    stop,
+                });
+                return None;
+            }
+            self.step();
+        }
+        self.pending.pop_front()
+    }
+
+    fn step(&mut self) {
+        let machine = &mut self.machine;
+        self.keys.apply(machine);
+        let (pc, cycle) = (machine.pc, machine.cycles);
+        let transfer = self.calls.before_step(machine);
+        if let (Some(transfer), Align::Functions) = (transfer, self.align) {
+            let event = match transfer {
+                Transfer::Call(function) => Event::Call(self.calls.name(function).to_string()),
+                Transfer::Return(function) => Event::Return(self.calls.name(function).to_string()),
+            };
+            self.pending.push_back(Observed {
+                event,
+                pc,
+                cycle,
+                stack: self.calls.active().map(str::to_string).collect(),
+            });
+        }
+
+        let instruction = machine.fetch(pc);
+        let address = word_address(machine.a);
+        let writes = instruction & 0x8000 != 0
+            && instruction & 0b00_1000 != 0
+            && self.watch.iter().any(|range| range.contains(&address));
+        let old = writes.then(|| machine.read(address));
+        machine.step();
+        self.cycles += 1;
+
+        let value = machine.read(address);
+        if old.is_some_and(|old| old != value) {
+            self.pending.push_back(Observed {
+                event: Event::Write { address, value },
+                pc,
+                cycle,
+                stack: self.calls.active().map(str::to_string).collect(),
+            });
+        }
+    }
+
+    /// How the run ended, once `next_event` has returned `None`.
+    #[must_use]
+    pub fn end(&self) -> Option<RunResult> {
+        self.end
+    }
+
+    #[must_use]
+    pub fn machine(&self) -> &HackMachine {
+        &self.machine
+    }
+}
+
+/// Runs both programs event by event until their events differ or both end.
+pub fn compare(left: &mut Run, right: &mut Run) -> Outcome {
+    let mut agreed = None;
+    let mut index = 0;
+    loop {
+        match (left.next_event(), right.next_event()) {
+            (None, None) => return Outcome::Same(index),
+            (Some(left), Some(right)) if left.event == right.event => {
+                agreed = Some((left, right));
+            }
+            (left, right) => {
+                return Outcome::Diverged(Box::new(Divergence {
+                    index,
+                    agreed,
+                    left,
+                    right,
+                }))
+            }
+        }
+        index += 1;
+    }
+}
@@ -0,0 +1,300 @@
+use crate::{
+    allocate_variables, compile_command, parse_command, predefined_symbols, CommandType,
+    CommandValue,
+};
+use std::collections::HashMap;
+
+/// Memory-mapped register holding the bank that appears in the ROM window, just past the keyboard
+pub const BANK_SELECT: u16 = 0x6001;
+/// ROM addresses from here up are served out of the selected bank
+pub const WINDOW: u16 = 0x4000;
+pub const BANK_SIZE: usize = 0x4000;
+/// Symbol for `BANK_SELECT` in banked programs, with a name no ordinary variable is given
+pub const BANK_SYMBOL: &str = "__BANK";
+
+const JUMP_ALWAYS: u16 = 0b111;
+/// Variable trampolines keep D in while they load the bank number through it
+const SAVED_D: &str = "__TRAMPOLINE_D";
+
+/// A program spread over the fixed ROM region and as many switchable banks as it needs.
+pub struct Layout {
+    /// ROM as stored: the fixed region padded out to the window, followed by each bank in turn
+    pub image: Vec<u16>,
+    /// Every symbol's value, with labels at the address the CPU sees them
+    pub symbols: HashMap<String, u16>,
+    /// The bank each label lives in, for labels outside the fixed region
+    pub banks: HashMap<String, u16>,
+    fixed_words: usize,
+    trampoline_words: usize,
+    trampolines: usize,
+    bank_words: Vec<usize>,
+}
+
+impl Layout {
+    pub fn print(&self) {
+        println!(
+            "fixed: {} of {WINDOW} words ({} in {} trampolines)",
+            self.fixed_words, self.trampoline_words, self.trampolines
+        );
+        for (bank, words) in self.bank_words.iter().enumerate() {
+            println!("bank {bank}: {words} of {BANK_SIZE} words");
+        }
+    }
+}
+
+/// Assembles a program for the banked ROM model.
+///
+/// Code is cut into units at labels that can only be reached by jumping, i.e. ones that follow an
+/// unconditional jump, and units are packed into banks in order. The first unit holds the entry
+/// point and stays in the fixed region together with a trampoline for each label that needs one.
+/// A trampoline writes the label's bank to `__BANK` and jumps to it, leaving D and the rest of RAM
+/// alone apart from the variable it saves D in.
+///
+/// A reference to a banked label goes through its trampoline unless it's an `@LABEL` immediately
+/// followed by a jump from inside the same bank. Label addresses that are stored, like the VM
+/// translator's return addresses, therefore always switch back to the right bank when jumped to.
+/// Jumps to raw ROM addresses are left as they are.
+pub fn assemble(commands: &[CommandType]) -> Layout {
+    let (regions, bank_words) = pack(split_units(commands));
+    let bank_of = |region: usize| u16::try_from(region.checked_sub(1)?).ok();
+
+    let mut symbols = predefined_symbols();
+    symbols.insert(BANK_SYMBOL.to_string(), BANK_SELECT);
+    let mut banks = HashMap::new();
+    for (region, commands) in regions.iter().enumerate() {
+        let mut address = if region == 0 { 0 } else { WINDOW };
+        for command in commands {
+            match command {
+                CommandType::CommandL(CommandValue::Symbol(label)) => {
+                    assert!(
+                        symbols.insert(label.clone(), address).is_none(),
+                        "Label already exists: {label}"
+                    );
+                    if let Some(bank) = bank_of(region) {
+                        banks.insert(label.clone(), bank);
+                    }
+                }
+                CommandType::CommandL(CommandValue::Number(_)) => {}
+                _ => address += 1,
+            }
+        }
+    }
+
+    // Trampolines go straight after the first unit, in order of first use
+    let mut trampolines: HashMap<String, u16> = HashMap::new();
+    let mut trampoline_code = Vec::new();
+    let fixed_code = words(&regions[0]);
+    for (region, commands) in regions.iter().enumerate() {
+        for (i, command) in commands.iter().enumerate() {
+            let CommandType::CommandA(CommandValue::Symbol(label)) = command else {
+                continue;
+            };
+            let Some(&bank) = banks.get(label) else {
+                continue;
+            };
+            if (jumps_directly(commands, i) && bank_of(region) == Some(bank))
+                || trampolines.contains_key(label)
+            {
+                continue;
+            }
+
+            let address = u16::try_from(fixed_code + trampoline_code.len()).expect("Too much code");
+            trampolines.insert(label.clone(), address);
+            trampoline_code.extend(trampoline(symbols[label], bank));
+        }
+    }
+    let fixed_words = fixed_code + trampoline_code.len();
+    assert!(
+        fixed_words <= usize::from(WINDOW),
+        "The fixed ROM region needs {fixed_words} words, {} of them trampolines",
+        trampoline_code.len()
+    );
+
+    let mut program: Vec<CommandType> = Vec::new();
+    let last = regions.len() - 1;
+    for (region, commands) in regions.iter().enumerate() {
+        let start = program.len();
+        for (i, command) in commands.iter().enumerate() {
+            program.push(match command {
+                CommandType::CommandL(_) => continue,
+                CommandType::CommandA(CommandValue::Symbol(label)) if banks.contains_key(label) => {
+                    let address = if jumps_directly(commands, i)
+                        && bank_of(region) == banks.get(label).copied()
+                    {
+                        symbols[label]
+                    } else {
+                        trampolines[label]
+                    };
+                    CommandType::CommandA(CommandValue::Number(address))
+                }
+                _ => command.clone(),
+            });
+        }
+        if region == 0 {
+            program.append(&mut trampoline_code);
+        }
+        if region != last {
+            let size = if region == 0 {
+                usize::from(WINDOW)
+            } else {
+                BANK_SIZE
+            };
+            program.resize(start + size, CommandType::CommandA(CommandValue::Number(0)));
+        }
+    }
+    allocate_variables(&mut program, &mut symbols);
+
+    Layout {
+        image: program.iter().map(compile_command).collect(),
+        symbols,
+        banks,
+        fixed_words,
+        trampoline_words: fixed_words - fixed_code,
+        trampolines: trampolines.len(),
+        bank_words,
+    }
+}
+
+/// Puts the first unit in the fixed region and fills banks with the rest in order, returning the
+/// regions' code along with how many words each bank uses.
+fn pack(units: Vec<Vec<CommandType>>) -> (Vec<Vec<CommandType>>, Vec<usize>) {
+    let mut units = units.into_iter();
+    let mut regions: Vec<Vec<CommandType>> = vec![units.next().unwrap()];
+    let mut bank_words: Vec<usize> = Vec::new();
+    for unit in units {
+        let size = words(&unit);
+        assert!(
+            size <= BANK_SIZE,
+            "{} words starting at {} don't fit in a bank",
+            size,
+            unit[0]
+        );
+        if bank_words.last().is_none_or(|used| used + size > BANK_SIZE) {
+            regions.push(Vec::new());
+            bank_words.push(0);
+        }
+        regions.last_mut().unwrap().extend(unit);
+        *bank_words.last_mut().unwrap() += size;
+    }
+    (regions, bank_words)
+}
+
+fn words(commands: &[CommandType]) -> usize {
+    commands
+        .iter()
+        .filter(|command| !matches!(command, CommandType::CommandL(_)))
+        .count()
+}
+
+/// Cuts the program before every run of labels that follows an unconditional jump, so no unit
+/// falls through into the next one.
+fn split_units(commands: &[CommandType]) -> Vec<Vec<CommandType>> {
+    let mut units = vec![Vec::new()];
+    let mut after_jump = false;
+    for command in commands {
+        match command {
+            CommandType::CommandL(_) => {
+                if after_jump {
+                    units.push(Vec::new());
+                    after_jump = false;
+                }
+            }
+            CommandType::CommandC { jump_condition, .. } => {
+                after_jump = *jump_condition == JUMP_ALWAYS;
+            }
+            CommandType::CommandA(_) => after_jump = false,
+        }
+        units.last_mut().unwrap().push(command.clone());
+    }
+    units
+}
+
+/// Whether the A-instruction at `i` only serves as the target of the jump right after it.
+fn jumps_directly(commands: &[CommandType], i: usize) -> bool {
+    matches!(
+        commands.get(i + 1),
+        Some(CommandType::CommandC {
+            destination_a: false,
+            jump_condition: 1..,
+            ..
+        })
+    )
+}
+
+/// Loads the bank number through D, which is saved and put back so the target sees the D the jump
+/// was made with. Every trampoline is the same size whatever the bank.
+fn trampoline(address: u16, bank: u16) -> Vec<CommandType> {
+    [
+        format!("@{SAVED_D}"),
+        "M=D".to_string(),
+        format!("@{bank}"),
+        "D=A".to_string(),
+        format!("@{BANK_SYMBOL}"),
+        "M=D".to_string(),
+        format!("@{SAVED_D}"),
+        "D=M".to_string(),
+        format!("@{address}"),
+        "0;JMP".to_string(),
+    ]
+    .iter()
+    .map(|line| parse_command(line))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    fn parse(source: &str) -> Vec<CommandType> {
+        source.split_whitespace().map(parse_command).collect()
+    }
+
+    /// Code that fills most of a bank without touching anything.
+    fn filler() -> String {
+        "D=D ".repeat(9000)
+    }
+
+    #[test]
+    fn splits_units_after_unconditional_jumps() {
+        let units = split_units(&parse("@X D;JGT (A) @A 0;JMP (B) (C) @R0 M=1 (D) @D 0;JMP"));
+        let starts: Vec<String> = units.iter().map(|unit| unit[0].to_string()).collect();
+        assert_eq!(starts, ["@X", "(B)"]);
+        assert_eq!(units[1].len(), 7);
+    }
+
+    #[test]
+    fn jumps_between_banks_keeping_d() {
+        let source = format!(
+            "@R0 M=0 D=-1 @FIRST 0;JMP \
+             (FIRST) {filler} @R0 M=M+1 @SECOND 0;JMP \
+             (SECOND) {filler} @R0 M=M+1 @THIRD 0;JMP \
+             (THIRD) {filler} @R1 M=D (END) @END 0;JMP",
+            filler = filler()
+        );
+        let layout = assemble(&parse(&source));
+        assert_eq!(layout.bank_words.len(), 3);
+        assert_eq!(layout.banks["THIRD"], 2);
+        assert_eq!(layout.trampolines, 3);
+        assert_eq!(layout.trampoline_words, 30);
+        assert_eq!(layout.symbols[BANK_SYMBOL], BANK_SELECT);
+
+        let mut cpu = Cpu::new(layout.image);
+        cpu.banked = true;
+        for _ in 0..100_000 {
+            if cpu.pc == layout.symbols["END"] && cpu.ram[usize::from(BANK_SELECT)] == 2 {
+                break;
+            }
+            cpu.step();
+        }
+        assert_eq!(cpu.pc, layout.symbols["END"]);
+        assert_eq!(cpu.ram[usize::from(BANK_SELECT)], 2);
+        assert_eq!((cpu.ram[0], cpu.ram[1]), (2, 0xFFFF));
+    }
+
+    #[test]
+    fn keeps_programs_own_bank_variable() {
+        let layout = assemble(&parse("@BANK M=1 (END) @END 0;JMP"));
+        assert_eq!(layout.symbols["BANK"], 16);
+    }
+}
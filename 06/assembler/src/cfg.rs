@@ -0,0 +1,290 @@
+use crate::{cpu, isa, CommandType, CommandValue};
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+const JUMP_ALWAYS: u16 = 0b111;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    Jump,
+    Fallthrough,
+}
+
+/// A straight run of instructions that's only entered at the top and only left at the bottom.
+#[derive(Clone, Debug)]
+pub struct BasicBlock {
+    /// ROM address of the first instruction
+    pub start: u16,
+    pub labels: Vec<String>,
+    pub commands: Vec<CommandType>,
+    pub successors: Vec<(usize, EdgeKind)>,
+    /// Ends with a jump whose target depends on A at runtime, like the VM translator's `@R14 A=M 0;JMP`
+    pub indirect_jump: bool,
+    /// Ends with a jump to the end of the program, such as to a label after the last instruction
+    pub exit: bool,
+}
+
+/// Splits a parsed program into basic blocks at labels, jump targets and jump instructions.
+///
+/// A jump's target is constant when the last write to A inside its block is an A-instruction
+/// holding a label or ROM address. Anything else, including A arriving from another block, is
+/// treated as a computed jump.
+pub fn build(commands: &[CommandType]) -> Vec<BasicBlock> {
+    let mut instructions: Vec<CommandType> = Vec::new();
+    let mut labels: HashMap<u16, Vec<String>> = HashMap::new();
+    let mut label_addresses: HashMap<String, u16> = HashMap::new();
+    for command in commands {
+        match command {
+            CommandType::CommandL(CommandValue::Symbol(label)) => {
+                let address = u16::try_from(instructions.len()).expect("Too much code");
+                labels.entry(address).or_default().push(label.clone());
+                label_addresses.insert(label.clone(), address);
+            }
+            CommandType::CommandL(CommandValue::Number(_)) => {}
+            _ => instructions.push(command.clone()),
+        }
+    }
+    let length = u16::try_from(instructions.len()).expect("Too much code");
+
+    // Walks back from a jump to the last write to A, giving up at any point control can enter from elsewhere
+    let target = |is_entry: &dyn Fn(u16) -> bool, jump: u16| -> Option<u16> {
+        if is_entry(jump) {
+            return None;
+        }
+        for address in (0..jump).rev() {
+            match &instructions[usize::from(address)] {
+                CommandType::CommandA(CommandValue::Symbol(symbol)) => {
+                    return label_addresses.get(symbol).copied();
+                }
+                CommandType::CommandA(CommandValue::Number(address)) => {
+                    return (*address <= length).then_some(*address);
+                }
+                CommandType::CommandC {
+                    destination_a: true,
+                    ..
+                } => return None,
+                _ => {}
+            }
+            if is_entry(address) {
+                break;
+            }
+        }
+        None
+    };
+
+    let jumps: Vec<u16> = (0..)
+        .zip(&instructions)
+        .filter(|(_, instruction)| {
+            matches!(
+                instruction,
+                CommandType::CommandC {
+                    jump_condition: 1..,
+                    ..
+                }
+            )
+        })
+        .map(|(address, _)| address)
+        .collect();
+
+    // Jumps to raw ROM addresses can land in the middle of labelled code, so those become entry
+    // points too before the block boundaries are settled
+    let mut entries: BTreeSet<u16> = labels.keys().copied().collect();
+    for &jump in &jumps {
+        if let Some(address) = target(&|address| labels.contains_key(&address), jump) {
+            entries.insert(address);
+        }
+    }
+    entries.retain(|address| *address < length);
+
+    let mut leaders = entries.clone();
+    leaders.insert(0);
+    leaders.extend(jumps.iter().map(|jump| jump + 1));
+    leaders.retain(|address| *address < length);
+
+    let starts: Vec<u16> = leaders.into_iter().collect();
+    let block_at: HashMap<u16, usize> = starts
+        .iter()
+        .enumerate()
+        .map(|(i, start)| (*start, i))
+        .collect();
+
+    let mut blocks = Vec::with_capacity(starts.len());
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(length);
+        let mut block = BasicBlock {
+            start,
+            labels: labels.get(&start).cloned().unwrap_or_default(),
+            commands: instructions[usize::from(start)..usize::from(end)].to_vec(),
+            successors: Vec::new(),
+            indirect_jump: false,
+            exit: false,
+        };
+
+        let (always, never) = jump_outcome(block.commands.last());
+        if !never {
+            let address = target(&|address| entries.contains(&address), end - 1);
+            match address.map(|address| (address, block_at.get(&address))) {
+                Some((_, Some(&successor))) => {
+                    block.successors.push((successor, EdgeKind::Jump));
+                }
+                Some((address, None)) if address == length => block.exit = true,
+                _ => block.indirect_jump = true,
+            }
+        }
+        if !always && i + 1 < starts.len() {
+            block.successors.push((i + 1, EdgeKind::Fallthrough));
+        }
+
+        blocks.push(block);
+    }
+
+    blocks
+}
+
+/// Whether the instruction ending a block always jumps and whether it never does.
+fn jump_outcome(last: Option<&CommandType>) -> (bool, bool) {
+    match last {
+        Some(&CommandType::CommandC {
+            operation,
+            jump_condition: jump_condition @ 1..,
+            ..
+        }) => {
+            if jump_condition == JUMP_ALWAYS {
+                (true, false)
+            } else if operation & (isa::EXTENDED | 0b10_1000) == 0b10_1000 {
+                // Both ALU inputs are zeroed, so the outcome is known ahead of time
+                let taken = cpu::jumps(jump_condition, cpu::compute(operation, 0, 0, 0));
+                (taken, !taken)
+            } else {
+                (false, false)
+            }
+        }
+        _ => (false, true),
+    }
+}
+
+pub fn to_dot(name: &str, blocks: &[BasicBlock]) -> String {
+    let mut dot = String::new();
+    writeln!(dot, "digraph \"{}\" {{", escape(name)).unwrap();
+    writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+
+    for (i, block) in blocks.iter().enumerate() {
+        let mut label = String::new();
+        for name in &block.labels {
+            write!(label, "({})\\l", escape(name)).unwrap();
+        }
+        for (address, command) in (block.start..).zip(&block.commands) {
+            write!(label, "{address}: {}\\l", escape(&command.to_string())).unwrap();
+        }
+        writeln!(dot, "    block{i} [label=\"{label}\"];").unwrap();
+
+        for (successor, kind) in &block.successors {
+            match kind {
+                EdgeKind::Jump => writeln!(dot, "    block{i} -> block{successor};").unwrap(),
+                EdgeKind::Fallthrough => {
+                    writeln!(dot, "    block{i} -> block{successor} [style=dashed];").unwrap();
+                }
+            }
+        }
+        if block.indirect_jump {
+            writeln!(dot, "    block{i} -> indirect [style=dotted];").unwrap();
+        }
+        if block.exit {
+            writeln!(dot, "    block{i} -> exit;").unwrap();
+        }
+    }
+
+    if blocks.iter().any(|block| block.indirect_jump) {
+        writeln!(
+            dot,
+            "    indirect [label=\"computed jump\", shape=diamond];"
+        )
+        .unwrap();
+    }
+    if blocks.iter().any(|block| block.exit) {
+        writeln!(dot, "    exit [label=\"end\", shape=oval];").unwrap();
+    }
+    writeln!(dot, "}}").unwrap();
+    dot
+}
+
+pub fn to_json(blocks: &[BasicBlock]) -> String {
+    let blocks: Vec<serde_json::Value> = blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| {
+            json!({
+                "id": i,
+                "start": block.start,
+                "end": usize::from(block.start) + block.commands.len(),
+                "labels": block.labels,
+                "instructions": block.commands.iter().map(ToString::to_string).collect::<Vec<_>>(),
+                "successors": block.successors.iter().map(|(successor, kind)| json!({
+                    "block": successor,
+                    "kind": match kind {
+                        EdgeKind::Jump => "jump",
+                        EdgeKind::Fallthrough => "fallthrough",
+                    },
+                })).collect::<Vec<_>>(),
+                "indirect_jump": block.indirect_jump,
+                "exit": block.exit,
+            })
+        })
+        .collect();
+
+    serde_json::to_string_pretty(&json!({ "blocks": blocks })).unwrap()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_command;
+
+    fn build_source(source: &str) -> Vec<BasicBlock> {
+        let commands: Vec<CommandType> = source.split_whitespace().map(parse_command).collect();
+        build(&commands)
+    }
+
+    #[test]
+    fn loops_back_to_the_label() {
+        let blocks = build_source("@R0 M=0 (LOOP) @R0 M=M+1 @LOOP 0;JMP");
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].labels, ["LOOP"]);
+        assert_eq!(blocks[0].successors, [(1, EdgeKind::Fallthrough)]);
+        assert_eq!(blocks[1].successors, [(1, EdgeKind::Jump)]);
+        assert!(!blocks[1].indirect_jump && !blocks[1].exit);
+    }
+
+    #[test]
+    fn branches_both_ways_on_a_condition() {
+        let blocks = build_source("@R0 D=M @SKIP D;JEQ @R1 M=D (SKIP) @SKIP 0;JMP");
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(
+            blocks[0].successors,
+            [(2, EdgeKind::Jump), (1, EdgeKind::Fallthrough)]
+        );
+        assert_eq!(blocks[1].successors, [(2, EdgeKind::Fallthrough)]);
+        assert_eq!(blocks[2].successors, [(2, EdgeKind::Jump)]);
+    }
+
+    #[test]
+    fn jumps_to_a_label_at_the_end_exit() {
+        let blocks = build_source("@R0 D=M @END D;JEQ @R1 M=D (END)");
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks[0].exit && !blocks[0].indirect_jump);
+        assert_eq!(blocks[0].successors, [(1, EdgeKind::Fallthrough)]);
+        assert!(!blocks[1].exit);
+    }
+
+    #[test]
+    fn jumps_through_a_register_are_indirect() {
+        let blocks = build_source("@R14 A=M 0;JMP");
+        assert!(blocks[0].indirect_jump && !blocks[0].exit);
+        assert!(blocks[0].successors.is_empty());
+    }
+}
@@ -0,0 +1,103 @@
+use crate::banking::{BANK_SELECT, BANK_SIZE, WINDOW};
+use crate::isa;
+
+/// Output of the Hack ALU for a C-instruction's a-bit and six control bits (see 02/ALU.hdl).
+pub fn compute(operation: u16, a: u16, d: u16, m: u16) -> u16 {
+    if operation & isa::EXTENDED != 0 {
+        let extension = isa::extension().expect("Extension operation without an ISA extension");
+        return (extension.compute)(operation & !isa::EXTENDED, a, d, m);
+    }
+
+    let mut x_in = d;
+    let mut y_in = if operation & 0b100_0000 == 0 { a } else { m };
+
+    if operation & 0b10_0000 != 0 {
+        x_in = 0;
+    }
+    if operation & 0b01_0000 != 0 {
+        x_in = !x_in;
+    }
+    if operation & 0b00_1000 != 0 {
+        y_in = 0;
+    }
+    if operation & 0b00_0100 != 0 {
+        y_in = !y_in;
+    }
+
+    let out = if operation & 0b10 == 0 {
+        x_in & y_in
+    } else {
+        x_in.wrapping_add(y_in)
+    };
+    if operation & 0b1 == 0 {
+        out
+    } else {
+        !out
+    }
+}
+
+/// Whether a C-instruction's jump bits fire for the given ALU output.
+pub fn jumps(jump_condition: u16, out: u16) -> bool {
+    let negative = out & 0x8000 != 0;
+    let zero = out == 0;
+    (jump_condition & 0b100 != 0 && negative)
+        || (jump_condition & 0b010 != 0 && zero)
+        || (jump_condition & 0b001 != 0 && !negative && !zero)
+}
+
+/// A Hack computer with the whole 32K address space as plain RAM, enough to run routines under test.
+pub struct Cpu {
+    pub a: u16,
+    pub d: u16,
+    pub pc: u16,
+    pub ram: Vec<u16>,
+    /// Serves ROM addresses from the window up out of the bank selected in RAM, for `--banked` images
+    pub banked: bool,
+    rom: Vec<u16>,
+}
+
+impl Cpu {
+    pub fn new(rom: Vec<u16>) -> Self {
+        Cpu {
+            a: 0,
+            d: 0,
+            pc: 0,
+            ram: vec![0; 0x8000],
+            banked: false,
+            rom,
+        }
+    }
+
+    pub fn step(&mut self) {
+        let mut location = usize::from(self.pc);
+        if self.banked && self.pc >= WINDOW {
+            location += usize::from(self.ram[usize::from(BANK_SELECT)]) * BANK_SIZE;
+        }
+        let instruction = self.rom.get(location).copied().unwrap_or(0);
+        if instruction & 0x8000 == 0 {
+            self.a = instruction;
+            self.pc = (self.pc + 1) & 0x7FFF;
+            return;
+        }
+
+        let address = usize::from(self.a & 0x7FFF);
+        let out = compute(isa::decode(instruction), self.a, self.d, self.ram[address]);
+        let jump_target = self.a & 0x7FFF;
+
+        if instruction & 0b00_1000 != 0 {
+            self.ram[address] = out;
+        }
+        if instruction & 0b10_0000 != 0 {
+            self.a = out;
+        }
+        if instruction & 0b01_0000 != 0 {
+            self.d = out;
+        }
+
+        self.pc = if jumps(instruction & 0b111, out) {
+            jump_target
+        } else {
+            (self.pc + 1) & 0x7FFF
+        };
+    }
+}
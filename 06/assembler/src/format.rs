@@ -0,0 +1,104 @@
+use crate::{inline_test, parse_command, CommandType};
+use std::fmt::Write;
+
+const INDENT: &str = "    ";
+
+enum Line {
+    Blank,
+    /// A comment on a line of its own and whether it sat indented in the original
+    Comment(String, bool),
+    Code(String, Option<String>),
+}
+
+/// Re-emits a program with labels flush left, instructions indented, every instruction in its
+/// canonical spelling and trailing comments aligned within each paragraph.
+///
+/// Formatting already formatted source returns it unchanged.
+pub fn format(source: &str) -> String {
+    let mut lines: Vec<Line> = Vec::new();
+    let mut in_test = false;
+    for line in source.lines() {
+        let (code, comment) = match line.split_once("//") {
+            Some((code, comment)) => (code.trim(), Some(comment.trim_end().to_string())),
+            None => (line.trim(), None),
+        };
+
+        lines.push(match (code.is_empty(), comment) {
+            (true, None) => Line::Blank,
+            (true, Some(comment)) => Line::Comment(comment, line.starts_with(char::is_whitespace)),
+            (false, comment) => {
+                // Inline test blocks aren't assembly, so they only get their indentation fixed
+                let code = if inline_test::test_directive(code).is_some() {
+                    in_test = true;
+                    code.to_string()
+                } else if code == ".end" {
+                    in_test = false;
+                    code.to_string()
+                } else if in_test {
+                    format!("{INDENT}{code}")
+                } else {
+                    canonical(code)
+                };
+                Line::Code(code, comment)
+            }
+        });
+    }
+
+    let mut formatted = String::new();
+    let mut paragraph: Vec<&Line> = Vec::new();
+    let mut blank = false;
+    for line in lines.iter().chain([&Line::Blank]) {
+        if let Line::Blank = line {
+            if !paragraph.is_empty() {
+                if blank {
+                    formatted.push('\n');
+                }
+                format_paragraph(&paragraph, &mut formatted);
+                paragraph.clear();
+            }
+            blank = !formatted.is_empty();
+        } else {
+            paragraph.push(line);
+        }
+    }
+
+    // Keep Windows line endings where the original used them
+    if source.contains("\r\n") {
+        formatted = formatted.replace('\n', "\r\n");
+    }
+    formatted
+}
+
+fn format_paragraph(paragraph: &[&Line], formatted: &mut String) {
+    let width = paragraph
+        .iter()
+        .filter_map(|line| match line {
+            Line::Code(code, Some(_)) => Some(code.len()),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    for line in paragraph {
+        match line {
+            Line::Blank => {}
+            Line::Comment(comment, indented) => {
+                if *indented {
+                    formatted.push_str(INDENT);
+                }
+                writeln!(formatted, "//{comment}").unwrap();
+            }
+            Line::Code(code, None) => writeln!(formatted, "{code}").unwrap(),
+            Line::Code(code, Some(comment)) => {
+                writeln!(formatted, "{code:width$} //{comment}").unwrap();
+            }
+        }
+    }
+}
+
+fn canonical(code: &str) -> String {
+    match parse_command(code) {
+        command @ CommandType::CommandL(_) => command.to_string(),
+        command => format!("{INDENT}{command}"),
+    }
+}
@@ -0,0 +1,335 @@
+use crate::{decode_command, isa, CommandType, CommandValue};
+use std::fmt::Write;
+
+const JUMP_ALWAYS: u16 = 0b111;
+
+/// Runtime shared by every translated program: memory, the ALU fallback for computations that
+/// aren't in the instruction table, keyboard input and screen dumps.
+const PRELUDE: &str = r#"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define SCREEN 0x4000
+#define KBD 0x6000
+
+static uint16_t ram[0x8000];
+
+/* Computations outside the documented instruction set, evaluated the way 02/ALU.hdl does */
+static inline uint16_t hack_alu(uint16_t operation, uint16_t a, uint16_t d, uint16_t m) {
+    uint16_t x = d, y = (operation & 0x40) ? m : a, out;
+    if (operation & 0x20) x = 0;
+    if (operation & 0x10) x = ~x;
+    if (operation & 0x08) y = 0;
+    if (operation & 0x04) y = ~y;
+    out = (operation & 0x02) ? (uint16_t)(x + y) : (uint16_t)(x & y);
+    return (operation & 0x01) ? (uint16_t)~out : out;
+}
+
+#ifdef HACK_KEYBOARD_HOOK
+/* Supplied by whoever builds with -DHACK_KEYBOARD_HOOK */
+uint16_t hack_keyboard(uint64_t cycle);
+#else
+/* Replays --keys "cycle:code,cycle:code,...", holding each key code from its cycle on */
+static uint64_t key_cycles[256];
+static uint16_t key_codes[256];
+static int key_count;
+
+static uint16_t hack_keyboard(uint64_t cycle) {
+    uint16_t code = 0;
+    for (int i = 0; i < key_count && key_cycles[i] <= cycle; i++) code = key_codes[i];
+    return code;
+}
+
+static void parse_keys(const char *script) {
+    while (*script && key_count < 256) {
+        char *end;
+        key_cycles[key_count] = strtoull(script, &end, 10);
+        if (*end != ':') break;
+        key_codes[key_count++] = (uint16_t)strtoul(end + 1, &end, 10);
+        script = *end == ',' ? end + 1 : end;
+    }
+}
+#endif
+
+/* Writes the 512x256 screen as a plain PBM image */
+static void dump_screen(const char *path) {
+    FILE *file = fopen(path, "w");
+    if (!file) {
+        perror(path);
+        exit(1);
+    }
+    fprintf(file, "P1\n512 256\n");
+    for (int row = 0; row < 256; row++) {
+        for (int column = 0; column < 512; column++) {
+            uint16_t word = ram[SCREEN + row * 32 + column / 16];
+            fputc((word >> (column % 16)) & 1 ? '1' : '0', file);
+            fputc(column % 32 == 31 ? '\n' : ' ', file);
+        }
+    }
+    fclose(file);
+}
+
+/* How often the keyboard register is refreshed, in cycles */
+#define KEYBOARD_INTERVAL 1024
+"#;
+
+/// Turns a loaded `.hack` program into a C program that runs it natively.
+///
+/// Every ROM word becomes a `case` of one big switch on the program counter. Instructions fall
+/// through into the next case and taken jumps go back around the dispatch loop, as does running
+/// past the last word. `@N 0;JMP` at address N is the usual halt idiom and ends the run.
+pub fn translate(name: &str, rom: &[u16]) -> String {
+    let mut c = format!("/* {name}, translated from Hack machine code by the assembler */\n");
+    c.push_str(PRELUDE);
+
+    writeln!(c, "\n#define ROM_SIZE {}\n", rom.len()).unwrap();
+    c.push_str(
+        "static uint64_t run(uint64_t limit) {\n    \
+         uint16_t a = 0, d = 0, pc = 0, out, target;\n    \
+         uint64_t cycles = 0;\n\n    \
+         for (;;) {\n        \
+         switch (pc) {\n",
+    );
+
+    for (address, &word) in (0..).zip(rom) {
+        let command = decode_command(word);
+        writeln!(c, "        case {address}: /* {command} */").unwrap();
+        c.push_str("            if (cycles == limit) return cycles;\n");
+        c.push_str("            if (cycles++ % KEYBOARD_INTERVAL == 0) ram[KBD] = hack_keyboard(cycles);\n");
+
+        match command {
+            CommandType::CommandA(CommandValue::Number(value)) => {
+                let halts = value == address
+                    && rom.get(usize::from(address) + 1).is_some_and(|&next| {
+                        matches!(
+                            decode_command(next),
+                            CommandType::CommandC {
+                                destination_a: false,
+                                destination_m: false,
+                                destination_d: false,
+                                jump_condition: JUMP_ALWAYS,
+                                ..
+                            }
+                        )
+                    });
+                if halts {
+                    c.push_str("            return cycles;\n");
+                } else {
+                    writeln!(c, "            a = {value};").unwrap();
+                }
+            }
+            CommandType::CommandC {
+                destination_a,
+                destination_m,
+                destination_d,
+                operation,
+                jump_condition,
+            } => {
+                writeln!(
+                    c,
+                    "            out = (uint16_t)({});",
+                    expression(operation)
+                )
+                .unwrap();
+                if jump_condition != 0 {
+                    c.push_str("            target = a & 0x7FFF;\n");
+                }
+                if destination_m {
+                    c.push_str("            ram[a & 0x7FFF] = out;\n");
+                }
+                if destination_a {
+                    c.push_str("            a = out;\n");
+                }
+                if destination_d {
+                    c.push_str("            d = out;\n");
+                }
+                match jump_condition {
+                    0 => {}
+                    JUMP_ALWAYS => c.push_str("            pc = target;\n            continue;\n"),
+                    _ => writeln!(
+                        c,
+                        "            if ({}) {{\n                pc = target;\n                continue;\n            }}",
+                        condition(jump_condition)
+                    )
+                    .unwrap(),
+                }
+            }
+            _ => unreachable!(),
+        }
+        if usize::from(address) + 1 < rom.len() {
+            c.push_str("            /* fall through */\n");
+        }
+    }
+
+    // Falling off the end of ROM executes empty words, which are all `@0`. The cases leave `pc` at
+    // wherever the loop last dispatched to, so the last one sets it before going round again
+    c.push_str(
+        "            pc = ROM_SIZE & 0x7FFF;\n            \
+         continue;\n        \
+         default:\n            \
+         if (cycles == limit) return cycles;\n            \
+         cycles++;\n            \
+         a = 0;\n            \
+         pc = (pc + 1) & 0x7FFF;\n            \
+         continue;\n        \
+         }\n    \
+         }\n}\n",
+    );
+
+    c.push_str(MAIN);
+    c
+}
+
+/// The computation as a C expression over `a`, `d` and memory, spelled straight from its mnemonic.
+fn expression(operation: u16) -> String {
+    match isa::mnemonic(operation) {
+        Some(mnemonic) if operation & isa::EXTENDED != 0 => {
+            let operand = expression(match &mnemonic[..1] {
+                "A" => 0b011_0000,
+                "D" => 0b000_1100,
+                _ => 0b111_0000,
+            });
+            match &mnemonic[1..] {
+                "<<" => format!("{operand} << 1"),
+                ">>" => format!("(int16_t){operand} >> 1"),
+                _ => panic!("No C translation for {mnemonic}"),
+            }
+        }
+        Some(mnemonic) => mnemonic
+            .chars()
+            .map(|c| match c {
+                'A' => "a".to_string(),
+                'D' => "d".to_string(),
+                'M' => "ram[a & 0x7FFF]".to_string(),
+                '!' => "~".to_string(),
+                c => c.to_string(),
+            })
+            .collect(),
+        None => format!("hack_alu({operation:#04x}, a, d, ram[a & 0x7FFF])"),
+    }
+}
+
+fn condition(jump_condition: u16) -> String {
+    let mut conditions = Vec::new();
+    if jump_condition & 0b100 != 0 {
+        conditions.push("(int16_t)out < 0");
+    }
+    if jump_condition & 0b010 != 0 {
+        conditions.push("out == 0");
+    }
+    if jump_condition & 0b001 != 0 {
+        conditions.push("(int16_t)out > 0");
+    }
+    conditions.join(" || ")
+}
+
+const MAIN: &str = r#"
+static void usage(const char *program) {
+    fprintf(stderr,
+            "usage: %s [--cycles N] [--set ADDRESS=VALUE]... [--keys CYCLE:CODE,...]\n"
+            "          [--screen FILE.pbm] [--dump FIRST-LAST]\n",
+            program);
+    exit(2);
+}
+
+int main(int argc, char **argv) {
+    uint64_t limit = 10000000;
+    const char *screen = NULL;
+    unsigned long first = 0, last = 15;
+
+    for (int i = 1; i < argc; i++) {
+        if (i + 1 >= argc) usage(argv[0]);
+        if (!strcmp(argv[i], "--cycles")) {
+            limit = strtoull(argv[++i], NULL, 10);
+        } else if (!strcmp(argv[i], "--set")) {
+            char *end;
+            unsigned long address = strtoul(argv[++i], &end, 0);
+            if (*end != '=' || address >= 0x8000) usage(argv[0]);
+            ram[address] = (uint16_t)strtol(end + 1, NULL, 0);
+        } else if (!strcmp(argv[i], "--keys")) {
+#ifdef HACK_KEYBOARD_HOOK
+            i++;
+            fprintf(stderr, "--keys is ignored when built with HACK_KEYBOARD_HOOK\n");
+#else
+            parse_keys(argv[++i]);
+#endif
+        } else if (!strcmp(argv[i], "--screen")) {
+            screen = argv[++i];
+        } else if (!strcmp(argv[i], "--dump")) {
+            if (sscanf(argv[++i], "%lu-%lu", &first, &last) != 2 || last >= 0x8000) usage(argv[0]);
+        } else {
+            usage(argv[0]);
+        }
+    }
+
+    uint64_t cycles = run(limit);
+    printf("%llu cycles\n", (unsigned long long)cycles);
+    for (unsigned long address = first; address <= last; address++) {
+        printf("RAM[%lu] = %d\n", address, (int16_t)ram[address]);
+    }
+    if (screen) dump_screen(screen);
+    return 0;
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::translate;
+
+    #[test]
+    fn runs_off_the_end_of_rom_through_the_dispatch_loop() {
+        // @5 D=A @R0 M=D
+        let c = translate("t.hack", &[5, 0xEC10, 0, 0xE308]);
+        let run = &c[c.find("static uint64_t run").unwrap()..c.find("static void usage").unwrap()];
+        assert_eq!(
+            run.trim(),
+            r"static uint64_t run(uint64_t limit) {
+    uint16_t a = 0, d = 0, pc = 0, out, target;
+    uint64_t cycles = 0;
+
+    for (;;) {
+        switch (pc) {
+        case 0: /* @5 */
+            if (cycles == limit) return cycles;
+            if (cycles++ % KEYBOARD_INTERVAL == 0) ram[KBD] = hack_keyboard(cycles);
+            a = 5;
+            /* fall through */
+        case 1: /* D=A */
+            if (cycles == limit) return cycles;
+            if (cycles++ % KEYBOARD_INTERVAL == 0) ram[KBD] = hack_keyboard(cycles);
+            out = (uint16_t)(a);
+            d = out;
+            /* fall through */
+        case 2: /* @0 */
+            if (cycles == limit) return cycles;
+            if (cycles++ % KEYBOARD_INTERVAL == 0) ram[KBD] = hack_keyboard(cycles);
+            a = 0;
+            /* fall through */
+        case 3: /* M=D */
+            if (cycles == limit) return cycles;
+            if (cycles++ % KEYBOARD_INTERVAL == 0) ram[KBD] = hack_keyboard(cycles);
+            out = (uint16_t)(d);
+            ram[a & 0x7FFF] = out;
+            pc = ROM_SIZE & 0x7FFF;
+            continue;
+        default:
+            if (cycles == limit) return cycles;
+            cycles++;
+            a = 0;
+            pc = (pc + 1) & 0x7FFF;
+            continue;
+        }
+    }
+}"
+        );
+    }
+
+    #[test]
+    fn halts_on_the_halt_idiom() {
+        // @0 0;JMP
+        let c = translate("t.hack", &[0, 0xEA87]);
+        assert!(c.contains("        case 0: /* @0 */\n"));
+        assert!(c.contains("            return cycles;\n            /* fall through */\n        case 1: /* 0;JMP */"));
+    }
+}
@@ -0,0 +1,365 @@
+use crate::banking::{self, BANK_SELECT};
+use crate::cpu::Cpu;
+use crate::{compile_command, parse_command, replace_symbols, CommandType, CommandValue};
+use std::collections::HashMap;
+use std::fmt;
+
+const DEFAULT_CYCLE_LIMIT: u64 = 1_000_000;
+const HARNESS_LABEL: &str = "__TEST_HARNESS";
+
+#[derive(Clone, Debug)]
+enum Location {
+    A,
+    D,
+    Address(u16),
+    Symbol(String),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::A => write!(f, "A"),
+            Location::D => write!(f, "D"),
+            Location::Address(address) => write!(f, "RAM[{address}]"),
+            Location::Symbol(symbol) => write!(f, "{symbol}"),
+        }
+    }
+}
+
+/// A test kept next to the routine it exercises:
+///
+/// ```text
+/// .test "mult 3*4"
+///     set R0=3, R1=4
+///     run MULT until END limit 1000
+///     expect R2=12
+/// .end
+/// ```
+///
+/// `set` and `expect` take RAM addresses (`RAM[100]`), symbols or D. Without `run` the test starts
+/// at ROM address 0, without `until` it runs for the whole cycle limit before checking results.
+#[derive(Clone, Debug)]
+pub struct InlineTest {
+    pub name: String,
+    /// Line of the `.test` directive, counting from 1
+    pub line: usize,
+    sets: Vec<(Location, u16)>,
+    start: Option<String>,
+    halt: Option<String>,
+    cycle_limit: u64,
+    expectations: Vec<(Location, u16)>,
+}
+
+/// Pulls the `.test` blocks out of a source file, leaving blank lines in their place so the
+/// remaining code keeps its line numbers.
+pub fn extract(source: &str) -> (String, Vec<InlineTest>) {
+    let mut program = String::with_capacity(source.len());
+    let mut tests = Vec::new();
+    let mut current: Option<InlineTest> = None;
+
+    for (i, line) in source.lines().enumerate() {
+        let code = line.split_once("//").unwrap_or((line, "")).0.trim();
+
+        if let Some(name) = test_directive(code) {
+            assert!(current.is_none(), "Nested .test on line {}", i + 1);
+            current = Some(InlineTest {
+                name: name.trim().trim_matches('"').to_string(),
+                line: i + 1,
+                sets: Vec::new(),
+                start: None,
+                halt: None,
+                cycle_limit: DEFAULT_CYCLE_LIMIT,
+                expectations: Vec::new(),
+            });
+        } else if code == ".end" {
+            match current.take() {
+                Some(test) => tests.push(test),
+                None => panic!(".end without .test on line {}", i + 1),
+            }
+        } else if let Some(test) = &mut current {
+            parse_test_line(test, code);
+        } else {
+            program.push_str(line);
+        }
+        program.push('\n');
+    }
+
+    if let Some(test) = current {
+        panic!("Test \"{}\" is missing its .end", test.name);
+    }
+    (program, tests)
+}
+
+/// The rest of a `.test` line after the directive, or `None` for any other line. Words that merely
+/// start with `.test`, like a `.testing` label, aren't the directive.
+pub fn test_directive(code: &str) -> Option<&str> {
+    code.strip_prefix(".test")
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+}
+
+fn parse_test_line(test: &mut InlineTest, line: &str) {
+    let Some((keyword, rest)) = line.split_once(char::is_whitespace) else {
+        assert!(line.is_empty(), "Invalid test command: {line}");
+        return;
+    };
+
+    match keyword {
+        "set" => test.sets.extend(parse_assignments(rest)),
+        "expect" => test.expectations.extend(parse_assignments(rest)),
+        "run" => {
+            let mut words = rest.split_whitespace();
+            while let Some(word) = words.next() {
+                match word {
+                    "until" => test.halt = words.next().map(ToString::to_string),
+                    "limit" => {
+                        test.cycle_limit = match words.next().map(str::parse) {
+                            Some(Ok(limit)) => limit,
+                            _ => panic!("Invalid cycle limit in: {line}"),
+                        }
+                    }
+                    _ => test.start = Some(word.to_string()),
+                }
+            }
+        }
+        _ => panic!("Unknown test command: {line}"),
+    }
+}
+
+fn parse_assignments(assignments: &str) -> Vec<(Location, u16)> {
+    assignments
+        .split(',')
+        .map(|assignment| {
+            let Some((location, value)) = assignment.split_once('=') else {
+                panic!("Invalid assignment: {assignment}")
+            };
+            let location = location.trim();
+            let value = value.trim();
+            let value = match (value.parse::<u16>(), value.parse::<i16>()) {
+                (Ok(value), _) => value,
+                (_, Ok(value)) => value.cast_unsigned(),
+                _ => panic!("Invalid value in assignment: {assignment}"),
+            };
+
+            let location = match location {
+                "A" => Location::A,
+                "D" => Location::D,
+                _ => match location
+                    .strip_prefix("RAM[")
+                    .and_then(|address| address.strip_suffix(']'))
+                    .unwrap_or(location)
+                    .parse::<u16>()
+                {
+                    Ok(address) => Location::Address(address),
+                    Err(_) => Location::Symbol(location.to_string()),
+                },
+            };
+            (location, value)
+        })
+        .collect()
+}
+
+/// Runs every test against the program and reports each result. Returns whether all of them passed.
+pub fn run_all(commands: &[CommandType], tests: &[InlineTest], banked: bool) -> bool {
+    let mut passed = 0;
+    for test in tests {
+        match run(commands, test, banked) {
+            Ok(cycles) => {
+                println!("PASS {} ({cycles} cycles)", test.name);
+                passed += 1;
+            }
+            Err(why) => println!("FAIL {} (line {}): {why}", test.name, test.line),
+        }
+    }
+
+    println!("{passed} of {} tests passed", tests.len());
+    passed == tests.len()
+}
+
+/// Assembles the program with a harness after it that loads the test's inputs and jumps to the
+/// routine, then runs it from the harness.
+fn run(commands: &[CommandType], test: &InlineTest, banked: bool) -> Result<u64, String> {
+    let mut program = commands.to_vec();
+    program.push(CommandType::CommandL(CommandValue::Symbol(
+        HARNESS_LABEL.to_string(),
+    )));
+    // D is loaded last so the stores to RAM don't clobber it
+    let (registers, memory): (Vec<_>, Vec<_>) = test
+        .sets
+        .iter()
+        .partition(|(location, _)| matches!(location, Location::A | Location::D));
+    for (location, value) in memory.into_iter().chain(registers) {
+        // A-instructions only reach 15 bits, so negative values are loaded inverted
+        let load = if value & 0x8000 == 0 {
+            [format!("@{value}"), "D=A".to_string()]
+        } else {
+            [format!("@{}", !value), "D=!A".to_string()]
+        };
+        let store = match location {
+            Location::A => {
+                return Err("A can't be set since the harness jumps through it".to_string())
+            }
+            Location::D => Vec::new(),
+            Location::Address(address) => vec![format!("@{address}"), "M=D".to_string()],
+            Location::Symbol(symbol) => vec![format!("@{symbol}"), "M=D".to_string()],
+        };
+        program.extend(load.iter().chain(&store).map(|line| parse_command(line)));
+    }
+    program.push(parse_command(&format!(
+        "@{}",
+        test.start.as_deref().unwrap_or("0")
+    )));
+    program.push(parse_command("0;JMP"));
+
+    let (rom, symbols, banks) = if banked {
+        let layout = banking::assemble(&program);
+        (layout.image, layout.symbols, layout.banks)
+    } else {
+        let symbols = replace_symbols(&mut program);
+        let rom = program
+            .iter()
+            .filter(|command| !matches!(command, CommandType::CommandL(_)))
+            .map(compile_command)
+            .collect();
+        (rom, symbols, HashMap::new())
+    };
+    let resolve = |symbol: &str| {
+        symbols
+            .get(symbol)
+            .copied()
+            .ok_or_else(|| format!("unknown symbol {symbol}"))
+    };
+
+    let mut cpu = Cpu::new(rom);
+    cpu.banked = banked;
+    cpu.pc = resolve(HARNESS_LABEL)?;
+    if let Some(&bank) = banks.get(HARNESS_LABEL) {
+        cpu.ram[usize::from(BANK_SELECT)] = bank;
+    }
+    let halt = match test.halt.as_deref() {
+        Some(label) => Some((resolve(label)?, banks.get(label).copied())),
+        None => None,
+    };
+    let halted = |cpu: &Cpu| {
+        halt.is_some_and(|(address, bank)| {
+            cpu.pc == address && bank.is_none_or(|bank| cpu.ram[usize::from(BANK_SELECT)] == bank)
+        })
+    };
+
+    let mut cycles = 0;
+    while !halted(&cpu) {
+        if cycles == test.cycle_limit {
+            if let Some(label) = &test.halt {
+                return Err(format!("didn't reach {label} within {cycles} cycles"));
+            }
+            break;
+        }
+        cpu.step();
+        cycles += 1;
+    }
+
+    let mut failures = Vec::new();
+    for (location, expected) in &test.expectations {
+        let actual = match location {
+            Location::A => cpu.a,
+            Location::D => cpu.d,
+            Location::Address(address) => cpu.ram[usize::from(address & 0x7FFF)],
+            Location::Symbol(symbol) => cpu.ram[usize::from(resolve(symbol)? & 0x7FFF)],
+        };
+        if actual != *expected {
+            failures.push(format!(
+                "{location} = {} (expected {})",
+                actual.cast_signed(),
+                expected.cast_signed()
+            ));
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(cycles)
+    } else {
+        Err(failures.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "\
+(MULT)
+    @R2
+    M=0
+(LOOP)
+    @R0
+    D=M
+    @END
+    D;JEQ
+    @R1
+    D=M
+    @R2
+    M=D+M
+    @R0
+    M=M-1
+    @LOOP
+    0;JMP
+(END)
+    @END
+    0;JMP
+// .testing notes stay comments
+.test \"mult 3*4\"
+    set R0=3, R1=4
+    run MULT until END limit 1000
+    expect R2=12
+.end
+.test wrong
+    set RAM[0]=2, R1=-5
+    run MULT until END
+    expect R2=-10, D=1
+.end
+";
+
+    fn commands(program: &str) -> Vec<CommandType> {
+        program
+            .lines()
+            .map(|line| line.split_once("//").unwrap_or((line, "")).0.trim())
+            .filter(|line| !line.is_empty())
+            .map(parse_command)
+            .collect()
+    }
+
+    #[test]
+    fn extracts_test_blocks() {
+        let (program, tests) = extract(SOURCE);
+        assert_eq!(program.lines().count(), SOURCE.lines().count());
+        assert!(program.contains("// .testing notes stay comments"));
+        assert!(!program.contains("expect"));
+
+        assert_eq!(tests.len(), 2);
+        assert_eq!((tests[0].name.as_str(), tests[0].line), ("mult 3*4", 21));
+        assert_eq!(tests[0].start.as_deref(), Some("MULT"));
+        assert_eq!(tests[0].halt.as_deref(), Some("END"));
+        assert_eq!(tests[0].cycle_limit, 1000);
+        assert_eq!(tests[1].cycle_limit, DEFAULT_CYCLE_LIMIT);
+        assert_eq!(tests[1].sets.len(), 2);
+    }
+
+    #[test]
+    fn leaves_labels_that_start_like_directives() {
+        let (program, tests) = extract(".testing\n.tests x\n");
+        assert!(tests.is_empty());
+        assert_eq!(program, ".testing\n.tests x\n");
+    }
+
+    #[test]
+    fn passes_and_fails_assertions() {
+        let (program, tests) = extract(SOURCE);
+        let commands = commands(&program);
+        for banked in [false, true] {
+            assert!(run(&commands, &tests[0], banked).is_ok());
+            assert_eq!(
+                run(&commands, &tests[1], banked),
+                Err("D = 0 (expected 1)".to_string())
+            );
+        }
+    }
+}
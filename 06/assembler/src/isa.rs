@@ -0,0 +1,113 @@
+use crate::OPERATIONS;
+use std::sync::OnceLock;
+
+/// Set on a command's operation when it comes from the ISA extension rather than the ALU table
+pub const EXTENDED: u16 = 0x80;
+
+/// Extra computations encoded in C-instructions whose spare bits 14 and 13 aren't both set.
+pub struct Extension {
+    pub name: &'static str,
+    /// Bits 15 to 13 of the extension's instructions, in place of the usual 111
+    pub prefix: u16,
+    /// Mnemonics and the seven bits that follow the prefix
+    pub operations: &'static [(&'static str, u16)],
+    /// Output for one of the extension's operations given A, D and M
+    pub compute: fn(operation: u16, a: u16, d: u16, m: u16) -> u16,
+}
+
+/// Extensions that `--isa` can select. Plain `hack` is the standard instruction set.
+pub static EXTENSIONS: [Extension; 1] = [Extension {
+    // The shift instructions understood by the official nand2tetris CPU emulator
+    name: "shift",
+    prefix: 0b101,
+    operations: &[
+        ("A<<", 0b010_0000),
+        ("D<<", 0b011_0000),
+        ("M<<", 0b110_0000),
+        ("A>>", 0b000_0000),
+        ("D>>", 0b001_0000),
+        ("M>>", 0b100_0000),
+    ],
+    compute: shift,
+}];
+
+static SELECTED: OnceLock<&'static Extension> = OnceLock::new();
+
+/// Enables an extension for the rest of the run. `hack` leaves the standard encoding alone.
+pub fn select(name: &str) {
+    if name == "hack" {
+        return;
+    }
+    let Some(extension) = EXTENSIONS.iter().find(|extension| extension.name == name) else {
+        let names: Vec<&str> = EXTENSIONS.iter().map(|extension| extension.name).collect();
+        panic!(
+            "Unknown ISA {name}, expected hack or one of: {}",
+            names.join(", ")
+        )
+    };
+    assert!(
+        SELECTED.set(extension).is_ok(),
+        "An ISA extension is already selected"
+    );
+}
+
+pub fn extension() -> Option<&'static Extension> {
+    SELECTED.get().copied()
+}
+
+/// The operation for an extension mnemonic, if an extension is selected and has it.
+pub fn lookup(mnemonic: &str) -> Option<u16> {
+    extension()?
+        .operations
+        .iter()
+        .find(|(name, _)| *name == mnemonic)
+        .map(|(_, bits)| EXTENDED | bits)
+}
+
+pub fn mnemonic(operation: u16) -> Option<&'static str> {
+    let operations = if operation & EXTENDED == 0 {
+        &OPERATIONS[..]
+    } else {
+        extension()?.operations
+    };
+    operations
+        .iter()
+        .find(|(_, bits)| *bits == operation & !EXTENDED)
+        .map(|(name, _)| *name)
+}
+
+/// Bits 15 to 6 of a C-instruction with the given operation.
+pub fn encode(operation: u16) -> u16 {
+    if operation & EXTENDED == 0 {
+        0xE000 | (operation << 6)
+    } else {
+        let extension = extension().expect("Extension operation without an ISA extension");
+        (extension.prefix << 13) | ((operation & !EXTENDED) << 6)
+    }
+}
+
+/// The operation of a C-instruction word. Words that don't carry the selected extension's prefix
+/// decode as standard instructions, the way the Hack CPU ignores bits 14 and 13.
+pub fn decode(word: u16) -> u16 {
+    let operation = (word >> 6) & 0x7F;
+    match extension() {
+        Some(extension) if word >> 13 == extension.prefix => EXTENDED | operation,
+        _ => operation,
+    }
+}
+
+/// Shifts A, D or M one bit left or, keeping the sign, right.
+fn shift(operation: u16, a: u16, d: u16, m: u16) -> u16 {
+    let operand = if operation & 0b001_0000 != 0 {
+        d
+    } else if operation & 0b100_0000 != 0 {
+        m
+    } else {
+        a
+    };
+    if operation & 0b010_0000 != 0 {
+        operand << 1
+    } else {
+        (operand.cast_signed() >> 1).cast_unsigned()
+    }
+}
@@ -0,0 +1,704 @@
+use crate::{
+    compile_command, inline_test, isa, parse_command, predefined_symbols, replace_symbols,
+    CommandType, JUMPS, OPERATIONS,
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+const ERROR_INVALID_PARAMS: i64 = -32602;
+const ERROR_METHOD_NOT_FOUND: i64 = -32601;
+
+const SEVERITY_ERROR: u8 = 1;
+
+const COMPLETION_KIND_OPERATOR: u8 = 24;
+const COMPLETION_KIND_CONSTANT: u8 = 21;
+const COMPLETION_KIND_VARIABLE: u8 = 6;
+const COMPLETION_KIND_REFERENCE: u8 = 18;
+
+/// A symbol written in the source, either as `@name` or as a `(name)` label.
+#[derive(Clone, Debug)]
+struct Occurrence {
+    name: String,
+    line: usize,
+    /// Columns in UTF-16 code units, as LSP positions count them
+    start: usize,
+    end: usize,
+    is_label: bool,
+}
+
+/// Everything the server knows about one open document, rebuilt on every change.
+#[derive(Default)]
+struct Analysis {
+    diagnostics: Vec<Value>,
+    occurrences: Vec<Occurrence>,
+    /// ROM address and encoded word of the instruction on each line
+    instructions: HashMap<usize, (u16, Option<u16>)>,
+    symbols: HashMap<String, u16>,
+}
+
+impl Analysis {
+    fn new(source: &str) -> Self {
+        let mut analysis = Analysis::default();
+
+        let program = match catch(|| inline_test::extract(source)) {
+            Ok((program, _)) => program,
+            Err(why) => {
+                analysis.diagnostics.push(diagnostic(0, 0, 0, &why));
+                source.to_string()
+            }
+        };
+
+        let mut commands: Vec<(usize, CommandType)> = Vec::new();
+        for (line_number, line) in program.lines().enumerate() {
+            let code = line.split_once("//").unwrap_or((line, "")).0;
+            let start = code.len() - code.trim_start().len();
+            let code = code.trim();
+            if code.is_empty() {
+                continue;
+            }
+
+            match catch(|| parse_command(code)) {
+                Ok(command) => commands.push((line_number, command)),
+                Err(why) => {
+                    let end = utf16_column(line, start + code.len());
+                    let start = utf16_column(line, start);
+                    analysis
+                        .diagnostics
+                        .push(diagnostic(line_number, start, end, &why));
+                    continue;
+                }
+            }
+
+            let (name, offset, is_label) = if let Some(name) = code.strip_prefix('@') {
+                (name, 1, false)
+            } else if let Some(name) = code.strip_prefix('(') {
+                (name.trim_end_matches(')'), 1, true)
+            } else {
+                continue;
+            };
+            if name.parse::<u16>().is_err() {
+                analysis.occurrences.push(Occurrence {
+                    name: name.to_string(),
+                    line: line_number,
+                    start: utf16_column(line, start + offset),
+                    end: utf16_column(line, start + offset + name.len()),
+                    is_label,
+                });
+            }
+        }
+
+        // Tests refer to the program's symbols too, so those count as uses
+        let mut in_test = false;
+        for (line_number, line) in source.lines().enumerate() {
+            let code = line.split_once("//").unwrap_or((line, "")).0;
+            if inline_test::test_directive(code.trim()).is_some() {
+                in_test = true;
+            } else if code.trim() == ".end" {
+                in_test = false;
+            } else if in_test {
+                for (offset, name) in test_symbols(code) {
+                    analysis.occurrences.push(Occurrence {
+                        name: name.to_string(),
+                        line: line_number,
+                        start: utf16_column(line, offset),
+                        end: utf16_column(line, offset + name.len()),
+                        is_label: false,
+                    });
+                }
+            }
+        }
+
+        let mut resolved: Vec<CommandType> = commands
+            .iter()
+            .map(|(_, command)| command.clone())
+            .collect();
+        match catch(AssertUnwindSafe(|| replace_symbols(&mut resolved))) {
+            Ok(symbols) => analysis.symbols = symbols,
+            Err(why) => {
+                // Point duplicate labels at their second definition rather than the top of the file
+                let duplicate = why
+                    .strip_prefix("Label already exists: ")
+                    .and_then(|label| {
+                        analysis
+                            .occurrences
+                            .iter()
+                            .filter(|occurrence| occurrence.is_label && occurrence.name == label)
+                            .nth(1)
+                    });
+                analysis.diagnostics.push(match duplicate {
+                    Some(occurrence) => {
+                        diagnostic(occurrence.line, occurrence.start, occurrence.end, &why)
+                    }
+                    None => diagnostic(0, 0, 0, &why),
+                });
+                resolved.clear();
+            }
+        }
+
+        let mut rom_location = 0;
+        for (i, (line_number, command)) in commands.iter().enumerate() {
+            if matches!(command, CommandType::CommandL(_)) {
+                continue;
+            }
+            let word = resolved.get(i).map(compile_command);
+            analysis
+                .instructions
+                .insert(*line_number, (rom_location, word));
+            rom_location += 1;
+        }
+
+        analysis
+    }
+
+    fn occurrence_at(&self, line: usize, character: usize) -> Option<&Occurrence> {
+        self.occurrences.iter().find(|occurrence| {
+            occurrence.line == line && (occurrence.start..=occurrence.end).contains(&character)
+        })
+    }
+
+    fn is_label(&self, name: &str) -> bool {
+        self.occurrences
+            .iter()
+            .any(|occurrence| occurrence.is_label && occurrence.name == name)
+    }
+}
+
+/// The symbols a line inside a `.test` block names, with their byte offsets into the line.
+fn test_symbols(code: &str) -> Vec<(usize, &str)> {
+    let Some((keyword, rest)) = code.trim_start().split_once(char::is_whitespace) else {
+        return Vec::new();
+    };
+    let names: Vec<&str> = match keyword {
+        "run" => {
+            let mut names = Vec::new();
+            let mut words = rest.split_whitespace();
+            while let Some(word) = words.next() {
+                match word {
+                    "until" => {}
+                    "limit" => {
+                        words.next();
+                    }
+                    _ => names.push(word),
+                }
+            }
+            names
+        }
+        "set" | "expect" => rest
+            .split(',')
+            .filter_map(|assignment| assignment.split_once('='))
+            .map(|(location, _)| location.trim())
+            .filter(|location| !matches!(*location, "A" | "D") && !location.starts_with("RAM["))
+            .collect(),
+        _ => Vec::new(),
+    };
+    names
+        .into_iter()
+        .filter(|name| !name.is_empty() && name.parse::<u16>().is_err())
+        .map(|name| (name.as_ptr() as usize - code.as_ptr() as usize, name))
+        .collect()
+}
+
+/// Runs the parser on input that may make it panic and turns the panic into an error message.
+fn catch<T>(f: impl FnOnce() -> T + panic::UnwindSafe) -> Result<T, String> {
+    panic::catch_unwind(f).map_err(|payload| {
+        payload
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| payload.downcast_ref::<&str>().map(ToString::to_string))
+            .unwrap_or_else(|| "Unknown error".to_string())
+    })
+}
+
+fn diagnostic(line: usize, start: usize, end: usize, message: &str) -> Value {
+    json!({
+        "range": range(line, start, end),
+        "severity": SEVERITY_ERROR,
+        "source": "assembler",
+        "message": message,
+    })
+}
+
+fn range(line: usize, start: usize, end: usize) -> Value {
+    json!({
+        "start": { "line": line, "character": start },
+        "end": { "line": line, "character": end },
+    })
+}
+
+fn location(uri: &str, occurrence: &Occurrence) -> Value {
+    json!({
+        "uri": uri,
+        "range": range(occurrence.line, occurrence.start, occurrence.end),
+    })
+}
+
+struct Server {
+    documents: HashMap<String, String>,
+    analyses: HashMap<String, Analysis>,
+    shutting_down: bool,
+}
+
+/// Serves the Language Server Protocol over stdin and stdout until the client asks it to exit.
+pub fn serve() {
+    // Parse errors are reported as diagnostics, not printed over the protocol stream
+    panic::set_hook(Box::new(|_| {}));
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut server = Server {
+        documents: HashMap::new(),
+        analyses: HashMap::new(),
+        shutting_down: false,
+    };
+
+    while let Some(message) = read_message(&mut input) {
+        let method = message["method"].as_str().unwrap_or_default().to_string();
+        if method == "exit" {
+            std::process::exit(i32::from(!server.shutting_down));
+        }
+
+        let response = server.handle(&method, &message["params"]);
+        if let Some(id) = message.get("id") {
+            send(&match response {
+                Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                Err((code, why)) => json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": { "code": code, "message": why },
+                }),
+            });
+        }
+    }
+}
+
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+fn send(message: &Value) {
+    let body = message.to_string();
+    let mut stdout = io::stdout().lock();
+    write!(stdout, "Content-Length: {}\r\n\r\n{body}", body.len()).unwrap();
+    stdout.flush().unwrap();
+}
+
+impl Server {
+    fn handle(&mut self, method: &str, params: &Value) -> Result<Value, (i64, String)> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let line = as_usize(&params["position"]["line"]);
+        let character = as_usize(&params["position"]["character"]);
+
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": ["@", "=", ";"] },
+                    "renameProvider": true,
+                },
+                "serverInfo": { "name": "assembler" },
+            })),
+            "shutdown" => {
+                self.shutting_down = true;
+                Ok(Value::Null)
+            }
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.update(&uri, text.to_string());
+                Ok(Value::Null)
+            }
+            "textDocument/didChange" => {
+                // Only full document sync is advertised, so the last change holds the whole text
+                if let Some(text) = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str())
+                {
+                    self.update(&uri, text.to_string());
+                }
+                Ok(Value::Null)
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.analyses.remove(&uri);
+                publish_diagnostics(&uri, &[]);
+                Ok(Value::Null)
+            }
+            "textDocument/definition" => Ok(self.definition(&uri, line, character)),
+            "textDocument/references" => {
+                let include_declaration = params["context"]["includeDeclaration"]
+                    .as_bool()
+                    .unwrap_or(true);
+                Ok(self.references(&uri, line, character, include_declaration))
+            }
+            "textDocument/hover" => Ok(self.hover(&uri, line, character)),
+            "textDocument/completion" => Ok(self.completion(&uri, line, character)),
+            "textDocument/rename" => {
+                let new_name = params["newName"].as_str().unwrap_or_default();
+                self.rename(&uri, line, character, new_name)
+            }
+            _ if method.starts_with("$/") || !method.contains('/') => Ok(Value::Null),
+            _ => Err((
+                ERROR_METHOD_NOT_FOUND,
+                format!("Unsupported method {method}"),
+            )),
+        }
+    }
+
+    fn update(&mut self, uri: &str, text: String) {
+        let analysis = Analysis::new(&text);
+        publish_diagnostics(uri, &analysis.diagnostics);
+        self.documents.insert(uri.to_string(), text);
+        self.analyses.insert(uri.to_string(), analysis);
+    }
+
+    fn symbol_at(
+        &self,
+        uri: &str,
+        line: usize,
+        character: usize,
+    ) -> Option<(&Analysis, &Occurrence)> {
+        let analysis = self.analyses.get(uri)?;
+        Some((analysis, analysis.occurrence_at(line, character)?))
+    }
+
+    /// Labels are defined where they're declared, variables where they're first used.
+    fn definition(&self, uri: &str, line: usize, character: usize) -> Value {
+        let Some((analysis, symbol)) = self.symbol_at(uri, line, character) else {
+            return Value::Null;
+        };
+        let mut same_name = analysis
+            .occurrences
+            .iter()
+            .filter(|occurrence| occurrence.name == symbol.name);
+
+        let definition = if analysis.is_label(&symbol.name) {
+            same_name.find(|occurrence| occurrence.is_label)
+        } else if predefined_symbols().contains_key(&symbol.name) {
+            None
+        } else {
+            same_name.next()
+        };
+        definition.map_or(Value::Null, |occurrence| location(uri, occurrence))
+    }
+
+    fn references(
+        &self,
+        uri: &str,
+        line: usize,
+        character: usize,
+        include_declaration: bool,
+    ) -> Value {
+        let Some((analysis, symbol)) = self.symbol_at(uri, line, character) else {
+            return Value::Null;
+        };
+        let locations: Vec<Value> = analysis
+            .occurrences
+            .iter()
+            .filter(|occurrence| {
+                occurrence.name == symbol.name && (include_declaration || !occurrence.is_label)
+            })
+            .map(|occurrence| location(uri, occurrence))
+            .collect();
+        Value::from(locations)
+    }
+
+    fn hover(&self, uri: &str, line: usize, character: usize) -> Value {
+        let Some(analysis) = self.analyses.get(uri) else {
+            return Value::Null;
+        };
+
+        let mut contents = Vec::new();
+        if let Some(symbol) = analysis.occurrence_at(line, character) {
+            let address = analysis.symbols.get(&symbol.name);
+            contents.push(match address {
+                Some(address) if analysis.is_label(&symbol.name) => {
+                    format!("`{}`: label at ROM[{address}]", symbol.name)
+                }
+                Some(address) if predefined_symbols().contains_key(&symbol.name) => {
+                    format!("`{}`: predefined symbol for RAM[{address}]", symbol.name)
+                }
+                Some(address) => format!("`{}`: variable at RAM[{address}]", symbol.name),
+                None => format!("`{}`: unresolved", symbol.name),
+            });
+        }
+        if let Some((rom_location, word)) = analysis.instructions.get(&line) {
+            contents.push(match word {
+                Some(word) => format!("ROM[{rom_location}]: `{word:016b}` (0x{word:04X})"),
+                None => format!("ROM[{rom_location}]"),
+            });
+        }
+
+        if contents.is_empty() {
+            Value::Null
+        } else {
+            json!({ "contents": { "kind": "markdown", "value": contents.join("\n\n") } })
+        }
+    }
+
+    /// Symbols after `@`, jump conditions after `;` and computations anywhere else.
+    fn completion(&self, uri: &str, line: usize, character: usize) -> Value {
+        let prefix: String = self
+            .documents
+            .get(uri)
+            .and_then(|text| text.lines().nth(line))
+            .map(|text| {
+                let mut units = 0;
+                text.chars()
+                    .take_while(|c| {
+                        units += c.len_utf16();
+                        units <= character
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let items: Vec<Value> = if prefix.contains('@') {
+            let mut symbols: Vec<(String, u8)> = predefined_symbols()
+                .into_keys()
+                .map(|name| (name, COMPLETION_KIND_CONSTANT))
+                .collect();
+            if let Some(analysis) = self.analyses.get(uri) {
+                for occurrence in &analysis.occurrences {
+                    if !symbols.iter().any(|(name, _)| *name == occurrence.name) {
+                        let kind = if analysis.is_label(&occurrence.name) {
+                            COMPLETION_KIND_REFERENCE
+                        } else {
+                            COMPLETION_KIND_VARIABLE
+                        };
+                        symbols.push((occurrence.name.clone(), kind));
+                    }
+                }
+            }
+            symbols.sort();
+            symbols
+                .into_iter()
+                .map(|(name, kind)| json!({ "label": name, "kind": kind }))
+                .collect()
+        } else if prefix.contains(';') {
+            JUMPS[1..]
+                .iter()
+                .map(|jump| json!({ "label": jump, "kind": COMPLETION_KIND_OPERATOR }))
+                .collect()
+        } else {
+            OPERATIONS
+                .iter()
+                .chain(isa::extension().map_or(&[][..], |extension| extension.operations))
+                .map(|(mnemonic, _)| json!({ "label": mnemonic, "kind": COMPLETION_KIND_OPERATOR }))
+                .collect()
+        };
+        Value::from(items)
+    }
+
+    fn rename(
+        &self,
+        uri: &str,
+        line: usize,
+        character: usize,
+        new_name: &str,
+    ) -> Result<Value, (i64, String)> {
+        let Some((analysis, symbol)) = self.symbol_at(uri, line, character) else {
+            return Err((
+                ERROR_INVALID_PARAMS,
+                "No symbol at this position".to_string(),
+            ));
+        };
+        if !analysis.is_label(&symbol.name) {
+            return Err((
+                ERROR_INVALID_PARAMS,
+                "Only labels can be renamed".to_string(),
+            ));
+        }
+        if new_name.is_empty()
+            || new_name.parse::<u16>().is_ok()
+            || new_name.contains(char::is_whitespace)
+        {
+            return Err((
+                ERROR_INVALID_PARAMS,
+                format!("Invalid label name: {new_name}"),
+            ));
+        }
+        // Taking a name that's already in use would merge two symbols into one
+        if new_name != symbol.name
+            && (predefined_symbols().contains_key(new_name)
+                || analysis.symbols.contains_key(new_name)
+                || analysis
+                    .occurrences
+                    .iter()
+                    .any(|occurrence| occurrence.name == new_name))
+        {
+            return Err((
+                ERROR_INVALID_PARAMS,
+                format!("{new_name} is already a symbol"),
+            ));
+        }
+
+        let edits: Vec<Value> = analysis
+            .occurrences
+            .iter()
+            .filter(|occurrence| occurrence.name == symbol.name)
+            .map(|occurrence| {
+                json!({
+                    "range": range(occurrence.line, occurrence.start, occurrence.end),
+                    "newText": new_name,
+                })
+            })
+            .collect();
+        Ok(json!({ "changes": { uri: edits } }))
+    }
+}
+
+/// The UTF-16 column of a byte offset into a line.
+fn utf16_column(line: &str, byte: usize) -> usize {
+    line[..byte].encode_utf16().count()
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: &[Value]) {
+    send(&json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    }));
+}
+
+fn as_usize(value: &Value) -> usize {
+    value
+        .as_u64()
+        .and_then(|value| usize::try_from(value).ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///mult.asm";
+    const SOURCE: &str = "\
+(MULT)
+    @sum
+    M=0
+(LOOP)
+    @LOOP
+    0;JMP
+.test loops
+    set sum=1
+    run MULT until LOOP limit 10
+    expect sum=0
+.end
+";
+
+    fn server() -> Server {
+        // Filled in directly, since update would publish diagnostics on stdout
+        Server {
+            documents: HashMap::from([(URI.to_string(), SOURCE.to_string())]),
+            analyses: HashMap::from([(URI.to_string(), Analysis::new(SOURCE))]),
+            shutting_down: false,
+        }
+    }
+
+    fn request(server: &mut Server, method: &str, line: usize, character: usize) -> Value {
+        let params = json!({
+            "textDocument": { "uri": URI },
+            "position": { "line": line, "character": character },
+            "context": { "includeDeclaration": true },
+            "newName": "START",
+        });
+        server.handle(method, &params).unwrap()
+    }
+
+    fn lines(locations: &Value) -> Vec<u64> {
+        locations
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|location| location["range"]["start"]["line"].as_u64().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn finds_symbols_in_code_and_tests() {
+        let analysis = Analysis::new(SOURCE);
+        assert!(analysis.diagnostics.is_empty());
+        assert_eq!(analysis.symbols.get("LOOP"), Some(&2));
+        assert_eq!(analysis.symbols.get("sum"), Some(&16));
+        let until = analysis.occurrence_at(8, 20).unwrap();
+        assert_eq!(
+            (until.name.as_str(), until.start, until.end),
+            ("LOOP", 19, 23)
+        );
+        assert_eq!(analysis.occurrence_at(9, 12).unwrap().name, "sum");
+    }
+
+    #[test]
+    fn goes_to_definitions() {
+        let mut server = server();
+        let definition = request(&mut server, "textDocument/definition", 4, 6);
+        assert_eq!(definition["range"]["start"]["line"], 3);
+        let definition = request(&mut server, "textDocument/definition", 9, 12);
+        assert_eq!(definition["range"]["start"]["line"], 1);
+    }
+
+    #[test]
+    fn finds_references() {
+        let mut server = server();
+        let references = request(&mut server, "textDocument/references", 3, 2);
+        assert_eq!(lines(&references), [3, 4, 8]);
+        let references = request(&mut server, "textDocument/references", 1, 6);
+        assert_eq!(lines(&references), [1, 7, 9]);
+    }
+
+    #[test]
+    fn renames_labels_in_tests_too() {
+        let mut server = server();
+        let rename = request(&mut server, "textDocument/rename", 0, 2);
+        let edits = &rename["changes"][URI];
+        assert_eq!(lines(edits), [0, 8]);
+        assert_eq!(edits[1]["range"]["start"]["character"], 8);
+        assert!(edits
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|edit| edit["newText"] == "START"));
+    }
+
+    #[test]
+    fn refuses_renames_onto_other_symbols() {
+        let mut server = server();
+        let params = json!({
+            "textDocument": { "uri": URI },
+            "position": { "line": 0, "character": 2 },
+            "newName": "sum",
+        });
+        assert!(server.handle("textDocument/rename", &params).is_err());
+        let params = json!({
+            "textDocument": { "uri": URI },
+            "position": { "line": 1, "character": 6 },
+            "newName": "total",
+        });
+        assert!(server.handle("textDocument/rename", &params).is_err());
+    }
+}
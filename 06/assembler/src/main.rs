@@ -2,20 +2,93 @@
 
 extern crate core;
 
+mod banking;
+mod cfg;
+mod cpu;
+mod format;
+mod hack_to_c;
+mod inline_test;
+mod isa;
+mod lsp;
+mod optimizer;
+mod source_map;
+mod superoptimizer;
+mod symbol_file;
+
+use clap::Parser;
 use std::collections::HashMap;
-use std::env;
+use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
+use std::process;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+#[allow(clippy::struct_excessive_bools)]
+/// Translates Hack assembly to Hack machine code.
+struct Args {
+    /// A .asm file to assemble
+    #[clap(required_unless_present = "lsp")]
+    input_path: Option<String>,
+
+    /// Runs the peephole optimizer over the parsed program before symbols are resolved
+    #[clap(short = 'O', long, action = clap::ArgAction::SetTrue)]
+    optimize: bool,
+
+    /// Treats the input as blank-line separated instruction windows and writes a table of shorter equivalent sequences
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    superoptimize: bool,
+
+    /// Writes the program's control-flow graph as Graphviz DOT and JSON instead of assembling it
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    cfg: bool,
+
+    /// Rewrites the input in canonical formatting instead of assembling it
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    format: bool,
+
+    /// Fails if the input isn't already formatted, without changing it
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    check: bool,
+
+    /// Runs the program's inline .test blocks on a simulated CPU instead of assembling it
+    #[clap(short, long, action = clap::ArgAction::SetTrue)]
+    test: bool,
+
+    /// Runs a language server for Hack assembly over stdin and stdout
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    lsp: bool,
+
+    /// Translates an assembled .hack file into a standalone C program instead of assembling
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    to_c: bool,
+
+    /// Spreads code over switchable 16K ROM banks, for programs too large for the 32K address space
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    banked: bool,
+
+    /// Instruction set to accept: the standard hack, or hack plus an extension such as shift
+    #[clap(long, default_value = "hack")]
+    isa: String,
 
-#[derive(Clone, Debug)]
+    /// Also writes the program's labels and variables to a .sym file next to the output
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    symbols: bool,
+
+    /// Also writes a .map file giving the source line of each ROM address
+    #[clap(long, action = clap::ArgAction::SetTrue, conflicts_with_all = &["optimize", "banked"])]
+    source_map: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 enum CommandValue {
     Number(u16),
     Symbol(String),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 enum CommandType {
     CommandA(CommandValue),
     CommandC {
@@ -28,32 +101,239 @@ enum CommandType {
     CommandL(CommandValue),
 }
 
+impl fmt::Display for CommandValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandValue::Number(num) => write!(f, "{num}"),
+            CommandValue::Symbol(symbol) => write!(f, "{symbol}"),
+        }
+    }
+}
+
+impl fmt::Display for CommandType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandType::CommandA(value) => write!(f, "@{value}"),
+            CommandType::CommandL(value) => write!(f, "({value})"),
+            CommandType::CommandC {
+                destination_a,
+                destination_m,
+                destination_d,
+                operation,
+                jump_condition,
+            } => {
+                for (enabled, register) in [
+                    (destination_a, 'A'),
+                    (destination_m, 'M'),
+                    (destination_d, 'D'),
+                ] {
+                    if *enabled {
+                        write!(f, "{register}")?;
+                    }
+                }
+                if *destination_a || *destination_m || *destination_d {
+                    write!(f, "=")?;
+                }
+
+                match isa::mnemonic(*operation) {
+                    Some(mnemonic) => write!(f, "{mnemonic}")?,
+                    None => write!(f, "{operation:#09b}")?,
+                }
+
+                if *jump_condition != 0 {
+                    write!(f, ";{}", JUMPS[usize::from(*jump_condition)])?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Computation mnemonics and their a-bit and six ALU control bits.
+const OPERATIONS: [(&str, u16); 28] = [
+    ("0", 0b010_1010),
+    ("1", 0b011_1111),
+    ("-1", 0b011_1010),
+    ("D", 0b000_1100),
+    ("A", 0b011_0000),
+    ("M", 0b111_0000),
+    ("!D", 0b000_1101),
+    ("!A", 0b011_0001),
+    ("!M", 0b111_0001),
+    ("-D", 0b000_1111),
+    ("-A", 0b011_0011),
+    ("-M", 0b111_0011),
+    ("D+1", 0b001_1111),
+    ("A+1", 0b011_0111),
+    ("M+1", 0b111_0111),
+    ("D-1", 0b000_1110),
+    ("A-1", 0b011_0010),
+    ("M-1", 0b111_0010),
+    ("D+A", 0b000_0010),
+    ("D+M", 0b100_0010),
+    ("D-A", 0b001_0011),
+    ("D-M", 0b101_0011),
+    ("A-D", 0b000_0111),
+    ("M-D", 0b100_0111),
+    ("D&A", 0b000_0000),
+    ("D&M", 0b100_0000),
+    ("D|A", 0b001_0101),
+    ("D|M", 0b101_0101),
+];
+
+/// Jump mnemonics indexed by their three jump bits.
+const JUMPS: [&str; 8] = ["", "JGT", "JEQ", "JGE", "JLT", "JNE", "JLE", "JMP"];
+
 const DEBUG_INFO: bool = false;
 
+#[allow(clippy::too_many_lines)]
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    assert!(args.len() >= 2, "Not enough arguments");
+    let args = Args::parse();
+    isa::select(&args.isa);
+
+    if args.lsp {
+        lsp::serve();
+        return;
+    }
 
-    let input_path = PathBuf::from(&args[1]);
-    let output_path = input_path.with_extension("hack");
-    println!("{} -> {}", input_path.display(), output_path.display());
+    let input_path = PathBuf::from(args.input_path.as_ref().unwrap());
+    let output_path = if args.format || args.check {
+        input_path.clone()
+    } else {
+        input_path.with_extension(if args.superoptimize {
+            "rewrites"
+        } else if args.cfg {
+            "dot"
+        } else if args.to_c {
+            "c"
+        } else {
+            "hack"
+        })
+    };
+    if !args.test {
+        println!("{} -> {}", input_path.display(), output_path.display());
+    }
 
     let in_file = match fs::read_to_string(&input_path) {
         Err(why) => panic!("couldn't open {}: {}", input_path.display(), why),
         Ok(file) => file,
     };
 
-    let mut commands: Vec<CommandType> = in_file
+    if args.to_c {
+        let rom = parse_hack(&in_file);
+        let name = input_path.file_name().unwrap().to_string_lossy();
+        if let Err(why) = fs::write(&output_path, hack_to_c::translate(&name, &rom)) {
+            panic!("couldn't write {}: {}", output_path.display(), why)
+        }
+        return;
+    }
+
+    if args.format || args.check {
+        let formatted = format::format(&in_file);
+        if args.check {
+            if formatted != in_file {
+                println!("{} is not formatted", input_path.display());
+                process::exit(1);
+            }
+        } else if let Err(why) = fs::write(&output_path, formatted) {
+            panic!("couldn't write {}: {}", output_path.display(), why)
+        }
+        return;
+    }
+
+    if args.superoptimize {
+        let table = superoptimizer::superoptimize(&in_file);
+        if let Err(why) = fs::write(&output_path, table) {
+            panic!("couldn't write {}: {}", output_path.display(), why)
+        }
+        return;
+    }
+
+    let (program, tests) = inline_test::extract(&in_file);
+    let (lines, mut commands): (Vec<usize>, Vec<CommandType>) = program
         .lines() // Split into lines
-        .map(|line| line.split_once("//").unwrap_or((line, "")).0.trim()) // Remove comments and whitespace
-        .filter(|line| !line.is_empty()) // Remove empty lines
-        .map(parse_command) // Parse everything
-        .collect();
+        .enumerate() // Keep line numbers for the source map
+        .map(|(i, line)| (i + 1, line.split_once("//").unwrap_or((line, "")).0.trim())) // Remove comments and whitespace
+        .filter(|(_, line)| !line.is_empty()) // Remove empty lines
+        .map(|(i, line)| (i, parse_command(line))) // Parse everything
+        .unzip();
     if DEBUG_INFO {
         println!("Parsed commands:\n{:#?}\n", commands);
     }
 
-    replace_symbols(&mut commands);
+    if args.optimize {
+        optimizer::optimize(&mut commands).print();
+        if DEBUG_INFO {
+            println!("Optimized commands:\n{commands:#?}\n");
+        }
+    }
+
+    if args.test {
+        if !inline_test::run_all(&commands, &tests, args.banked) {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if args.cfg {
+        let blocks = cfg::build(&commands);
+        let name = input_path.file_stem().unwrap().to_string_lossy();
+        let json_path = input_path.with_extension("json");
+        println!("{} -> {}", input_path.display(), json_path.display());
+
+        for (path, contents) in [
+            (&output_path, cfg::to_dot(&name, &blocks)),
+            (&json_path, cfg::to_json(&blocks)),
+        ] {
+            if let Err(why) = fs::write(path, contents) {
+                panic!("couldn't write {}: {}", path.display(), why)
+            }
+        }
+        return;
+    }
+
+    let labels = symbol_file::labels(&commands);
+    let write_symbols = |symbols: &HashMap<String, u16>, banks: &HashMap<String, u16>| {
+        let sym_path = output_path.with_extension("sym");
+        println!("{} -> {}", input_path.display(), sym_path.display());
+        if let Err(why) = fs::write(&sym_path, symbol_file::write(&labels, symbols, banks)) {
+            panic!("couldn't write {}: {}", sym_path.display(), why)
+        }
+    };
+
+    if args.banked {
+        let layout = banking::assemble(&commands);
+        layout.print();
+        if args.symbols {
+            write_symbols(&layout.symbols, &layout.banks);
+        }
+        let mut out_file = match File::create(&output_path) {
+            Err(why) => panic!("couldn't create {}: {}", output_path.display(), why),
+            Ok(file) => file,
+        };
+        for word in &layout.image {
+            if let Err(why) = writeln!(&mut out_file, "{word:0>16b}") {
+                panic!("couldn't write {}: {}", output_path.display(), why)
+            }
+        }
+        return;
+    }
+
+    let symbols = replace_symbols(&mut commands);
+    if args.symbols {
+        write_symbols(&symbols, &HashMap::new());
+    }
+    if args.source_map {
+        let map_path = output_path.with_extension("map");
+        println!("{} -> {}", input_path.display(), map_path.display());
+        let source_name = input_path.file_name().unwrap().to_string_lossy();
+        if let Err(why) = fs::write(
+            &map_path,
+            source_map::write(&source_name, &commands, &lines),
+        ) {
+            panic!("couldn't write {}: {}", map_path.display(), why)
+        }
+    }
     if DEBUG_INFO {
         println!("With symbols replaced:\n{:#?}\n", commands);
     }
@@ -78,6 +358,18 @@ fn main() {
     }
 }
 
+/// Reads the words of an assembled `.hack` file, one binary number per line.
+fn parse_hack(source: &str) -> Vec<u16> {
+    source
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            u16::from_str_radix(line, 2).unwrap_or_else(|_| panic!("Invalid machine code: {line}"))
+        })
+        .collect()
+}
+
 fn parse_command(command: &str) -> CommandType {
     match command.chars().next().unwrap() {
         '@' => CommandType::CommandA(match command[1..].parse::<u16>() {
@@ -92,6 +384,7 @@ fn parse_command(command: &str) -> CommandType {
             ))
         }
         _ => {
+            let command = &command.replace(char::is_whitespace, "");
             let destination_str = command.split_once('=').unwrap_or(("", "")).0.to_uppercase();
             let mut operation_str = command
                 .split_once('=')
@@ -109,48 +402,21 @@ fn parse_command(command: &str) -> CommandType {
             let destination_m = destination_str.contains('M');
             let destination_d = destination_str.contains('D');
 
-            let operation: u16 = match operation_str.as_str() {
-                "0" => 0b010_1010,
-                "1" => 0b011_1111,
-                "-1" => 0b011_1010,
-                "D" => 0b000_1100,
-                "A" => 0b011_0000,
-                "M" => 0b111_0000,
-                "!D" => 0b000_1101,
-                "!A" => 0b011_0001,
-                "!M" => 0b111_0001,
-                "-D" => 0b000_1111,
-                "-A" => 0b011_0011,
-                "-M" => 0b111_0011,
-                "D+1" => 0b001_1111,
-                "A+1" => 0b011_0111,
-                "M+1" => 0b111_0111,
-                "D-1" => 0b000_1110,
-                "A-1" => 0b011_0010,
-                "M-1" => 0b111_0010,
-                "D+A" => 0b000_0010,
-                "D+M" => 0b100_0010,
-                "D-A" => 0b001_0011,
-                "D-M" => 0b101_0011,
-                "A-D" => 0b000_0111,
-                "M-D" => 0b100_0111,
-                "D&A" => 0b000_0000,
-                "D&M" => 0b100_0000,
-                "D|A" => 0b001_0101,
-                "D|M" => 0b101_0101,
-                _ => panic!("Unknown operation {} in command {}", operation_str, command),
+            let Some(operation) = OPERATIONS
+                .iter()
+                .find(|(mnemonic, _)| {
+                    *mnemonic == operation_str || *mnemonic == commuted(&operation_str)
+                })
+                .map(|(_, operation)| *operation)
+                .or_else(|| isa::lookup(&operation_str))
+            else {
+                panic!("Unknown operation {operation_str} in command {command}")
             };
 
-            let jump_condition: u16 = match jump_str.as_str() {
-                "" => 0b000,
-                "JGT" => 0b001,
-                "JEQ" => 0b010,
-                "JGE" => 0b011,
-                "JLT" => 0b100,
-                "JNE" => 0b101,
-                "JLE" => 0b110,
-                "JMP" => 0b111,
-                _ => panic!("Unknown jump condition {} in command {}", jump_str, command),
+            let Some((jump_condition, _)) =
+                (0..).zip(JUMPS).find(|(_, mnemonic)| *mnemonic == jump_str)
+            else {
+                panic!("Unknown jump condition {jump_str} in command {command}")
             };
 
             CommandType::CommandC {
@@ -164,8 +430,37 @@ fn parse_command(command: &str) -> CommandType {
     }
 }
 
-fn replace_symbols(commands: &mut Vec<CommandType>) {
-    let mut symbols_table: HashMap<String, u16> = HashMap::from([
+/// Turns an assembled instruction word back into a command, the reverse of `compile_command`.
+fn decode_command(word: u16) -> CommandType {
+    if word & 0x8000 == 0 {
+        CommandType::CommandA(CommandValue::Number(word))
+    } else {
+        CommandType::CommandC {
+            destination_a: word & 0b10_0000 != 0,
+            destination_m: word & 0b00_1000 != 0,
+            destination_d: word & 0b01_0000 != 0,
+            operation: isa::decode(word),
+            jump_condition: word & 0b111,
+        }
+    }
+}
+
+/// The same computation with the operands of a commutative operator swapped, so `M+D` reads as `D+M`.
+fn commuted(operation: &str) -> String {
+    match operation.find(['+', '&', '|']) {
+        Some(i) if i > 0 => format!(
+            "{}{}{}",
+            &operation[i + 1..],
+            &operation[i..=i],
+            &operation[..i]
+        ),
+        _ => operation.to_string(),
+    }
+}
+
+/// Symbols every Hack program starts out with.
+fn predefined_symbols() -> HashMap<String, u16> {
+    HashMap::from([
         ("SP".to_string(), 0),
         ("LCL".to_string(), 1),
         ("ARG".to_string(), 2),
@@ -189,7 +484,12 @@ fn replace_symbols(commands: &mut Vec<CommandType>) {
         ("R15".to_string(), 15),
         ("SCREEN".to_string(), 0x4000),
         ("KBD".to_string(), 0x6000),
-    ]);
+    ])
+}
+
+/// Replaces labels and variables with their addresses and returns the finished symbol table.
+fn replace_symbols(commands: &mut Vec<CommandType>) -> HashMap<String, u16> {
+    let mut symbols_table = predefined_symbols();
 
     let mut rom_location: u16 = 0;
     for command in &mut *commands {
@@ -210,6 +510,13 @@ fn replace_symbols(commands: &mut Vec<CommandType>) {
     }
     assert!(rom_location <= 0x8000, "Too much code");
 
+    allocate_variables(commands, &mut symbols_table);
+    symbols_table
+}
+
+/// Replaces every symbol with its value, giving symbols that aren't in the table yet the next free
+/// RAM address from 16 up.
+fn allocate_variables(commands: &mut [CommandType], symbols_table: &mut HashMap<String, u16>) {
     let mut variable_location: u16 = 16;
     for command in &mut *commands {
         if let CommandType::CommandA(CommandValue::Symbol(symbol)) = command {
@@ -236,8 +543,7 @@ fn compile_command(command: &CommandType) -> u16 {
             operation,
             jump_condition,
         } => {
-            0xE000
-                | (operation << 6)
+            isa::encode(operation)
                 | (u16::from(destination_a) << 5)
                 | (u16::from(destination_d) << 4)
                 | (u16::from(destination_m) << 3)
@@ -0,0 +1,384 @@
+use crate::{isa, CommandType, CommandValue};
+use std::collections::HashMap;
+
+const REGISTER_A: u8 = 0b001;
+const REGISTER_D: u8 = 0b010;
+const REGISTER_M: u8 = 0b100;
+
+const JUMP_ALWAYS: u16 = 0b111;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RuleStats {
+    pub rewrites: usize,
+    pub words_saved: usize,
+}
+
+impl RuleStats {
+    fn record(&mut self, words_saved: usize) {
+        self.rewrites += 1;
+        self.words_saved += words_saved;
+    }
+}
+
+/// How often each peephole rule fired and how many ROM words it saved.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Report {
+    pub redundant_loads: RuleStats,
+    pub duplicate_computations: RuleStats,
+    pub jumps_to_next: RuleStats,
+    pub threaded_jumps: RuleStats,
+}
+
+impl Report {
+    fn rules(&self) -> [(&'static str, RuleStats); 4] {
+        [
+            ("redundant A loads", self.redundant_loads),
+            ("duplicate computations", self.duplicate_computations),
+            ("jumps to next instruction", self.jumps_to_next),
+            ("threaded jumps", self.threaded_jumps),
+        ]
+    }
+
+    fn total_rewrites(&self) -> usize {
+        self.rules().iter().map(|(_, stats)| stats.rewrites).sum()
+    }
+
+    pub fn words_saved(&self) -> usize {
+        self.rules()
+            .iter()
+            .map(|(_, stats)| stats.words_saved)
+            .sum()
+    }
+
+    pub fn print(&self) {
+        println!("Peephole optimizer saved {} words", self.words_saved());
+        for (name, stats) in self.rules() {
+            println!(
+                "    {name}: {} words saved ({} rewrites)",
+                stats.words_saved, stats.rewrites
+            );
+        }
+    }
+}
+
+/// Applies every peephole rule until none of them changes the program.
+///
+/// Labels are never removed or moved relative to the instructions around them, so every jump
+/// target keeps its meaning. Knowledge about register contents is dropped at each label because
+/// control can arrive there from anywhere.
+pub fn optimize(commands: &mut Vec<CommandType>) -> Report {
+    let mut report = Report::default();
+    loop {
+        let rewrites = report.total_rewrites();
+
+        remove_redundant_loads(commands, &mut report.redundant_loads);
+        remove_duplicate_computations(commands, &mut report.duplicate_computations);
+        remove_jumps_to_next(commands, &mut report.jumps_to_next);
+        thread_jumps(commands, &mut report.threaded_jumps);
+
+        if report.total_rewrites() == rewrites {
+            return report;
+        }
+    }
+}
+
+/// Registers a C-instruction depends on. Writing to M counts as reading A because A holds the address.
+fn reads(command: &CommandType) -> u8 {
+    let mut registers = 0;
+    if let CommandType::CommandC {
+        destination_m,
+        operation,
+        ..
+    } = *command
+    {
+        if operation & isa::EXTENDED != 0 {
+            // Extension operations name the one register they work on
+            let mnemonic = isa::mnemonic(operation).unwrap_or_default();
+            for (name, register) in [
+                ('A', REGISTER_A),
+                ('D', REGISTER_D),
+                ('M', REGISTER_A | REGISTER_M),
+            ] {
+                if mnemonic.contains(name) {
+                    registers |= register;
+                }
+            }
+        } else {
+            // zx clears the ALU's x input (D) and zy clears its y input (A or M)
+            if operation & 0b010_0000 == 0 {
+                registers |= REGISTER_D;
+            }
+            if operation & 0b000_1000 == 0 {
+                registers |= if operation & 0b100_0000 == 0 {
+                    REGISTER_A
+                } else {
+                    REGISTER_A | REGISTER_M
+                };
+            }
+        }
+        if destination_m {
+            registers |= REGISTER_A;
+        }
+    }
+    registers
+}
+
+fn writes(command: &CommandType) -> u8 {
+    match *command {
+        CommandType::CommandC {
+            destination_a,
+            destination_m,
+            destination_d,
+            ..
+        } => {
+            (if destination_a { REGISTER_A } else { 0 })
+                | (if destination_d { REGISTER_D } else { 0 })
+                | (if destination_m { REGISTER_M } else { 0 })
+        }
+        CommandType::CommandA(_) => REGISTER_A,
+        CommandType::CommandL(_) => 0,
+    }
+}
+
+fn jump_condition(command: &CommandType) -> u16 {
+    match *command {
+        CommandType::CommandC { jump_condition, .. } => jump_condition,
+        _ => 0,
+    }
+}
+
+/// `@X` when A is already known to hold X.
+fn remove_redundant_loads(commands: &mut Vec<CommandType>, stats: &mut RuleStats) {
+    let mut a_register: Option<CommandValue> = None;
+    commands.retain(|command| match command {
+        CommandType::CommandL(_) => {
+            a_register = None;
+            true
+        }
+        CommandType::CommandA(value) => {
+            if a_register.as_ref() == Some(value) {
+                stats.record(1);
+                false
+            } else {
+                a_register = Some(value.clone());
+                true
+            }
+        }
+        CommandType::CommandC { destination_a, .. } => {
+            if *destination_a {
+                a_register = None;
+            }
+            true
+        }
+    });
+}
+
+/// A C-instruction repeated back to back when it doesn't jump and none of its outputs feed its own inputs.
+fn remove_duplicate_computations(commands: &mut Vec<CommandType>, stats: &mut RuleStats) {
+    let mut previous: Option<CommandType> = None;
+    commands.retain(|command| {
+        let redundant = previous.as_ref() == Some(command)
+            && matches!(command, CommandType::CommandC { .. })
+            && jump_condition(command) == 0
+            && writes(command) & reads(command) == 0;
+        previous = Some(command.clone());
+
+        if redundant {
+            stats.record(1);
+        }
+        !redundant
+    });
+}
+
+/// `@L` and a jump with no destination immediately followed by `(L)`.
+///
+/// Only applied when the code after the label loads A before using it, since the fallthrough path
+/// would otherwise arrive with a different A than before.
+fn remove_jumps_to_next(commands: &mut Vec<CommandType>, stats: &mut RuleStats) {
+    let mut i = 0;
+    while i + 2 < commands.len() {
+        if let CommandType::CommandA(CommandValue::Symbol(target)) = &commands[i] {
+            let jump = &commands[i + 1];
+            if jump_condition(jump) != 0 && writes(jump) == 0 {
+                let labels_end = commands[i + 2..]
+                    .iter()
+                    .position(|command| !matches!(command, CommandType::CommandL(_)))
+                    .map_or(commands.len(), |offset| i + 2 + offset);
+                let lands_on_target = commands[i + 2..labels_end].iter().any(
+                    |command| matches!(command, CommandType::CommandL(CommandValue::Symbol(label)) if label == target),
+                );
+
+                if lands_on_target && loads_a_before_use(commands, labels_end) {
+                    commands.drain(i..i + 2);
+                    stats.record(2);
+                    continue;
+                }
+            }
+        }
+        i += 1;
+    }
+}
+
+/// Whether the code from `start` on, taken in order, loads A before anything uses it. Jumps use A
+/// since they go wherever it points, and running out of code counts as a use to stay safe.
+fn loads_a_before_use(commands: &[CommandType], start: usize) -> bool {
+    for command in &commands[start..] {
+        if reads(command) & REGISTER_A != 0 || jump_condition(command) != 0 {
+            return false;
+        }
+        if writes(command) & REGISTER_A != 0 {
+            return true;
+        }
+    }
+    false
+}
+
+/// `@X` before a jump whose target `(X)` is just `@Y 0;JMP` gets retargeted straight to Y.
+///
+/// A conditional jump is only retargeted when the code it falls through to loads A before using
+/// it, since A holds Y instead of X afterwards.
+fn thread_jumps(commands: &mut [CommandType], stats: &mut RuleStats) {
+    let labels: HashMap<String, usize> = commands
+        .iter()
+        .enumerate()
+        .filter_map(|(i, command)| match command {
+            CommandType::CommandL(CommandValue::Symbol(label)) => Some((label.clone(), i)),
+            _ => None,
+        })
+        .collect();
+
+    // The value loaded by an unconditional `@Y 0;JMP` sitting right after the label
+    let trampoline = |label: &str| -> Option<CommandValue> {
+        let start = labels.get(label)?;
+        let mut body = commands[*start..]
+            .iter()
+            .filter(|command| !matches!(command, CommandType::CommandL(_)));
+        match (body.next()?, body.next()?) {
+            (CommandType::CommandA(value), jump)
+                if jump_condition(jump) == JUMP_ALWAYS && writes(jump) == 0 =>
+            {
+                Some(value.clone())
+            }
+            _ => None,
+        }
+    };
+
+    let mut retargets: Vec<(usize, CommandValue)> = Vec::new();
+    for i in 0..commands.len().saturating_sub(1) {
+        let (CommandType::CommandA(CommandValue::Symbol(target)), jump) =
+            (&commands[i], &commands[i + 1])
+        else {
+            continue;
+        };
+        if jump_condition(jump) == 0
+            || reads(jump) & (REGISTER_A | REGISTER_M) != 0
+            || writes(jump) & (REGISTER_A | REGISTER_M) != 0
+            || (jump_condition(jump) != JUMP_ALWAYS && !loads_a_before_use(commands, i + 2))
+        {
+            continue;
+        }
+
+        let mut visited = vec![target.clone()];
+        let mut destination = None;
+        while let Some(next) = trampoline(visited.last().unwrap()) {
+            match next {
+                CommandValue::Symbol(ref label) if labels.contains_key(label) => {
+                    if visited.contains(label) {
+                        // Jumps already inside an endless trampoline loop (like a halt) are left alone
+                        if *label == visited[0] {
+                            destination = None;
+                        }
+                        break;
+                    }
+                    visited.push(label.clone());
+                    destination = Some(next);
+                }
+                _ => {
+                    destination = Some(next);
+                    break;
+                }
+            }
+        }
+
+        if let Some(destination) = destination {
+            retargets.push((i, destination));
+        }
+    }
+
+    for (i, destination) in retargets {
+        commands[i] = CommandType::CommandA(destination);
+        stats.record(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::optimize;
+    use crate::cpu::Cpu;
+    use crate::{compile_command, parse_command, replace_symbols, CommandType};
+
+    fn parse(source: &str) -> Vec<CommandType> {
+        source.split_whitespace().map(parse_command).collect()
+    }
+
+    /// RAM[0..16] after running the program long enough to reach its halt loop.
+    fn run(mut commands: Vec<CommandType>) -> Vec<u16> {
+        replace_symbols(&mut commands);
+        let rom = commands
+            .iter()
+            .filter(|command| !matches!(command, CommandType::CommandL(_)))
+            .map(compile_command)
+            .collect();
+        let mut cpu = Cpu::new(rom);
+        for _ in 0..10_000 {
+            cpu.step();
+        }
+        cpu.ram[..16].to_vec()
+    }
+
+    /// Checks the optimized program leaves the same RAM[0..16] behind, and returns it.
+    fn assert_equivalent(source: &str) -> Vec<CommandType> {
+        let commands = parse(source);
+        let mut optimized = commands.clone();
+        optimize(&mut optimized);
+        assert_eq!(run(optimized.clone()), run(commands), "{source}");
+        optimized
+    }
+
+    #[test]
+    fn keeps_jump_to_next_when_a_is_used_later() {
+        // D;JGT loops back through the A the jump to L left behind
+        let source = "@5 D=A @L 0;JMP (L) D=D-1 D;JGT @R0 M=1 (END) @END 0;JMP";
+        let optimized = assert_equivalent(source);
+        assert_eq!(optimized, parse(source));
+    }
+
+    #[test]
+    fn removes_jump_to_next_when_a_is_loaded_first() {
+        let optimized = assert_equivalent("@L 0;JMP (L) @R0 M=1 (END) @END 0;JMP");
+        assert_eq!(optimized, parse("(L) @R0 M=1 (END) @END 0;JMP"));
+    }
+
+    #[test]
+    fn keeps_conditional_jump_target_used_on_fallthrough() {
+        let source = "@X D;JEQ D=A @R0 M=D (END) @END 0;JMP (X) @END 0;JMP";
+        let optimized = assert_equivalent(source);
+        assert_eq!(optimized, parse(source));
+    }
+
+    #[test]
+    fn threads_unconditional_jumps() {
+        let optimized = assert_equivalent("@X 0;JMP @R0 M=1 (X) @END 0;JMP (END) @END 0;JMP");
+        assert_eq!(optimized[0], parse_command("@END"));
+    }
+
+    #[test]
+    fn multiplies_the_same_after_optimizing() {
+        // R2 = R0 * R1 by repeated addition, clearing R2 twice for the rules to find
+        assert_equivalent(
+            "@6 D=A @R0 M=D @7 D=A @R1 M=D @R2 M=0 @R2 M=0 \
+             (LOOP) @R1 D=M @END D;JEQ @R0 D=M @R2 M=D+M @R1 M=M-1 @LOOP 0;JMP \
+             (END) @END 0;JMP",
+        );
+    }
+}
@@ -0,0 +1,25 @@
+use crate::CommandType;
+use std::fmt::Write;
+
+/// Maps each ROM address back to the line of assembly it came from, for tools that run the
+/// assembled code:
+///
+/// ```text
+/// source Mult.asm
+/// 0 12
+/// 1 13
+/// ```
+///
+/// The first line names the source file, relative to the map. Each line after it is a ROM address
+/// and the 1-based source line of its instruction. `lines` holds the source line of each command.
+pub fn write(source_name: &str, commands: &[CommandType], lines: &[usize]) -> String {
+    let mut file = format!("source {source_name}\n");
+    let instructions = commands
+        .iter()
+        .zip(lines)
+        .filter(|(command, _)| !matches!(command, CommandType::CommandL(_)));
+    for (address, (_, line)) in instructions.enumerate() {
+        writeln!(file, "{address} {line}").unwrap();
+    }
+    file
+}
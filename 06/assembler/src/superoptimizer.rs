@@ -0,0 +1,426 @@
+use crate::{compile_command, cpu, isa, parse_command, CommandType, CommandValue, OPERATIONS};
+use std::fmt::Write;
+
+const REGISTER_A: u8 = 0b001;
+const REGISTER_D: u8 = 0b010;
+const MEMORY: u8 = 0b100;
+
+/// Random machine states every accepted replacement has to agree with the original on.
+const TEST_VECTORS: usize = 256;
+
+/// Longest window searched. Every extra instruction multiplies the search by the hundred or so
+/// candidate instructions, so five would already take hours.
+const MAX_WINDOW: usize = 4;
+
+/// Register values that tend to expose differences between computations.
+const EDGE_VALUES: [u16; 6] = [0, 1, 0xFFFF, 0x7FFF, 0x8000, 0x4000];
+
+/// A straight-line sequence to be shortened and what has to be preserved across it.
+///
+/// Window files hold sequences separated by blank lines. `// live-in: A D` and `// live-out: D M`
+/// comments above a sequence restrict which registers it depends on and which results matter
+/// afterwards. M in the live-out set means memory writes are observable. By default every register
+/// is live and memory writes are kept.
+struct Window {
+    commands: Vec<CommandType>,
+    live_in: u8,
+    live_out: u8,
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Window {
+            commands: Vec::new(),
+            live_in: REGISTER_A | REGISTER_D,
+            live_out: REGISTER_A | REGISTER_D | MEMORY,
+        }
+    }
+}
+
+/// One random starting state shared by the original sequence and its candidates.
+struct Vector {
+    a: u16,
+    d: u16,
+    /// Stand-ins for whichever of A and D aren't live-in, so candidates can't depend on them
+    dead_a: u16,
+    dead_d: u16,
+    memory_seed: u64,
+    /// Instruction words of every candidate, with this vector's symbol values filled in
+    candidate_words: Vec<u16>,
+    expected: Observation,
+}
+
+#[derive(PartialEq, Eq)]
+struct Observation {
+    a: Option<u16>,
+    d: Option<u16>,
+    memory: Option<Vec<(u16, u16)>>,
+}
+
+struct State {
+    a: u16,
+    d: u16,
+    memory_seed: u64,
+    writes: Vec<(u16, u16)>,
+}
+
+impl State {
+    fn read(&self, address: u16) -> u16 {
+        match self.writes.iter().find(|(written, _)| *written == address) {
+            Some(&(_, value)) => value,
+            None => initial_memory(self.memory_seed, address),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u16) {
+        match self
+            .writes
+            .iter_mut()
+            .find(|(written, _)| *written == address)
+        {
+            Some(write) => write.1 = value,
+            None => self.writes.push((address, value)),
+        }
+    }
+
+    fn execute(&mut self, instruction: u16) {
+        if instruction & 0x8000 == 0 {
+            self.a = instruction;
+            return;
+        }
+
+        let out = cpu::compute(isa::decode(instruction), self.a, self.d, self.read(self.a));
+        if instruction & 0b00_1000 != 0 {
+            self.write(self.a, out);
+        }
+        if instruction & 0b10_0000 != 0 {
+            self.a = out;
+        }
+        if instruction & 0b01_0000 != 0 {
+            self.d = out;
+        }
+    }
+
+    fn observe(&self, live_out: u8) -> Observation {
+        let memory = (live_out & MEMORY != 0).then(|| {
+            let mut changed: Vec<(u16, u16)> = self
+                .writes
+                .iter()
+                .copied()
+                .filter(|&(address, value)| initial_memory(self.memory_seed, address) != value)
+                .collect();
+            changed.sort_unstable();
+            changed
+        });
+
+        Observation {
+            a: (live_out & REGISTER_A != 0).then_some(self.a),
+            d: (live_out & REGISTER_D != 0).then_some(self.d),
+            memory,
+        }
+    }
+}
+
+/// splitmix64, which is plenty for generating test states.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        mix(self.0)
+    }
+
+    fn next_u16(&mut self) -> u16 {
+        (self.next() >> 48) as u16
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        usize::from(self.next_u16()) % bound
+    }
+
+    fn register(&mut self) -> u16 {
+        if self.next().is_multiple_of(4) {
+            EDGE_VALUES[self.below(EDGE_VALUES.len())]
+        } else {
+            self.next_u16()
+        }
+    }
+}
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn initial_memory(seed: u64, address: u16) -> u16 {
+    (mix(seed ^ u64::from(address)) >> 48) as u16
+}
+
+/// Searches every window in a window file for the shortest equivalent sequence and returns the
+/// table of rewrites that were found.
+///
+/// Equivalence is established by running both sequences from the same random states (including
+/// ones where different symbols alias the same address), not by proof, so rewrites should still be
+/// reviewed before they're adopted.
+pub fn superoptimize(source: &str) -> String {
+    let mut table = String::new();
+    for (i, window) in parse_windows(source).iter().enumerate() {
+        let original = join(&window.commands);
+        if window.commands.len() > MAX_WINDOW {
+            println!(
+                "Window {}: skipped, {} instructions is more than the {MAX_WINDOW} searched: {original}",
+                i + 1,
+                window.commands.len()
+            );
+            continue;
+        }
+        match search(window) {
+            Some(replacement) => {
+                println!(
+                    "Window {}: {} -> {} words: {original} => {}",
+                    i + 1,
+                    window.commands.len(),
+                    replacement.len(),
+                    join(&replacement)
+                );
+                writeln!(
+                    table,
+                    "{original} => {}  // live-in: {}, live-out: {}, saves {} words",
+                    join(&replacement),
+                    register_names(window.live_in),
+                    register_names(window.live_out),
+                    window.commands.len() - replacement.len()
+                )
+                .unwrap();
+            }
+            None => println!("Window {}: no shorter sequence for {original}", i + 1),
+        }
+    }
+    table
+}
+
+fn parse_windows(source: &str) -> Vec<Window> {
+    let mut windows = Vec::new();
+    let mut window = Window::default();
+
+    for line in source.lines().chain([""]) {
+        let (code, comment) = line.split_once("//").unwrap_or((line, ""));
+        let code = code.trim();
+        let comment = comment.trim();
+
+        if let Some(registers) = comment.strip_prefix("live-in:") {
+            window.live_in = parse_registers(registers);
+        } else if let Some(registers) = comment.strip_prefix("live-out:") {
+            window.live_out = parse_registers(registers);
+        }
+
+        if line.trim().is_empty() {
+            if !window.commands.is_empty() {
+                windows.push(std::mem::take(&mut window));
+            }
+        } else if !code.is_empty() {
+            let command = parse_command(code);
+            assert!(
+                matches!(
+                    command,
+                    CommandType::CommandA(_)
+                        | CommandType::CommandC {
+                            jump_condition: 0,
+                            ..
+                        }
+                ),
+                "Superoptimizer windows must be straight-line code: {code}"
+            );
+            window.commands.push(command);
+        }
+    }
+
+    windows
+}
+
+fn parse_registers(registers: &str) -> u8 {
+    registers
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|register| !register.is_empty() && *register != "none")
+        .map(|register| match register.to_uppercase().as_str() {
+            "A" => REGISTER_A,
+            "D" => REGISTER_D,
+            "M" => MEMORY,
+            _ => panic!("Unknown register {register} in live set"),
+        })
+        .fold(0, |registers, register| registers | register)
+}
+
+fn register_names(registers: u8) -> String {
+    let names: Vec<&str> = [(REGISTER_A, "A"), (REGISTER_D, "D"), (MEMORY, "M")]
+        .into_iter()
+        .filter(|(register, _)| registers & register != 0)
+        .map(|(_, name)| name)
+        .collect();
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names.join(" ")
+    }
+}
+
+fn join(commands: &[CommandType]) -> String {
+    let commands: Vec<String> = commands.iter().map(ToString::to_string).collect();
+    commands.join(", ")
+}
+
+/// Iterative deepening over every A-instruction loading a value the window already uses and every
+/// C-instruction that stores its result somewhere.
+fn search(window: &Window) -> Option<Vec<CommandType>> {
+    let mut values: Vec<CommandValue> = Vec::new();
+    for command in &window.commands {
+        if let CommandType::CommandA(value) = command {
+            if !values.contains(value) {
+                values.push(value.clone());
+            }
+        }
+    }
+
+    let mut candidates: Vec<CommandType> =
+        values.iter().cloned().map(CommandType::CommandA).collect();
+    for &(_, operation) in &OPERATIONS {
+        for destination in 1..8 {
+            candidates.push(CommandType::CommandC {
+                destination_a: destination & 0b100 != 0,
+                destination_m: destination & 0b001 != 0,
+                destination_d: destination & 0b010 != 0,
+                operation,
+                jump_condition: 0,
+            });
+        }
+    }
+
+    let mut rng = Rng(0x4841_434B);
+    let vectors: Vec<Vector> = (0..TEST_VECTORS)
+        .map(|i| {
+            let resolved = resolve_values(&values, &mut rng);
+            let word = |command: &CommandType| match command {
+                CommandType::CommandA(value) => {
+                    resolved[values.iter().position(|known| known == value).unwrap()]
+                }
+                _ => compile_command(command),
+            };
+
+            let (a, d) = if i < EDGE_VALUES.len() {
+                (EDGE_VALUES[i], EDGE_VALUES[EDGE_VALUES.len() - 1 - i])
+            } else {
+                (rng.register(), rng.register())
+            };
+            let memory_seed = rng.next();
+
+            let mut state = State {
+                a,
+                d,
+                memory_seed,
+                writes: Vec::new(),
+            };
+            for command in &window.commands {
+                state.execute(word(command));
+            }
+
+            Vector {
+                a,
+                d,
+                dead_a: rng.register(),
+                dead_d: rng.register(),
+                memory_seed,
+                candidate_words: candidates.iter().map(word).collect(),
+                expected: state.observe(window.live_out),
+            }
+        })
+        .collect();
+
+    let matches = |sequence: &[usize], vector: &Vector| {
+        let mut state = State {
+            a: if window.live_in & REGISTER_A != 0 {
+                vector.a
+            } else {
+                vector.dead_a
+            },
+            d: if window.live_in & REGISTER_D != 0 {
+                vector.d
+            } else {
+                vector.dead_d
+            },
+            memory_seed: vector.memory_seed,
+            writes: Vec::new(),
+        };
+        for &candidate in sequence {
+            state.execute(vector.candidate_words[candidate]);
+        }
+        state.observe(window.live_out) == vector.expected
+    };
+
+    for length in 0..window.commands.len() {
+        let mut sequence = vec![0; length];
+        loop {
+            if vectors.iter().all(|vector| matches(&sequence, vector)) {
+                return Some(sequence.iter().map(|&i| candidates[i].clone()).collect());
+            }
+
+            // Advance like an odometer and move on to the next length once it rolls over
+            let Some(position) = sequence.iter().rposition(|&i| i + 1 < candidates.len()) else {
+                break;
+            };
+            sequence[position] += 1;
+            for i in &mut sequence[position + 1..] {
+                *i = 0;
+            }
+        }
+    }
+
+    None
+}
+
+/// Concrete addresses for the window's values in one test state. Numbers keep their value while
+/// symbols get random ones, occasionally colliding with another value to model aliasing.
+fn resolve_values(values: &[CommandValue], rng: &mut Rng) -> Vec<u16> {
+    let mut resolved: Vec<u16> = Vec::with_capacity(values.len());
+    for value in values {
+        resolved.push(match value {
+            CommandValue::Number(num) => *num & 0x7FFF,
+            CommandValue::Symbol(_) => {
+                if !resolved.is_empty() && rng.next().is_multiple_of(8) {
+                    resolved[rng.below(resolved.len())]
+                } else {
+                    rng.next_u16() & 0x7FFF
+                }
+            }
+        });
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search_source(source: &str) -> Option<String> {
+        let windows = parse_windows(source);
+        search(&windows[0]).map(|replacement| join(&replacement))
+    }
+
+    #[test]
+    fn folds_constants() {
+        assert_eq!(search_source("D=0\nD=D+1\n").as_deref(), Some("D=1"));
+    }
+
+    #[test]
+    fn drops_dead_loads() {
+        assert_eq!(
+            search_source("// live-out: D\n@R0\nD=M\n@R1\n").as_deref(),
+            Some("@R0, D=M")
+        );
+    }
+
+    #[test]
+    fn keeps_stores() {
+        assert_eq!(search_source("@X\nM=D\n"), None);
+    }
+}
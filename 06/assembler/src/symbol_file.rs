@@ -0,0 +1,56 @@
+use crate::banking::{BANK_SELECT, BANK_SYMBOL};
+use crate::{predefined_symbols, CommandType, CommandValue};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+/// Lists a program's labels and variables for tools that run the assembled code, one per line:
+///
+/// ```text
+/// label LOOP 4
+/// label DRAW 16390 1
+/// variable i 16
+/// ```
+///
+/// The optional last column is the ROM bank of labels placed by `--banked`. Predefined symbols are
+/// left out since every tool already knows them.
+pub fn write(
+    labels: &HashSet<String>,
+    symbols: &HashMap<String, u16>,
+    banks: &HashMap<String, u16>,
+) -> String {
+    let predefined = predefined_symbols();
+    let mut label_lines: Vec<(u16, &str)> = Vec::new();
+    let mut variables: Vec<(u16, &str)> = Vec::new();
+    for (name, &value) in symbols {
+        if labels.contains(name) {
+            label_lines.push((value, name));
+        } else if !predefined.contains_key(name) && (name != BANK_SYMBOL || value != BANK_SELECT) {
+            variables.push((value, name));
+        }
+    }
+    label_lines.sort_unstable_by_key(|&(value, name)| (banks.get(name).copied(), value, name));
+    variables.sort_unstable();
+
+    let mut file = String::new();
+    for (value, name) in label_lines {
+        match banks.get(name) {
+            Some(bank) => writeln!(file, "label {name} {value} {bank}").unwrap(),
+            None => writeln!(file, "label {name} {value}").unwrap(),
+        }
+    }
+    for (value, name) in variables {
+        writeln!(file, "variable {name} {value}").unwrap();
+    }
+    file
+}
+
+/// Names of the labels a program defines, taken before symbols are replaced.
+pub fn labels(commands: &[CommandType]) -> HashSet<String> {
+    commands
+        .iter()
+        .filter_map(|command| match command {
+            CommandType::CommandL(CommandValue::Symbol(label)) => Some(label.clone()),
+            _ => None,
+        })
+        .collect()
+}
@@ -2,6 +2,7 @@
 
 extern crate core;
 
+use std::fmt::Write as _;
 use std::fs;
 use std::fs::File;
 use std::path::PathBuf;
@@ -22,6 +23,10 @@ struct Args {
     /// Prints parsed commands and compiled code to console
     #[clap(short, long, action = clap::ArgAction::SetTrue)]
     debug: bool,
+
+    /// Also writes a .vmmap file giving the .vm file and line each range of .asm lines came from
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    source_map: bool,
 }
 
 fn main() {
@@ -36,6 +41,7 @@ fn main() {
     println!("{} -> {}", input_path.display(), output_path.display());
 
     let mut asm: String = "".to_string();
+    let mut source_map = String::new();
     if args.use_bootstrap {
         asm.push_str("@256\nD=A\n@SP\nM=D\n");
         compile_file("", &vec![parse_command("call Sys.init 0")], &mut asm);
@@ -50,18 +56,24 @@ fn main() {
             Ok(file) => file,
         };
 
-        let commands: Vec<CommandType> = in_file
+        let (lines, commands): (Vec<usize>, Vec<CommandType>) = in_file
             .lines() // Split into lines
-            .map(|line| line.split_once("//").unwrap_or((line, "")).0.trim()) // Remove comments and whitespace
-            .filter(|line| !line.is_empty()) // Remove empty lines
-            .map(parse_command) // Parse everything
-            .collect();
+            .enumerate() // Keep line numbers for the source map
+            .map(|(i, line)| (i + 1, line.split_once("//").unwrap_or((line, "")).0.trim())) // Remove comments and whitespace
+            .filter(|(_, line)| !line.is_empty()) // Remove empty lines
+            .map(|(i, line)| (i, parse_command(line))) // Parse everything
+            .unzip();
         if args.debug {
             println!("Parsed commands for {}:\n{:#?}", file.path().display(), commands);
         }
 
         let file_name = file.path().file_stem().unwrap().to_str().unwrap();
-        compile_file(file_name, &commands, &mut asm);
+        let first_line = asm.matches('\n').count() + 1;
+        let starts = compile_file(file_name, &commands, &mut asm);
+        let source_name = file.path().strip_prefix(output_path.parent().unwrap()).unwrap_or(file.path());
+        for (start, line) in starts.iter().zip(&lines) {
+            writeln!(source_map, "{} {} {}", first_line + start, source_name.display(), line).unwrap();
+        }
         if args.debug {
             println!("Generated code for {}:\n{}", file.path().display(), asm);
         }
@@ -73,6 +85,14 @@ fn main() {
     if let Err(why) = writeln!(&mut out_file, "{}", asm) {
         panic!("couldn't write {}: {}", output_path.display(), why)
     }
+
+    if args.source_map {
+        let map_path = output_path.with_extension("vmmap");
+        println!("{} -> {}", input_path.display(), map_path.display());
+        if let Err(why) = fs::write(&map_path, source_map) {
+            panic!("couldn't write {}: {}", map_path.display(), why)
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -189,12 +209,17 @@ fn get_segment(segment: &str) -> Segment {
     }
 }
 
-fn compile_file(file_name: &str, commands: &Vec<CommandType>, output: &mut String){
+/// Appends the code for `commands` to `output` and returns the line of that code each command
+/// starts on, counting from 0 at the first line appended.
+#[allow(clippy::too_many_lines)]
+fn compile_file(file_name: &str, commands: &Vec<CommandType>, output: &mut String) -> Vec<usize> {
     let mut command_number = 0;
     let mut current_function = "".to_string();
+    let mut starts = Vec::with_capacity(commands.len());
+    let mut line = 0;
 
     for command in commands {
-        output.push_str(match command {
+        let code = match command {
             CommandType::Arithmetic(ArithmeticOperation::Add) => "@SP\nAM=M-1\nD=M\nA=A-1\nM=D+M\n".to_string(),
             CommandType::Arithmetic(ArithmeticOperation::Sub) => "@SP\nAM=M-1\nD=M\nA=A-1\nM=M-D\n".to_string(),
             CommandType::Arithmetic(ArithmeticOperation::Neg) => "@SP\nA=M-1\nM=-M\n".to_string(),
@@ -289,7 +314,11 @@ D=A+1\n@SP\nM=D
 @R13\nAM=M-1\nD=M\n@ARG\nM=D
 @R13\nAM=M-1\nD=M\n@LCL\nM=D
 @R14\nA=M\n0;JMP\n".to_string(),
-        }.as_str());
+        };
+        starts.push(line);
+        line += code.matches('\n').count();
+        output.push_str(&code);
         command_number += 1;
     }
+    starts
 }
\ No newline at end of file